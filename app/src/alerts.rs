@@ -0,0 +1,587 @@
+use std::{collections::HashMap, sync::Arc};
+
+use async_trait::async_trait;
+use chrono::{Duration, Utc};
+use lettre::{
+    message::Mailbox, transport::smtp::authentication::Credentials, AsyncSmtpTransport,
+    AsyncTransport, Message, Tokio1Executor,
+};
+use tokio_util::task::TaskTracker;
+use tracing::{warn, Instrument};
+
+use crate::cqrs::CounterEvent;
+use crate::domain::models::{
+    Alert, AlertChannel, AlertCondition, AlertRule, Counter, Notification, Webhook,
+};
+use crate::webhooks::{WebhookMessage, WebhookSender};
+use kountr_db::{
+    error::DbError,
+    event_store::{EventFilter, EventStore},
+    repository::Repository,
+};
+
+// ====================== SINKS ===============================================
+
+/// Hands fired alerts over to a notification channel.
+#[async_trait]
+pub trait AlertSink: Send + Sync {
+    async fn send(&self, rule: &AlertRule, alert: &Alert) -> Result<(), String>;
+}
+
+/// Adds alerts to the in-app notification list.
+pub struct InAppSink {
+    repo: Repository,
+}
+
+impl InAppSink {
+    pub fn new(repo: &Repository) -> Self {
+        Self { repo: repo.clone() }
+    }
+}
+
+#[async_trait]
+impl AlertSink for InAppSink {
+    async fn send(&self, _rule: &AlertRule, alert: &Alert) -> Result<(), String> {
+        let notification = Notification {
+            id: uuid::Uuid::new_v4().to_string(),
+            alert_id: alert.id.clone(),
+            message: alert.message.clone(),
+            read: false,
+            created_at: alert.fired_at,
+        };
+
+        self.repo
+            .insert_notification(notification.into())
+            .await
+            .map(|_| ())
+            .map_err(|err| err.to_string())
+    }
+}
+
+/// Posts alerts to the webhook whose id is the rule target, with the usual
+/// signature, retries and delivery log.
+pub struct WebhookSink {
+    repo: Repository,
+    sender: WebhookSender,
+}
+
+impl WebhookSink {
+    pub fn new(repo: &Repository, sender: WebhookSender) -> Self {
+        Self {
+            repo: repo.clone(),
+            sender,
+        }
+    }
+}
+
+#[async_trait]
+impl AlertSink for WebhookSink {
+    async fn send(&self, rule: &AlertRule, alert: &Alert) -> Result<(), String> {
+        let webhook: Webhook = self
+            .repo
+            .find_webhook_by_id(rule.target.clone())
+            .await
+            .map_err(|err| format!("cannot find webhook {}: {}", rule.target, err))?
+            .into();
+
+        let message = WebhookMessage {
+            id: alert.id.clone(),
+            event_type: "AlertFired".to_string(),
+            body: serde_json::to_string(alert).map_err(|err| err.to_string())?,
+        };
+
+        self.sender.spawn_deliver(webhook, message);
+
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct SmtpOptions {
+    pub host: String,
+    pub port: u16,
+    pub from: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// Use TLS, disable it to point to a local test server.
+    pub tls: bool,
+}
+
+// lettre waits up to a minute by default, for each step of the conversation
+const SMTP_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
+/// Emails alerts to the address in the rule target. Sending happens in the
+/// background, so that commands don't wait for the SMTP server: failures are
+/// recorded on the alert afterwards.
+#[derive(Clone)]
+pub struct EmailSink {
+    repo: Repository,
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+    from: Mailbox,
+    tasks: TaskTracker,
+}
+
+impl EmailSink {
+    pub fn new(repo: &Repository, opts: &SmtpOptions) -> Result<Self, String> {
+        let mut builder = if opts.tls {
+            AsyncSmtpTransport::<Tokio1Executor>::relay(&opts.host).map_err(|err| err.to_string())?
+        } else {
+            AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(&opts.host)
+        };
+        builder = builder.port(opts.port).timeout(Some(SMTP_TIMEOUT));
+
+        if let (Some(username), Some(password)) = (&opts.username, &opts.password) {
+            builder = builder.credentials(Credentials::new(username.clone(), password.clone()));
+        }
+
+        let from = opts
+            .from
+            .parse()
+            .map_err(|err| format!("invalid sender address {}: {}", opts.from, err))?;
+
+        Ok(Self {
+            repo: repo.clone(),
+            transport: builder.build(),
+            from,
+            tasks: TaskTracker::new(),
+        })
+    }
+
+    /// Waits for the emails being sent.
+    pub async fn drain(&self) {
+        self.tasks.close();
+        self.tasks.wait().await;
+    }
+
+    async fn deliver(&self, alert_id: String, email: Message) {
+        let Err(err) = self.transport.send(email).await else {
+            return;
+        };
+        warn!("Cannot email alert {}: {}", alert_id, err);
+
+        if let Err(err) = self.repo.update_alert_error(alert_id, err.to_string()).await {
+            warn!("Cannot record the email error: {}", err);
+        }
+    }
+}
+
+#[async_trait]
+impl AlertSink for EmailSink {
+    async fn send(&self, rule: &AlertRule, alert: &Alert) -> Result<(), String> {
+        let to: Mailbox = rule
+            .target
+            .parse()
+            .map_err(|err| format!("invalid recipient address {}: {}", rule.target, err))?;
+
+        let email = Message::builder()
+            .from(self.from.clone())
+            .to(to)
+            .subject(format!("[kountr] {}", rule.name))
+            .body(alert.message.clone())
+            .map_err(|err| err.to_string())?;
+
+        let sink = self.clone();
+        let alert_id = alert.id.clone();
+        let delivery = async move { sink.deliver(alert_id, email).await };
+        self.tasks.spawn(delivery.in_current_span());
+
+        Ok(())
+    }
+}
+
+/// The sinks available to alert rules, by channel.
+#[derive(Clone, Default)]
+pub struct AlertSinks(HashMap<AlertChannel, Arc<dyn AlertSink>>);
+
+impl AlertSinks {
+    pub fn with(mut self, channel: AlertChannel, sink: impl AlertSink + 'static) -> Self {
+        self.0.insert(channel, Arc::new(sink));
+        self
+    }
+
+    pub fn get(&self, channel: AlertChannel) -> Option<Arc<dyn AlertSink>> {
+        self.0.get(&channel).cloned()
+    }
+}
+
+// ====================== RULES ===============================================
+
+/// Evaluates the alert rules of a counter after it changed, firing them through their sinks.
+#[derive(Clone)]
+pub struct AlertsEngine {
+    repo: Repository,
+    store: EventStore,
+    sinks: AlertSinks,
+}
+
+// How many events are loaded at once to compute daily totals.
+const EVENTS_PAGE_SIZE: u64 = 500;
+
+impl AlertsEngine {
+    pub fn new(repo: &Repository, store: &EventStore, sinks: AlertSinks) -> Self {
+        Self {
+            repo: repo.clone(),
+            store: store.clone(),
+            sinks,
+        }
+    }
+
+    /// `counter` has the projected value, after a change of `delta`.
+    pub async fn evaluate(&self, counter: &Counter, delta: i32) -> Result<(), DbError> {
+        let rules = self
+            .repo
+            .list_alert_rules_by_counter(counter.id.clone())
+            .await?;
+
+        for rule in rules {
+            let rule: AlertRule = match rule.try_into() {
+                Ok(rule) => rule,
+                Err(err) => {
+                    warn!("Skipping alert rule: {}", err);
+                    continue;
+                }
+            };
+
+            let message = match rule.condition {
+                AlertCondition::TodayAbove(threshold) => {
+                    let today = self.today_total(&counter.id).await?;
+                    if today <= threshold {
+                        continue;
+                    }
+                    format!("{}: {} is {} today", rule.name, counter.name, today)
+                }
+                condition => {
+                    if !condition_met(condition, counter.value - delta, counter.value) {
+                        continue;
+                    }
+                    format!("{}: {} is {}", rule.name, counter.name, counter.value)
+                }
+            };
+
+            if self.is_debounced(&rule).await? {
+                continue;
+            }
+
+            self.fire(&rule, counter, message).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn fire(&self, rule: &AlertRule, counter: &Counter, message: String) -> Result<(), DbError> {
+        let mut alert = Alert {
+            id: uuid::Uuid::new_v4().to_string(),
+            rule_id: rule.id.clone(),
+            counter_id: counter.id.clone(),
+            message,
+            value: counter.value,
+            channel: rule.channel,
+            error: None,
+            fired_at: Utc::now(),
+        };
+
+        let Some(sink) = self.sinks.get(rule.channel) else {
+            let err = format!("no sink configured for {}", rule.channel.as_str());
+            warn!("Cannot send alert {} for rule {}: {}", alert.id, rule.id, err);
+            alert.error = Some(err);
+            self.repo.insert_alert(alert.into()).await?;
+            return Ok(());
+        };
+
+        // Before sending, so that sinks working in the background can record their errors on it
+        self.repo.insert_alert(alert.clone().into()).await?;
+
+        if let Err(err) = sink.send(rule, &alert).await {
+            warn!("Cannot send alert {} for rule {}: {}", alert.id, rule.id, err);
+            self.repo.update_alert_error(alert.id, err).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn is_debounced(&self, rule: &AlertRule) -> Result<bool, DbError> {
+        if rule.debounce_secs <= 0 {
+            return Ok(false);
+        }
+
+        let last = self.repo.find_last_alert_by_rule(rule.id.clone()).await?;
+        let window = Duration::seconds(rule.debounce_secs as i64);
+
+        Ok(last.is_some_and(|alert| Utc::now() - alert.fired_at < window))
+    }
+
+    async fn today_total(&self, counter_id: &str) -> Result<i32, DbError> {
+        let midnight = Utc::now()
+            .date_naive()
+            .and_hms_opt(0, 0, 0)
+            .unwrap()
+            .and_utc();
+
+        let mut filter = EventFilter {
+            limit: EVENTS_PAGE_SIZE,
+            aggregate_id: Some(counter_id.to_string()),
+            from: Some(midnight),
+            ..Default::default()
+        };
+
+        let mut total = 0;
+        loop {
+            let events = self.store.stream_events(&filter).await?;
+            let Some(last) = events.last() else {
+                return Ok(total);
+            };
+            filter.after = last.sequence;
+
+            total += events
+                .into_iter()
+                .filter_map(|stored| {
+                    serde_json::from_value::<CounterEvent>(stored.event.payload).ok()
+                })
+                .map(|evt| evt.delta())
+                .sum::<i32>();
+        }
+    }
+}
+
+fn condition_met(condition: AlertCondition, previous: i32, value: i32) -> bool {
+    match condition {
+        AlertCondition::ValueAbove(threshold) => value > threshold,
+        AlertCondition::ValueBelow(threshold) => value < threshold,
+        AlertCondition::ValueReaches(threshold) => {
+            (previous < threshold && value >= threshold)
+                || (previous > threshold && value <= threshold)
+        }
+        // needs the event history, see `AlertsEngine::today_total`
+        AlertCondition::TodayAbove(_) => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use tokio::{
+        io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+        net::TcpListener,
+    };
+
+    use super::*;
+    use crate::test_repo;
+
+    /// Records the alerts handed over to it.
+    #[derive(Clone, Default)]
+    struct RecordingSink(Arc<Mutex<Vec<String>>>);
+
+    #[async_trait]
+    impl AlertSink for RecordingSink {
+        async fn send(&self, _rule: &AlertRule, alert: &Alert) -> Result<(), String> {
+            self.0.lock().unwrap().push(alert.message.clone());
+            Ok(())
+        }
+    }
+
+    async fn new_rule(repo: &Repository, condition: AlertCondition, debounce_secs: i32) -> Counter {
+        let counter = Counter::new("Coffee".to_string(), 0);
+        let rule = AlertRule::new(
+            counter.id.clone(),
+            "Too much".to_string(),
+            condition,
+            AlertChannel::InApp,
+            String::new(),
+            debounce_secs,
+        );
+        repo.insert_alert_rule(rule.into()).await.unwrap();
+
+        counter
+    }
+
+    #[test]
+    fn checks_value_conditions() {
+        assert!(condition_met(AlertCondition::ValueAbove(3), 3, 4));
+        assert!(!condition_met(AlertCondition::ValueAbove(3), 2, 3));
+        assert!(condition_met(AlertCondition::ValueBelow(0), 0, -1));
+        assert!(!condition_met(AlertCondition::ValueBelow(0), 1, 0));
+        assert!(!condition_met(AlertCondition::TodayAbove(0), 0, 10));
+    }
+
+    #[test]
+    fn fires_when_reaching_the_threshold_only() {
+        let reaches = AlertCondition::ValueReaches(5);
+
+        assert!(condition_met(reaches, 4, 5));
+        assert!(condition_met(reaches, 6, 5));
+        // Jumping over the threshold counts too, eg. when setting the value
+        assert!(condition_met(reaches, 0, 10));
+        assert!(condition_met(reaches, 10, 0));
+
+        assert!(!condition_met(reaches, 5, 6));
+        assert!(!condition_met(reaches, 5, 5));
+        assert!(!condition_met(reaches, 3, 4));
+    }
+
+    #[tokio::test]
+    async fn debounces_alerts_of_a_rule() {
+        let repo = test_repo().await;
+        let sink = RecordingSink::default();
+        let sinks = AlertSinks::default().with(AlertChannel::InApp, sink.clone());
+        let engine = AlertsEngine::new(&repo, &EventStore::in_memory(), sinks);
+
+        let mut counter = new_rule(&repo, AlertCondition::ValueAbove(0), 3600).await;
+        counter.value = 1;
+        engine.evaluate(&counter, 1).await.unwrap();
+        counter.value = 2;
+        engine.evaluate(&counter, 1).await.unwrap();
+
+        assert_eq!(*sink.0.lock().unwrap(), vec!["Too much: Coffee is 1"]);
+
+        let mut counter = new_rule(&repo, AlertCondition::ValueAbove(0), 0).await;
+        counter.value = 1;
+        engine.evaluate(&counter, 1).await.unwrap();
+        engine.evaluate(&counter, 0).await.unwrap();
+
+        assert_eq!(sink.0.lock().unwrap().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn records_alerts_without_a_sink() {
+        let repo = test_repo().await;
+        let engine = AlertsEngine::new(&repo, &EventStore::in_memory(), AlertSinks::default());
+
+        let mut counter = new_rule(&repo, AlertCondition::ValueBelow(0), 0).await;
+        counter.value = -1;
+        engine.evaluate(&counter, -1).await.unwrap();
+
+        let alerts: Vec<Alert> = repo
+            .list_alerts()
+            .await
+            .unwrap()
+            .into_iter()
+            .filter(|alert| alert.counter_id == counter.id)
+            .map(|alert| alert.try_into().unwrap())
+            .collect();
+        assert!(matches!(
+            alerts.as_slice(),
+            [alert] if alert.error.as_deref() == Some("no sink configured for in_app")
+        ));
+    }
+
+    /// Accepts one email, just enough of SMTP for lettre, and returns its DATA.
+    async fn smtp_server(listener: TcpListener) -> String {
+        let (socket, _) = listener.accept().await.unwrap();
+        let (read, mut write) = socket.into_split();
+        let mut lines = BufReader::new(read).lines();
+
+        write.write_all(b"220 localhost ESMTP\r\n").await.unwrap();
+        let mut data = String::new();
+        while let Some(line) = lines.next_line().await.unwrap() {
+            let command = line.to_ascii_uppercase();
+            let reply: &[u8] = if command.starts_with("EHLO") {
+                b"250-localhost\r\n250 8BITMIME\r\n"
+            } else if command.starts_with("DATA") {
+                write.write_all(b"354 go ahead\r\n").await.unwrap();
+                while let Some(line) = lines.next_line().await.unwrap() {
+                    if line == "." {
+                        break;
+                    }
+                    data.push_str(&line);
+                    data.push('\n');
+                }
+                b"250 queued\r\n"
+            } else if command.starts_with("QUIT") {
+                write.write_all(b"221 bye\r\n").await.unwrap();
+                break;
+            } else {
+                b"250 ok\r\n"
+            };
+            write.write_all(reply).await.unwrap();
+        }
+
+        data
+    }
+
+    fn smtp_options(port: u16) -> SmtpOptions {
+        SmtpOptions {
+            host: "127.0.0.1".to_string(),
+            port,
+            from: "kountr@example.com".to_string(),
+            username: None,
+            password: None,
+            tls: false,
+        }
+    }
+
+    #[tokio::test]
+    async fn emails_alerts() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = tokio::spawn(smtp_server(listener));
+
+        let sink = EmailSink::new(&test_repo().await, &smtp_options(port)).unwrap();
+
+        let mut rule = AlertRule::new(
+            "c1".to_string(),
+            "Too much".to_string(),
+            AlertCondition::ValueAbove(0),
+            AlertChannel::Email,
+            "me@example.com".to_string(),
+            0,
+        );
+        let alert = Alert {
+            id: "a1".to_string(),
+            rule_id: rule.id.clone(),
+            counter_id: "c1".to_string(),
+            message: "Too much: Coffee is 1".to_string(),
+            value: 1,
+            channel: AlertChannel::Email,
+            error: None,
+            fired_at: Utc::now(),
+        };
+        sink.send(&rule, &alert).await.unwrap();
+
+        let data = server.await.unwrap();
+        assert!(data.contains("From: kountr@example.com"), "{}", data);
+        assert!(data.contains("To: me@example.com"), "{}", data);
+        assert!(data.contains("Subject: [kountr] Too much"), "{}", data);
+        assert!(data.contains("Too much: Coffee is 1"), "{}", data);
+
+        rule.target = "not an address".to_string();
+        let err = sink.send(&rule, &alert).await.unwrap_err();
+        assert!(err.starts_with("invalid recipient address not an address"), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn records_emails_failing_in_the_background() {
+        // Nothing listens there anymore
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        drop(listener);
+
+        let repo = test_repo().await;
+        let sink = EmailSink::new(&repo, &smtp_options(port)).unwrap();
+        let sinks = AlertSinks::default().with(AlertChannel::Email, sink.clone());
+        let engine = AlertsEngine::new(&repo, &EventStore::in_memory(), sinks);
+
+        let mut counter = Counter::new("Coffee".to_string(), 1);
+        let rule = AlertRule::new(
+            counter.id.clone(),
+            "Too much".to_string(),
+            AlertCondition::ValueAbove(0),
+            AlertChannel::Email,
+            "me@example.com".to_string(),
+            0,
+        );
+        repo.insert_alert_rule(rule.into()).await.unwrap();
+        counter.value = 2;
+        engine.evaluate(&counter, 1).await.unwrap();
+        sink.drain().await;
+
+        let alert = repo
+            .list_alerts()
+            .await
+            .unwrap()
+            .into_iter()
+            .find(|alert| alert.counter_id == counter.id)
+            .unwrap();
+        assert!(alert.error.is_some());
+    }
+}
@@ -4,11 +4,25 @@ use serde::{Deserialize, Serialize};
 
 use super::{CounterEvent, CounterCommand};
 
+const COUNTER_NOT_FOUND: &str = "counter not found";
+
+/// The error of commands on counters that don't exist, or don't anymore.
+pub fn counter_not_found() -> CqrsError {
+    CqrsError::new(COUNTER_NOT_FOUND.to_string())
+}
+
+/// Whether a command has been rejected because its counter doesn't exist.
+pub fn is_counter_not_found(err: &CqrsError) -> bool {
+    err.to_string() == COUNTER_NOT_FOUND
+}
+
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct CounterState {
     id: String,
     name: String,
     value: i32,
+    created: bool,
+    deleted: bool,
 }
 
 impl Default for CounterState {
@@ -17,6 +31,8 @@ impl Default for CounterState {
             id: uuid::Uuid::new_v4().to_string(),
             value: 0,
             name: "Default counter".to_string(),
+            created: false,
+            deleted: false,
         }
     }
 }
@@ -28,6 +44,11 @@ impl Aggregate for CounterState {
     type Id = String;
 
     async fn handle(&self, command: CounterCommand) -> Result<Vec<Event>, CqrsError> {
+        let exists = self.created && !self.deleted;
+        if !exists && !matches!(command, CounterCommand::Create { .. }) {
+            return Err(counter_not_found());
+        }
+
         match command {
             CounterCommand::Create { name, value } => Ok(vec![CounterEvent::CounterCreated {
                 aggregate_id: self.id.clone(),
@@ -66,23 +87,24 @@ impl Aggregate for CounterState {
     fn apply(&mut self, event: &Self::Event) {
         match event {
             CounterEvent::CounterCreated {
-                aggregate_id, name, ..
+                aggregate_id,
+                name,
+                value,
             } => {
                 self.id = aggregate_id.clone();
-                self.value = 0;
+                self.value = *value;
                 self.name = name.clone();
+                self.created = true;
             }
-            CounterEvent::CounterIncremented { amount, .. } => {
-                self.value += amount;
-            }
-            CounterEvent::CounterDecremented { amount, .. } => {
-                self.value -= amount;
+            CounterEvent::CounterIncremented { .. } | CounterEvent::CounterDecremented { .. } => {
+                // decrements are stored with a negative amount, as for the projection
+                self.value += event.delta();
             }
             CounterEvent::CounterUpdated { name, value, .. } => {
                 self.name = name.clone();
                 self.value = value.clone();
             }
-            CounterEvent::CounterDeleted { .. } => {}
+            CounterEvent::CounterDeleted { .. } => self.deleted = true,
         }
     }
 
@@ -94,3 +116,174 @@ impl Aggregate for CounterState {
         self.id = id.clone();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn apply_all(state: &mut CounterState, events: Vec<CounterEvent>) {
+        for event in events {
+            state.apply(&event);
+        }
+    }
+
+    #[tokio::test]
+    async fn create_is_for_the_aggregate_id() {
+        let mut state = CounterState::default();
+        state.set_aggregate_id("c1".to_string());
+
+        let events = state
+            .handle(CounterCommand::Create {
+                name: "Coffee".to_string(),
+                value: 3,
+            })
+            .await
+            .unwrap();
+
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].aggregate_id, "c1");
+        assert_eq!(
+            events[0].get_payload::<CounterEvent>(),
+            CounterEvent::CounterCreated {
+                aggregate_id: "c1".to_string(),
+                name: "Coffee".to_string(),
+                value: 3,
+            }
+        );
+    }
+
+    fn created(id: &str) -> CounterState {
+        let mut state = CounterState::default();
+        state.apply(&CounterEvent::CounterCreated {
+            aggregate_id: id.to_string(),
+            name: "Coffee".to_string(),
+            value: 0,
+        });
+
+        state
+    }
+
+    #[tokio::test]
+    async fn commands_emit_their_events() {
+        let id = "c1".to_string();
+        let state = created(&id);
+
+        let cases = vec![
+            (
+                CounterCommand::Increment {
+                    id: id.clone(),
+                    amount: 1,
+                },
+                "CounterIncremented",
+            ),
+            (
+                CounterCommand::Decrement {
+                    id: id.clone(),
+                    amount: -1,
+                },
+                "CounterDecremented",
+            ),
+            (
+                CounterCommand::Update {
+                    id: id.clone(),
+                    name: "Tea".to_string(),
+                    value: 7,
+                },
+                "CounterUpdated",
+            ),
+            (CounterCommand::Delete { id: id.clone() }, "CounterDeleted"),
+        ];
+
+        for (command, event_type) in cases {
+            let events = state.handle(command).await.unwrap();
+
+            assert_eq!(events.len(), 1);
+            assert_eq!(events[0].event_type, event_type);
+            assert_eq!(events[0].aggregate_id, id);
+        }
+    }
+
+    #[tokio::test]
+    async fn rejects_commands_on_missing_or_deleted_counters() {
+        let mut deleted = created("c1");
+        deleted.apply(&CounterEvent::CounterDeleted {
+            aggregate_id: "c1".to_string(),
+        });
+
+        for state in [CounterState::default(), deleted] {
+            let err = state
+                .handle(CounterCommand::Increment {
+                    id: "c1".to_string(),
+                    amount: 1,
+                })
+                .await
+                .unwrap_err();
+            assert!(is_counter_not_found(&err));
+
+            let delete = state.handle(CounterCommand::Delete { id: "c1".to_string() }).await;
+            assert!(delete.is_err());
+        }
+    }
+
+    #[test]
+    fn applies_events_in_order() {
+        let id = "c1".to_string();
+        let mut state = CounterState::default();
+
+        apply_all(
+            &mut state,
+            vec![
+                CounterEvent::CounterCreated {
+                    aggregate_id: id.clone(),
+                    name: "Coffee".to_string(),
+                    value: 3,
+                },
+                CounterEvent::CounterIncremented {
+                    aggregate_id: id.clone(),
+                    amount: 1,
+                },
+                CounterEvent::CounterIncremented {
+                    aggregate_id: id.clone(),
+                    amount: 1,
+                },
+                CounterEvent::CounterDecremented {
+                    aggregate_id: id.clone(),
+                    amount: -1,
+                },
+            ],
+        );
+
+        assert_eq!(state.aggregate_id(), id);
+        assert_eq!(state.name, "Coffee");
+        assert_eq!(state.value, 4);
+    }
+
+    #[test]
+    fn update_replaces_name_and_value() {
+        let id = "c1".to_string();
+        let mut state = CounterState::default();
+
+        apply_all(
+            &mut state,
+            vec![
+                CounterEvent::CounterCreated {
+                    aggregate_id: id.clone(),
+                    name: "Coffee".to_string(),
+                    value: 3,
+                },
+                CounterEvent::CounterUpdated {
+                    aggregate_id: id.clone(),
+                    name: "Tea".to_string(),
+                    value: 10,
+                },
+                CounterEvent::CounterIncremented {
+                    aggregate_id: id.clone(),
+                    amount: 1,
+                },
+            ],
+        );
+
+        assert_eq!(state.name, "Tea");
+        assert_eq!(state.value, 11);
+    }
+}
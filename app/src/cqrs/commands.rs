@@ -21,3 +21,16 @@ pub enum CounterCommand {
         id: String,
     },
 }
+
+impl CounterCommand {
+    /// The variant name, eg. for metrics.
+    pub fn name(&self) -> &'static str {
+        match self {
+            CounterCommand::Create { .. } => "Create",
+            CounterCommand::Increment { .. } => "Increment",
+            CounterCommand::Decrement { .. } => "Decrement",
+            CounterCommand::Update { .. } => "Update",
+            CounterCommand::Delete { .. } => "Delete",
+        }
+    }
+}
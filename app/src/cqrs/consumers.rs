@@ -1,26 +1,34 @@
+use std::fmt::Display;
+
 use async_trait::async_trait;
 use mini_cqrs::*;
+use tokio::sync::broadcast;
+use tracing::warn;
 
+use crate::alerts::AlertsEngine;
 use crate::domain::models;
-use kountr_db::repository::Repository;
+use crate::projections::{Projection, ProjectionHandle};
+use crate::webhooks::{WebhookMessage, WebhookSender};
+use kountr_db::{error::DbError, event_store::SequencedEvent, repository::Repository};
 
-use super::{CounterView, CounterEvent};
+use super::{CounterEvent, CounterReadStore, CounterStore, CounterView};
 
 #[derive(Clone)]
-pub struct CounterEventConsumer {
-    pub counter_model: CounterView,
+pub struct CounterEventConsumer<S> {
+    pub counter_model: CounterView<S>,
 }
 
-impl CounterEventConsumer {
-    pub fn new(repo: &Repository) -> Self {
+impl<S: CounterReadStore + mini_cqrs::Repository + Clone> CounterEventConsumer<S> {
+    pub fn new(store: &S) -> Self {
         Self {
-            counter_model: CounterView::new(repo),
+            counter_model: CounterView::new(store),
         }
     }
 }
 
 #[async_trait]
-impl EventConsumer for CounterEventConsumer {
+impl<S: CounterReadStore + mini_cqrs::Repository + Clone> EventConsumer for CounterEventConsumer<S> {
+    #[tracing::instrument(name = "consumer", skip_all, fields(consumer = "counters", event = %evt.event_type))]
     async fn process(&mut self, evt: Event) {
         let event = evt.get_payload::<CounterEvent>();
         match event {
@@ -30,62 +38,359 @@ impl EventConsumer for CounterEventConsumer {
                 value,
             } => {
                 let counter = models::Counter::new_with_id(aggregate_id.clone(), name, value);
-                _ = self.counter_model.update(counter).await;
+                ok_or_record("counters", self.counter_model.update(counter).await);
             }
             CounterEvent::CounterIncremented {
                 aggregate_id,
                 amount,
             } => {
-                let mut counter = self
-                    .counter_model
-                    .repo
-                    .find_counter_by_id(aggregate_id.clone())
-                    .await
-                    .unwrap();
+                // the projection worker must not die on events of deleted counters
+                let Ok(mut counter) = self.counter_model.find(&aggregate_id).await else {
+                    return;
+                };
                 counter.value += amount;
-                _ = self.counter_model.update(counter.into()).await;
+                ok_or_record("counters", self.counter_model.update(counter).await);
             }
             CounterEvent::CounterDecremented {
                 aggregate_id,
                 amount,
             } => {
-                let mut counter = self
-                    .counter_model
-                    .repo
-                    .find_counter_by_id(aggregate_id.clone())
-                    .await
-                    .unwrap();
+                let Ok(mut counter) = self.counter_model.find(&aggregate_id).await else {
+                    return;
+                };
                 counter.value += amount;
-                _ = self.counter_model.update(counter.into()).await;
+                ok_or_record("counters", self.counter_model.update(counter).await);
             }
             CounterEvent::CounterUpdated {
                 aggregate_id,
                 name,
                 value,
             } => {
-                let mut counter = self
-                    .counter_model
-                    .repo
-                    .find_counter_by_id(aggregate_id.clone())
-                    .await
-                    .unwrap();
+                let Ok(mut counter) = self.counter_model.find(&aggregate_id).await else {
+                    return;
+                };
                 counter.name = name;
                 counter.value = value;
-                _ = self.counter_model.update(counter.into()).await;
+                ok_or_record("counters", self.counter_model.update(counter).await);
+            }
+            CounterEvent::CounterDeleted { aggregate_id } => {
+                ok_or_record("counters", self.counter_model.delete(&aggregate_id).await);
             }
+        }
+    }
+}
+
+/// Builds the counters read model from the event log, see `ProjectionWorker`.
+#[derive(Clone)]
+pub struct CounterProjection<S> {
+    store: S,
+}
+
+impl<S: CounterReadStore + Clone> CounterProjection<S> {
+    pub fn new(store: &S) -> Self {
+        Self {
+            store: store.clone(),
+        }
+    }
+}
+
+#[async_trait]
+impl<S: CounterReadStore + Clone> Projection for CounterProjection<S> {
+    async fn checkpoint(&self, name: &str) -> Result<i64, DbError> {
+        self.store.find_checkpoint(name).await
+    }
+
+    #[tracing::instrument(name = "consumer", skip_all, fields(consumer = "counters", event = %stored.event.event_type))]
+    async fn apply(&self, name: &str, after: i64, stored: &SequencedEvent) -> Result<(), DbError> {
+        let event: CounterEvent = serde_json::from_value(stored.event.payload.clone())
+            .map_err(|err| DbError::Invalid(format!("event {}: {}", stored.event.id, err)))?;
+
+        self.store
+            .project(name, after, stored.sequence, event.into())
+            .await
+    }
+}
+
+/// Waits for the projection worker to process the saved events, so that the
+/// `CounterProjection` is fed in order from the checkpointed event log.
+#[derive(Clone)]
+pub struct ProjectionEventConsumer {
+    handle: ProjectionHandle,
+}
+
+impl ProjectionEventConsumer {
+    pub fn new(handle: ProjectionHandle) -> Self {
+        Self { handle }
+    }
+}
+
+#[async_trait]
+impl EventConsumer for ProjectionEventConsumer {
+    #[tracing::instrument(name = "consumer", skip_all, fields(consumer = "projection", event = %_evt.event_type))]
+    async fn process(&mut self, _evt: Event) {
+        self.handle.sync().await;
+    }
+}
+
+/// Publishes projected counter changes to live subscribers (eg. websockets).
+/// It must run after `CounterEventConsumer`, so that it reads updated values.
+#[derive(Clone)]
+pub struct BroadcastEventConsumer<S> {
+    store: S,
+    tx: broadcast::Sender<models::CounterChange>,
+}
+
+impl<S: CounterReadStore + Clone> BroadcastEventConsumer<S> {
+    pub fn new(store: &S, tx: broadcast::Sender<models::CounterChange>) -> Self {
+        Self {
+            store: store.clone(),
+            tx,
+        }
+    }
+}
+
+#[async_trait]
+impl<S: CounterReadStore + Clone> EventConsumer for BroadcastEventConsumer<S> {
+    #[tracing::instrument(name = "consumer", skip_all, fields(consumer = "broadcast", event = %evt.event_type))]
+    async fn process(&mut self, evt: Event) {
+        let change = match evt.get_payload::<CounterEvent>() {
             CounterEvent::CounterDeleted { aggregate_id } => {
-                _ = self
-                    .counter_model
-                    .repo
-                    .delete_counter(aggregate_id.clone())
-                    .await;
+                models::CounterChange::Deleted(aggregate_id)
             }
+            event => match self.store.find_counter(&event.aggregate_id()).await {
+                Ok(counter) => models::CounterChange::Updated(counter),
+                Err(_) => return,
+            },
+        };
+
+        // no subscribers is not an error
+        _ = self.tx.send(change);
+    }
+}
+
+/// Delivers events to the subscribed webhooks. Deliveries run in background, so
+/// that slow or failing receivers don't hold up commands.
+#[derive(Clone)]
+pub struct WebhookEventConsumer {
+    repo: Repository,
+    sender: WebhookSender,
+}
+
+impl WebhookEventConsumer {
+    pub fn new(repo: &Repository, sender: WebhookSender) -> Self {
+        Self {
+            repo: repo.clone(),
+            sender,
         }
     }
 }
 
+#[async_trait]
+impl EventConsumer for WebhookEventConsumer {
+    #[tracing::instrument(name = "consumer", skip_all, fields(consumer = "webhooks", event = %evt.event_type))]
+    async fn process(&mut self, evt: Event) {
+        let Some(webhooks) = ok_or_record("webhooks", self.repo.list_webhooks().await) else {
+            return;
+        };
+
+        let message = WebhookMessage::from(&evt);
+        for webhook in webhooks.into_iter().map(models::Webhook::from) {
+            if !webhook.accepts(&evt.event_type) {
+                continue;
+            }
+
+            self.sender.spawn_deliver(webhook, message.clone());
+        }
+    }
+}
+
+/// Evaluates alert rules after counter values change. It must run after
+/// `CounterEventConsumer`, so that rules see updated values.
+#[derive(Clone)]
+pub struct AlertEventConsumer<S> {
+    store: S,
+    engine: AlertsEngine,
+}
+
+impl<S: CounterReadStore + Clone> AlertEventConsumer<S> {
+    pub fn new(store: &S, engine: AlertsEngine) -> Self {
+        Self {
+            store: store.clone(),
+            engine,
+        }
+    }
+}
+
+#[async_trait]
+impl<S: CounterReadStore + Clone> EventConsumer for AlertEventConsumer<S> {
+    #[tracing::instrument(name = "consumer", skip_all, fields(consumer = "alerts", event = %evt.event_type))]
+    async fn process(&mut self, evt: Event) {
+        let event = evt.get_payload::<CounterEvent>();
+        match event {
+            CounterEvent::CounterIncremented { ref aggregate_id, .. }
+            | CounterEvent::CounterDecremented { ref aggregate_id, .. } => {
+                if let Ok(counter) = self.store.find_counter(aggregate_id).await {
+                    ok_or_record("alerts", self.engine.evaluate(&counter, event.delta()).await);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Consumers can't fail the command that stored the event, errors are only logged and counted.
+fn ok_or_record<T, E: Display>(consumer: &'static str, result: Result<T, E>) -> Option<T> {
+    result
+        .map_err(|err| {
+            warn!("Consumer {} failed: {}", consumer, err);
+            metrics::increment_counter!("kountr_consumer_errors_total", "consumer" => consumer);
+        })
+        .ok()
+}
+
+// The app picks the read store at startup, see `CounterStore`.
+pub type AppCounterEventConsumer = CounterEventConsumer<CounterStore>;
+pub type AppBroadcastEventConsumer = BroadcastEventConsumer<CounterStore>;
+pub type AppAlertEventConsumer = AlertEventConsumer<CounterStore>;
+
 event_consumers_group! {
     MainEventConsumers {
-        Counter => CounterEventConsumer,
+        Counter => AppCounterEventConsumer,
+        Projection => ProjectionEventConsumer,
+        Broadcast => AppBroadcastEventConsumer,
+        Webhook => WebhookEventConsumer,
+        Alert => AppAlertEventConsumer,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cqrs::InMemoryCounterStore;
+
+    fn created(id: &str, value: i32) -> Event {
+        CounterEvent::CounterCreated {
+            aggregate_id: id.to_string(),
+            name: "Coffee".to_string(),
+            value,
+        }
+        .into()
+    }
+
+    fn incremented(id: &str) -> Event {
+        CounterEvent::CounterIncremented {
+            aggregate_id: id.to_string(),
+            amount: 1,
+        }
+        .into()
+    }
+
+    fn decremented(id: &str) -> Event {
+        CounterEvent::CounterDecremented {
+            aggregate_id: id.to_string(),
+            amount: -1,
+        }
+        .into()
+    }
+
+    #[tokio::test]
+    async fn counter_consumer_projects_counters() {
+        let store = InMemoryCounterStore::default();
+        let mut consumer = CounterEventConsumer::new(&store);
+
+        consumer.process(created("c1", 3)).await;
+        consumer.process(incremented("c1")).await;
+        consumer.process(incremented("c1")).await;
+        consumer.process(decremented("c1")).await;
+
+        let counter = store.find_counter("c1").await.unwrap();
+        assert_eq!(counter.name, "Coffee");
+        assert_eq!(counter.value, 4);
+
+        consumer
+            .process(
+                CounterEvent::CounterUpdated {
+                    aggregate_id: "c1".to_string(),
+                    name: "Tea".to_string(),
+                    value: 10,
+                }
+                .into(),
+            )
+            .await;
+
+        let counter = store.find_counter("c1").await.unwrap();
+        assert_eq!(counter.name, "Tea");
+        assert_eq!(counter.value, 10);
+
+        consumer
+            .process(
+                CounterEvent::CounterDeleted {
+                    aggregate_id: "c1".to_string(),
+                }
+                .into(),
+            )
+            .await;
+
+        assert!(matches!(
+            store.find_counter("c1").await,
+            Err(kountr_db::error::DbError::NotFound)
+        ));
+    }
+
+    #[tokio::test]
+    async fn counter_consumer_skips_unknown_counters() {
+        let store = InMemoryCounterStore::default();
+        let mut consumer = CounterEventConsumer::new(&store);
+
+        consumer.process(incremented("missing")).await;
+
+        assert!(store.list_counters().await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn broadcast_consumer_publishes_projected_changes() {
+        let store = InMemoryCounterStore::default();
+        let (tx, mut rx) = broadcast::channel(8);
+        let mut projection = CounterEventConsumer::new(&store);
+        let mut broadcast = BroadcastEventConsumer::new(&store, tx);
+
+        for event in [created("c1", 0), incremented("c1")] {
+            projection.process(event.clone()).await;
+            broadcast.process(event).await;
+        }
+
+        match rx.recv().await.unwrap() {
+            models::CounterChange::Updated(counter) => assert_eq!(counter.value, 0),
+            change => panic!("unexpected change: {:?}", change),
+        }
+        match rx.recv().await.unwrap() {
+            models::CounterChange::Updated(counter) => assert_eq!(counter.value, 1),
+            change => panic!("unexpected change: {:?}", change),
+        }
+
+        broadcast
+            .process(
+                CounterEvent::CounterDeleted {
+                    aggregate_id: "c1".to_string(),
+                }
+                .into(),
+            )
+            .await;
+
+        assert!(matches!(
+            rx.recv().await.unwrap(),
+            models::CounterChange::Deleted(id) if id == "c1"
+        ));
+    }
+
+    #[tokio::test]
+    async fn broadcast_consumer_skips_unknown_counters() {
+        let store = InMemoryCounterStore::default();
+        let (tx, mut rx) = broadcast::channel(8);
+        let mut broadcast = BroadcastEventConsumer::new(&store, tx);
+
+        broadcast.process(incremented("missing")).await;
+
+        assert!(rx.try_recv().is_err());
     }
 }
@@ -1,3 +1,4 @@
+use kountr_db::{repository::CounterUpdate, upcasting::Upcasters};
 use mini_cqrs::*;
 use serde::{Deserialize, Serialize};
 
@@ -38,6 +39,71 @@ impl ToString for CounterEvent {
     }
 }
 
+impl CounterEvent {
+    /// Upgrades stored payloads to the current shape of the events, so that
+    /// old ones keep loading after a variant changes. Every change needs a step
+    /// for its event type, eg. when adding a `step` field to increments:
+    ///
+    /// ```ignore
+    /// .with("CounterIncremented", |mut payload| {
+    ///     payload["CounterIncremented"]["step"] = 1.into();
+    ///     payload
+    /// })
+    /// ```
+    pub fn upcasters() -> Upcasters {
+        Upcasters::default()
+    }
+
+    /// How much the event changes the counter value, as applied by the projection.
+    pub fn delta(&self) -> i32 {
+        match self {
+            CounterEvent::CounterIncremented { amount, .. } => *amount,
+            CounterEvent::CounterDecremented { amount, .. } => *amount,
+            _ => 0,
+        }
+    }
+}
+
+/// The change of the counters read model, see `CounterProjection`.
+impl From<CounterEvent> for CounterUpdate {
+    fn from(event: CounterEvent) -> Self {
+        match event {
+            CounterEvent::CounterCreated {
+                aggregate_id,
+                name,
+                value,
+            } => CounterUpdate::Create {
+                id: aggregate_id,
+                name,
+                value,
+            },
+            CounterEvent::CounterIncremented {
+                aggregate_id,
+                amount,
+            }
+            | CounterEvent::CounterDecremented {
+                aggregate_id,
+                amount,
+            } => CounterUpdate::Add {
+                id: aggregate_id,
+                amount,
+            },
+            CounterEvent::CounterUpdated {
+                aggregate_id,
+                name,
+                value,
+            } => CounterUpdate::Update {
+                id: aggregate_id,
+                name,
+                value,
+            },
+            CounterEvent::CounterDeleted { aggregate_id } => {
+                CounterUpdate::Delete { id: aggregate_id }
+            }
+        }
+    }
+}
+
 impl EventPayload for CounterEvent {
     fn aggregate_id(&self) -> String {
         match self {
@@ -51,3 +117,70 @@ impl EventPayload for CounterEvent {
 }
 
 wrap_event!(CounterEvent);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Payloads as they've been stored since the first release (schema version 1).
+    const V1_FIXTURES: &str = include_str!("fixtures/counter_events_v1.json");
+
+    #[derive(Deserialize)]
+    struct Fixture {
+        event_type: String,
+        schema_version: i32,
+        payload: serde_json::Value,
+        expected: CounterEvent,
+    }
+
+    #[test]
+    fn historical_payloads_load_as_current_events() {
+        let fixtures: Vec<Fixture> = serde_json::from_str(V1_FIXTURES).unwrap();
+        let upcasters = CounterEvent::upcasters();
+
+        for fixture in fixtures {
+            let payload = upcasters
+                .upcast(&fixture.event_type, fixture.schema_version, fixture.payload)
+                .unwrap();
+            let event: CounterEvent = serde_json::from_value(payload).unwrap();
+
+            assert_eq!(event, fixture.expected);
+            assert_eq!(event.to_string(), fixture.event_type);
+        }
+    }
+
+    #[test]
+    fn fixtures_cover_every_event_type() {
+        let fixtures: Vec<Fixture> = serde_json::from_str(V1_FIXTURES).unwrap();
+        let mut types: Vec<String> = fixtures.into_iter().map(|f| f.event_type).collect();
+        types.sort();
+        types.dedup();
+
+        assert_eq!(
+            types,
+            vec![
+                "CounterCreated",
+                "CounterDecremented",
+                "CounterDeleted",
+                "CounterIncremented",
+                "CounterUpdated",
+            ]
+        );
+    }
+
+    #[test]
+    fn new_events_have_the_current_version() {
+        let upcasters = CounterEvent::upcasters();
+        let event = CounterEvent::CounterDeleted {
+            aggregate_id: "c1".to_string(),
+        };
+
+        let payload = serde_json::to_value(&event).unwrap();
+        let version = upcasters.current_version(&event.to_string());
+
+        assert_eq!(
+            upcasters.upcast(&event.to_string(), version, payload.clone()).unwrap(),
+            payload
+        );
+    }
+}
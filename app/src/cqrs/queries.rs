@@ -1,8 +1,8 @@
 use async_trait::async_trait;
 use mini_cqrs::*;
 
+use super::CounterReadStore;
 use crate::domain::models;
-use kountr_db::repository::Repository;
 
 #[derive(Clone)]
 pub struct AppQueries {}
@@ -11,50 +11,51 @@ pub struct AppQueries {}
 impl QueriesRunner for AppQueries {}
 
 #[derive(Clone)]
-pub struct GetCounterQuery {
+pub struct GetCounterQuery<S> {
     pub id: String,
-    repo: Repository,
+    store: S,
 }
 
-impl GetCounterQuery {
-    pub fn new(id: String, repo: &Repository) -> Self {
+impl<S: CounterReadStore + Clone> GetCounterQuery<S> {
+    pub fn new(id: String, store: &S) -> Self {
         Self {
             id,
-            repo: repo.clone(),
+            store: store.clone(),
         }
     }
 }
 
 #[async_trait]
-impl Query for GetCounterQuery {
+impl<S: CounterReadStore + Clone> Query for GetCounterQuery<S> {
     type Output = Result<Option<models::Counter>, CqrsError>;
 
     async fn apply(&self) -> Self::Output {
-        let model = self.repo.find_counter_by_id(self.id.clone()).await;
+        let counter = self.store.find_counter(&self.id).await;
 
-        Ok(Some(model.unwrap().into()))
+        Ok(counter.ok())
     }
 }
 
 #[derive(Clone)]
-pub struct ListCountersQuery {
-    repo: Repository,
+pub struct ListCountersQuery<S> {
+    store: S,
 }
 
-impl ListCountersQuery {
-    pub fn new(repo: &Repository) -> Self {
-        Self { repo: repo.clone() }
+impl<S: CounterReadStore + Clone> ListCountersQuery<S> {
+    pub fn new(store: &S) -> Self {
+        Self {
+            store: store.clone(),
+        }
     }
 }
 
 #[async_trait]
-impl Query for ListCountersQuery {
+impl<S: CounterReadStore + Clone> Query for ListCountersQuery<S> {
     type Output = Result<Vec<models::Counter>, CqrsError>;
 
     async fn apply(&self) -> Self::Output {
-        if let Ok(list) = self.repo.list_counters().await {
-            let result = list.into_iter().map(|c| c.into());
-            return Ok(result.collect());
+        if let Ok(list) = self.store.list_counters().await {
+            return Ok(list);
         }
         // TODO: handle errors
         Ok(vec![])
@@ -1,30 +1,299 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, RwLock},
+};
+
 use async_trait::async_trait;
 use mini_cqrs::*;
 
 use crate::domain::models;
-use kountr_db::repository::Repository;
+use kountr_db::{
+    error::DbError,
+    repository::{CounterUpdate, Repository},
+};
+
+/// Where the counters read model is kept, it's fed by `CounterEventConsumer`.
+///
+/// Consumers and queries are generic over it, so that other stores (eg. a
+/// Redis-compatible server or a file) can plug in by implementing it, along
+/// with `mini_cqrs::Repository` and `Clone`.
+#[async_trait]
+pub trait CounterReadStore: Send + Sync {
+    async fn find_counter(&self, id: &str) -> Result<models::Counter, DbError>;
+    async fn list_counters(&self) -> Result<Vec<models::Counter>, DbError>;
+    /// Up to `limit` counters ordered by id, following the `after` one (`None` to start over).
+    async fn list_counters_page(
+        &self,
+        after: Option<&str>,
+        limit: u64,
+    ) -> Result<Vec<models::Counter>, DbError>;
+    /// Inserts the counter, or replaces the one with the same id.
+    async fn save_counter(&self, counter: models::Counter) -> Result<(), DbError>;
+    async fn delete_counter(&self, id: &str) -> Result<(), DbError>;
+    /// The sequence of the last event applied by `projection`, 0 before the first one.
+    async fn find_checkpoint(&self, projection: &str) -> Result<i64, DbError>;
+    /// Applies the change of the event `sequence` and moves the checkpoint of `projection`
+    /// from `after` to it, all at once. Fails with `DbError::Conflict` when the checkpoint
+    /// isn't at `after` anymore.
+    async fn project(
+        &self,
+        projection: &str,
+        after: i64,
+        sequence: i64,
+        update: CounterUpdate,
+    ) -> Result<(), DbError>;
+}
+
+#[async_trait]
+impl CounterReadStore for Repository {
+    async fn find_counter(&self, id: &str) -> Result<models::Counter, DbError> {
+        let counter = self.find_counter_by_id(id.to_string()).await?;
+
+        Ok(counter.into())
+    }
+
+    async fn list_counters(&self) -> Result<Vec<models::Counter>, DbError> {
+        let counters = Repository::list_counters(self).await?;
+
+        Ok(counters.into_iter().map(Into::into).collect())
+    }
+
+    async fn list_counters_page(
+        &self,
+        after: Option<&str>,
+        limit: u64,
+    ) -> Result<Vec<models::Counter>, DbError> {
+        let counters = Repository::list_counters_page(self, after.map(str::to_string), limit).await?;
+
+        Ok(counters.into_iter().map(Into::into).collect())
+    }
+
+    async fn save_counter(&self, counter: models::Counter) -> Result<(), DbError> {
+        self.insert_or_update_counter(counter.into()).await?;
+
+        Ok(())
+    }
+
+    async fn delete_counter(&self, id: &str) -> Result<(), DbError> {
+        Repository::delete_counter(self, id.to_string()).await
+    }
+
+    async fn find_checkpoint(&self, projection: &str) -> Result<i64, DbError> {
+        Repository::find_checkpoint(self, projection).await
+    }
+
+    async fn project(
+        &self,
+        projection: &str,
+        after: i64,
+        sequence: i64,
+        update: CounterUpdate,
+    ) -> Result<(), DbError> {
+        self.project_counter(projection, after, sequence, update).await
+    }
+}
+
+/// Keeps counters in memory only, eg. for tests and ephemeral runs. Clones share the same counters.
+#[derive(Clone, Default)]
+pub struct InMemoryCounterStore {
+    counters: Arc<RwLock<Vec<models::Counter>>>,
+    checkpoints: Arc<RwLock<HashMap<String, i64>>>,
+}
+
+impl mini_cqrs::Repository for InMemoryCounterStore {}
+
+#[async_trait]
+impl CounterReadStore for InMemoryCounterStore {
+    async fn find_counter(&self, id: &str) -> Result<models::Counter, DbError> {
+        let counters = self.counters.read().unwrap();
+
+        counters
+            .iter()
+            .find(|counter| counter.id == id)
+            .cloned()
+            .ok_or(DbError::NotFound)
+    }
+
+    async fn list_counters(&self) -> Result<Vec<models::Counter>, DbError> {
+        Ok(self.counters.read().unwrap().clone())
+    }
+
+    async fn list_counters_page(
+        &self,
+        after: Option<&str>,
+        limit: u64,
+    ) -> Result<Vec<models::Counter>, DbError> {
+        let mut counters: Vec<models::Counter> = self
+            .counters
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|counter| after.is_none_or(|after| counter.id.as_str() > after))
+            .cloned()
+            .collect();
+        counters.sort_by(|a, b| a.id.cmp(&b.id));
+        counters.truncate(limit as usize);
+
+        Ok(counters)
+    }
+
+    async fn save_counter(&self, counter: models::Counter) -> Result<(), DbError> {
+        let mut counters = self.counters.write().unwrap();
+
+        match counters.iter_mut().find(|c| c.id == counter.id) {
+            Some(existing) => *existing = counter,
+            None => counters.push(counter),
+        }
+
+        Ok(())
+    }
 
+    async fn delete_counter(&self, id: &str) -> Result<(), DbError> {
+        let mut counters = self.counters.write().unwrap();
+
+        let count = counters.len();
+        counters.retain(|counter| counter.id != id);
+        if counters.len() == count {
+            return Err(DbError::NotFound);
+        }
+
+        Ok(())
+    }
+
+    async fn find_checkpoint(&self, projection: &str) -> Result<i64, DbError> {
+        let checkpoints = self.checkpoints.read().unwrap();
+
+        Ok(checkpoints.get(projection).copied().unwrap_or(0))
+    }
+
+    async fn project(
+        &self,
+        projection: &str,
+        after: i64,
+        sequence: i64,
+        update: CounterUpdate,
+    ) -> Result<(), DbError> {
+        // Held until the change is applied, like the checkpoint row in a database
+        let mut checkpoints = self.checkpoints.write().unwrap();
+        let checkpoint = checkpoints.entry(projection.to_string()).or_insert(0);
+        if *checkpoint != after {
+            return Err(DbError::Conflict(format!(
+                "the checkpoint of {} is not at {} anymore",
+                projection, after
+            )));
+        }
+
+        let mut counters = self.counters.write().unwrap();
+        match update {
+            CounterUpdate::Create { id, name, value } => {
+                let counter = models::Counter::new_with_id(id, name, value);
+                match counters.iter_mut().find(|c| c.id == counter.id) {
+                    Some(existing) => *existing = counter,
+                    None => counters.push(counter),
+                }
+            }
+            CounterUpdate::Add { id, amount } => {
+                if let Some(counter) = counters.iter_mut().find(|c| c.id == id) {
+                    counter.value += amount;
+                }
+            }
+            CounterUpdate::Update { id, name, value } => {
+                if let Some(counter) = counters.iter_mut().find(|c| c.id == id) {
+                    counter.name = name;
+                    counter.value = value;
+                }
+            }
+            CounterUpdate::Delete { id } => counters.retain(|counter| counter.id != id),
+        }
+        *checkpoint = sequence;
+
+        Ok(())
+    }
+}
+
+/// Any `CounterReadStore`, for when it's chosen at startup (eg. database or memory).
 #[derive(Clone)]
-pub struct CounterView {
-    pub repo: Repository,
+pub struct CounterStore(Arc<dyn CounterReadStore>);
+
+impl CounterStore {
+    pub fn new(store: impl CounterReadStore + 'static) -> Self {
+        Self(Arc::new(store))
+    }
 }
 
-impl CounterView {
-    pub fn new(repo: &Repository) -> Self {
-        Self { repo: repo.clone() }
+impl mini_cqrs::Repository for CounterStore {}
+
+#[async_trait]
+impl CounterReadStore for CounterStore {
+    async fn find_counter(&self, id: &str) -> Result<models::Counter, DbError> {
+        self.0.find_counter(id).await
+    }
+
+    async fn list_counters(&self) -> Result<Vec<models::Counter>, DbError> {
+        self.0.list_counters().await
+    }
+
+    async fn list_counters_page(
+        &self,
+        after: Option<&str>,
+        limit: u64,
+    ) -> Result<Vec<models::Counter>, DbError> {
+        self.0.list_counters_page(after, limit).await
+    }
+
+    async fn save_counter(&self, counter: models::Counter) -> Result<(), DbError> {
+        self.0.save_counter(counter).await
+    }
+
+    async fn delete_counter(&self, id: &str) -> Result<(), DbError> {
+        self.0.delete_counter(id).await
+    }
+
+    async fn find_checkpoint(&self, projection: &str) -> Result<i64, DbError> {
+        self.0.find_checkpoint(projection).await
+    }
+
+    async fn project(
+        &self,
+        projection: &str,
+        after: i64,
+        sequence: i64,
+        update: CounterUpdate,
+    ) -> Result<(), DbError> {
+        self.0.project(projection, after, sequence, update).await
+    }
+}
+
+#[derive(Clone)]
+pub struct CounterView<S> {
+    store: S,
+}
+
+impl<S: CounterReadStore + mini_cqrs::Repository + Clone> CounterView<S> {
+    pub fn new(store: &S) -> Self {
+        Self {
+            store: store.clone(),
+        }
+    }
+
+    pub async fn find(&self, id: &str) -> Result<models::Counter, DbError> {
+        self.store.find_counter(id).await
+    }
+
+    pub async fn delete(&self, id: &str) -> Result<(), DbError> {
+        self.store.delete_counter(id).await
     }
 }
 
 #[async_trait]
-impl ModelReader for CounterView {
-    type Repo = Repository;
+impl<S: CounterReadStore + mini_cqrs::Repository + Clone> ModelReader for CounterView<S> {
+    type Repo = S;
     type Model = models::Counter;
 
     async fn update(&mut self, data: Self::Model) -> Result<(), CqrsError> {
-        let _ = self
-            .repo
-            .insert_or_update_counter(data.clone().into())
-            .await;
-        Ok(())
+        self.store
+            .save_counter(data)
+            .await
+            .map_err(|err| CqrsError::new(err.to_string()))
     }
 }
@@ -0,0 +1,54 @@
+use futures::{stream, Stream, StreamExt};
+use serde::Serialize;
+
+use kountr_db::error::DbError;
+
+pub const COUNTER_HEADERS: &[&str] = &["id", "name", "value"];
+pub const DAILY_VALUE_HEADERS: &[&str] = &["date", "value", "change"];
+
+/// Encodes rows as CSV: a chunk with the header, then one per row, so that
+/// large exports can be written out as they're read.
+pub fn csv_chunks<T: Serialize>(
+    headers: &'static [&'static str],
+    rows: impl Stream<Item = Result<T, DbError>>,
+) -> impl Stream<Item = Result<Vec<u8>, DbError>> {
+    let header = encode(|writer| writer.write_record(headers));
+    let rows = rows.map(|row| row.and_then(|row| encode(|writer| writer.serialize(row))));
+
+    stream::once(async { header }).chain(rows)
+}
+
+fn encode(
+    write: impl FnOnce(&mut csv::Writer<Vec<u8>>) -> csv::Result<()>,
+) -> Result<Vec<u8>, DbError> {
+    let mut writer = csv::WriterBuilder::new()
+        .has_headers(false)
+        .from_writer(vec![]);
+    write(&mut writer).map_err(|err| DbError::Invalid(err.to_string()))?;
+
+    writer
+        .into_inner()
+        .map_err(|err| DbError::Invalid(err.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::domain::models::Counter;
+
+    #[tokio::test]
+    async fn escapes_fields() {
+        let counter = Counter::new_with_id("c1".to_string(), "Tea, \"green\"".to_string(), 3);
+        let rows = stream::iter(vec![Ok(counter)]);
+
+        let chunks: Vec<Vec<u8>> = csv_chunks(COUNTER_HEADERS, rows)
+            .map(Result::unwrap)
+            .collect()
+            .await;
+
+        assert_eq!(
+            String::from_utf8(chunks.concat()).unwrap(),
+            "id,name,value\nc1,\"Tea, \"\"green\"\"\",3\n"
+        );
+    }
+}
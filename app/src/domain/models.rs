@@ -1,4 +1,7 @@
-#[derive(Clone)]
+use chrono::{DateTime, NaiveDate, Utc};
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Counter {
     pub id: String,
     pub name: String,
@@ -28,3 +31,211 @@ impl std::fmt::Display for Counter {
         write!(f, "Counter {{ id: {}, name: {}, value: {} }}", self.id, self.name, self.value)
     }
 }
+
+/// A change to a counter, as seen by live subscribers once it has been projected.
+#[derive(Clone, Debug)]
+pub enum CounterChange {
+    Updated(Counter),
+    Deleted(String),
+}
+
+/// An event as exposed to the outside, with its position in the global log.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct StoredEvent {
+    pub sequence: i64,
+    pub id: String,
+    pub event_type: String,
+    pub aggregate_id: String,
+    pub timestamp: DateTime<Utc>,
+    pub data: serde_json::Value,
+}
+
+/// The value of a counter at the end of a day (UTC), derived from its events.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct DailyValue {
+    pub date: NaiveDate,
+    pub value: i32,
+    /// Net change since the end of the previous day.
+    pub change: i32,
+}
+
+/// Whether the app can serve requests, eg. for load balancers.
+#[derive(Clone, Debug, Serialize)]
+pub struct Readiness {
+    pub ready: bool,
+    pub pending_migrations: usize,
+    /// How many stored events haven't been applied to the counters yet.
+    pub projection_lag: u64,
+}
+
+/// A subscription to counter events, delivered to `url` as signed JSON payloads.
+#[derive(Clone, Debug, Serialize)]
+pub struct Webhook {
+    pub id: String,
+    pub url: String,
+    /// Event types to deliver, all of them when empty.
+    pub event_types: Vec<String>,
+    #[serde(skip_serializing)]
+    pub secret: String,
+    pub created_at: DateTime<Utc>,
+}
+
+impl Webhook {
+    pub fn new(url: String, event_types: Vec<String>, secret: String) -> Self {
+        Self {
+            id: uuid::Uuid::new_v4().to_string(),
+            url,
+            event_types,
+            secret,
+            created_at: Utc::now(),
+        }
+    }
+
+    pub fn accepts(&self, event_type: &str) -> bool {
+        self.event_types.is_empty() || self.event_types.iter().any(|t| t == event_type)
+    }
+}
+
+/// A single attempt to deliver a message to a webhook.
+#[derive(Clone, Debug, Serialize)]
+pub struct WebhookDelivery {
+    pub id: String,
+    pub webhook_id: String,
+    pub event_id: String,
+    pub event_type: String,
+    pub attempt: i32,
+    pub status_code: Option<i32>,
+    pub error: Option<String>,
+    pub success: bool,
+    pub timestamp: DateTime<Utc>,
+}
+
+/// When an alert rule fires, checked against a counter after each change.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(tag = "kind", content = "threshold", rename_all = "snake_case")]
+pub enum AlertCondition {
+    /// The value is greater than the threshold.
+    ValueAbove(i32),
+    /// The value is lower than the threshold.
+    ValueBelow(i32),
+    /// The value just reached the threshold (eg. a goal), from either side.
+    ValueReaches(i32),
+    /// The net change since midnight (UTC) is greater than the threshold.
+    TodayAbove(i32),
+}
+
+impl AlertCondition {
+    pub fn parse(kind: &str, threshold: i32) -> Option<Self> {
+        match kind {
+            "value_above" => Some(AlertCondition::ValueAbove(threshold)),
+            "value_below" => Some(AlertCondition::ValueBelow(threshold)),
+            "value_reaches" => Some(AlertCondition::ValueReaches(threshold)),
+            "today_above" => Some(AlertCondition::TodayAbove(threshold)),
+            _ => None,
+        }
+    }
+
+    pub fn kind(&self) -> &'static str {
+        match self {
+            AlertCondition::ValueAbove(_) => "value_above",
+            AlertCondition::ValueBelow(_) => "value_below",
+            AlertCondition::ValueReaches(_) => "value_reaches",
+            AlertCondition::TodayAbove(_) => "today_above",
+        }
+    }
+
+    pub fn threshold(&self) -> i32 {
+        match self {
+            AlertCondition::ValueAbove(t)
+            | AlertCondition::ValueBelow(t)
+            | AlertCondition::ValueReaches(t)
+            | AlertCondition::TodayAbove(t) => *t,
+        }
+    }
+}
+
+/// Where fired alerts are sent.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AlertChannel {
+    InApp,
+    Webhook,
+    Email,
+}
+
+impl AlertChannel {
+    pub fn parse(channel: &str) -> Option<Self> {
+        match channel {
+            "in_app" => Some(AlertChannel::InApp),
+            "webhook" => Some(AlertChannel::Webhook),
+            "email" => Some(AlertChannel::Email),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AlertChannel::InApp => "in_app",
+            AlertChannel::Webhook => "webhook",
+            AlertChannel::Email => "email",
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct AlertRule {
+    pub id: String,
+    pub counter_id: String,
+    pub name: String,
+    pub condition: AlertCondition,
+    pub channel: AlertChannel,
+    /// A webhook id or an email address, depending on the channel.
+    pub target: String,
+    /// Minimum number of seconds between two alerts of this rule.
+    pub debounce_secs: i32,
+    pub created_at: DateTime<Utc>,
+}
+
+impl AlertRule {
+    pub fn new(
+        counter_id: String,
+        name: String,
+        condition: AlertCondition,
+        channel: AlertChannel,
+        target: String,
+        debounce_secs: i32,
+    ) -> Self {
+        Self {
+            id: uuid::Uuid::new_v4().to_string(),
+            counter_id,
+            name,
+            condition,
+            channel,
+            target,
+            debounce_secs,
+            created_at: Utc::now(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Alert {
+    pub id: String,
+    pub rule_id: String,
+    pub counter_id: String,
+    pub message: String,
+    pub value: i32,
+    pub channel: AlertChannel,
+    /// Set when the alert couldn't be handed over to its channel.
+    pub error: Option<String>,
+    pub fired_at: DateTime<Utc>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Notification {
+    pub id: String,
+    pub alert_id: String,
+    pub message: String,
+    pub read: bool,
+    pub created_at: DateTime<Utc>,
+}
@@ -0,0 +1,346 @@
+use std::collections::HashSet;
+use std::io::{BufRead, Write};
+
+use chrono::{DateTime, Utc};
+use mini_cqrs::{Event, EventPayload, EventStore as _};
+use serde::{Deserialize, Serialize};
+
+use crate::cqrs::{
+    CounterEvent, CounterProjection, CounterReadStore, CounterStore, InMemoryCounterStore,
+};
+use crate::projections::ProjectionWorker;
+use crate::COUNTERS_PROJECTION;
+use kountr_db::{
+    error::DbError,
+    event_store::{EventFilter, EventStore},
+    repository::Repository,
+    Database,
+};
+
+// How many events are read from the store at once while exporting.
+const EXPORT_PAGE_SIZE: u64 = 500;
+
+/// A line of an exported event log, in JSON Lines format.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LogEntry {
+    pub id: String,
+    pub event_type: String,
+    pub aggregate_id: String,
+    pub timestamp: DateTime<Utc>,
+    /// The version `data` was written with, older ones are upcast on import.
+    pub schema_version: i32,
+    pub data: serde_json::Value,
+}
+
+#[derive(Debug, Default)]
+pub struct ImportReport {
+    pub imported: usize,
+    /// Events skipped because they were already stored (or repeated in the log).
+    pub duplicates: usize,
+}
+
+/// Backs up and restores the event log of a database, eg. to migrate or seed an instance.
+///
+/// It doesn't start any background worker, the server shouldn't be running
+/// while importing, or its projection could race with the rebuild.
+pub struct EventLog {
+    repo: Repository,
+    store: EventStore,
+}
+
+impl EventLog {
+    pub async fn connect(db_url: &str) -> Result<Self, DbError> {
+        let db = Database::connect(db_url).await?;
+
+        let repo = Repository::new(&db);
+        repo.run_migrations().await?;
+
+        let store = EventStore::new(db).with_upcasters(CounterEvent::upcasters());
+
+        Ok(Self { repo, store })
+    }
+
+    /// Writes every stored event, in order, one JSON object per line. Returns how many they were.
+    pub async fn export(&self, out: &mut impl Write) -> Result<usize, DbError> {
+        let mut filter = EventFilter {
+            limit: EXPORT_PAGE_SIZE,
+            ..Default::default()
+        };
+        let upcasters = CounterEvent::upcasters();
+        let mut count = 0;
+
+        loop {
+            let page = self.store.stream_events(&filter).await?;
+            let Some(last) = page.last() else {
+                return Ok(count);
+            };
+            filter.after = last.sequence;
+
+            for stored in page {
+                let entry = LogEntry {
+                    schema_version: upcasters.current_version(&stored.event.event_type),
+                    id: stored.event.id,
+                    event_type: stored.event.event_type,
+                    aggregate_id: stored.event.aggregate_id,
+                    timestamp: stored.event.timestamp,
+                    data: stored.event.payload,
+                };
+
+                serde_json::to_writer(&mut *out, &entry).map_err(invalid)?;
+                writeln!(out).map_err(invalid)?;
+                count += 1;
+            }
+        }
+    }
+
+    /// Reads an exported log and appends the events that aren't stored yet,
+    /// then rebuilds the counters. Nothing is stored if any line is invalid.
+    pub async fn import(&self, input: impl BufRead) -> Result<ImportReport, DbError> {
+        let mut entries = vec![];
+        for (index, line) in input.lines().enumerate() {
+            let line = line.map_err(invalid)?;
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            let entry: LogEntry = serde_json::from_str(&line)
+                .map_err(|err| DbError::Invalid(format!("line {}: {}", index + 1, err)))?;
+            entries.push((index + 1, entry));
+        }
+
+        let ids: Vec<String> = entries.iter().map(|(_, entry)| entry.id.clone()).collect();
+        let mut seen = self.store.find_existing_ids(&ids).await?;
+
+        let mut report = ImportReport::default();
+        let mut created = HashSet::new();
+        let mut events = vec![];
+
+        for (line, entry) in entries {
+            if !seen.insert(entry.id.clone()) {
+                report.duplicates += 1;
+                continue;
+            }
+
+            let event = validate(entry).map_err(|err| DbError::Invalid(format!("line {}: {}", line, err)))?;
+
+            let creates = matches!(
+                event.get_payload::<CounterEvent>(),
+                CounterEvent::CounterCreated { .. }
+            );
+            if !creates
+                && !created.contains(&event.aggregate_id)
+                && self.store.load_events(event.aggregate_id.clone()).await?.is_empty()
+            {
+                return Err(DbError::Invalid(format!(
+                    "line {}: counter {} is not created before its {} event",
+                    line, event.aggregate_id, event.event_type
+                )));
+            }
+            created.insert(event.aggregate_id.clone());
+
+            events.push(event);
+        }
+
+        if events.is_empty() {
+            return Ok(report);
+        }
+
+        self.store.import_events(&events).await?;
+        report.imported = events.len();
+
+        self.rebuild_counters().await?;
+
+        Ok(report)
+    }
+
+    /// Projects the counters read model again from the whole event log, in memory,
+    /// then replaces the stored one with it in a single transaction.
+    pub async fn rebuild_counters(&self) -> Result<usize, DbError> {
+        let counters = CounterStore::new(InMemoryCounterStore::default());
+        let mut worker = ProjectionWorker::new(
+            COUNTERS_PROJECTION,
+            self.store.clone(),
+            CounterProjection::new(&counters),
+        );
+        let count = worker.catch_up().await?;
+
+        let sequence = counters.find_checkpoint(COUNTERS_PROJECTION).await?;
+        let models = counters.list_counters().await?.into_iter().map(Into::into).collect();
+        self.repo.replace_counters(COUNTERS_PROJECTION, sequence, models).await?;
+
+        Ok(count)
+    }
+}
+
+/// Checks that the entry holds a current counter event, and turns it into one.
+fn validate(entry: LogEntry) -> Result<Event, String> {
+    for (field, value) in [("id", &entry.id), ("aggregate_id", &entry.aggregate_id)] {
+        uuid::Uuid::parse_str(value).map_err(|_| format!("{} is not a uuid: {}", field, value))?;
+    }
+
+    let payload = CounterEvent::upcasters().upcast(&entry.event_type, entry.schema_version, entry.data)?;
+    let event: CounterEvent =
+        serde_json::from_value(payload.clone()).map_err(|err| format!("invalid {}: {}", entry.event_type, err))?;
+
+    if event.to_string() != entry.event_type {
+        return Err(format!("data of a {} event is a {}", entry.event_type, event.to_string()));
+    }
+    if event.aggregate_id() != entry.aggregate_id {
+        return Err(format!("data belongs to counter {}", event.aggregate_id()));
+    }
+
+    Ok(Event {
+        id: entry.id,
+        event_type: entry.event_type,
+        aggregate_id: entry.aggregate_id,
+        payload,
+        version: 1,
+        timestamp: entry.timestamp,
+    })
+}
+
+fn invalid(err: impl std::fmt::Display) -> DbError {
+    DbError::Invalid(err.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    const ID: &str = "0b6c5b4e-4a56-4f0b-9b7e-4a0c4e8f6a01";
+    const COUNTER_ID: &str = "5f3f0a56-2c1e-4a43-8a37-9a2b8f1c7d02";
+
+    fn entry(event_type: &str, data: serde_json::Value) -> LogEntry {
+        LogEntry {
+            id: ID.to_string(),
+            event_type: event_type.to_string(),
+            aggregate_id: COUNTER_ID.to_string(),
+            timestamp: Utc::now(),
+            schema_version: 1,
+            data,
+        }
+    }
+
+    fn created(id: &str, value: i32) -> Event {
+        CounterEvent::CounterCreated {
+            aggregate_id: id.to_string(),
+            name: "Coffee".to_string(),
+            value,
+        }
+        .into()
+    }
+
+    fn incremented(id: &str) -> Event {
+        CounterEvent::CounterIncremented {
+            aggregate_id: id.to_string(),
+            amount: 1,
+        }
+        .into()
+    }
+
+    fn log_line(event: Event) -> String {
+        let entry = LogEntry {
+            id: event.id,
+            event_type: event.event_type,
+            aggregate_id: event.aggregate_id,
+            timestamp: event.timestamp,
+            schema_version: 1,
+            data: event.payload,
+        };
+
+        serde_json::to_string(&entry).unwrap() + "\n"
+    }
+
+    /// A log over events in memory, rebuilding the counters of its own database.
+    async fn event_log() -> EventLog {
+        EventLog {
+            repo: crate::test_repo().await,
+            store: EventStore::in_memory().with_upcasters(CounterEvent::upcasters()),
+        }
+    }
+
+    // The rebuild replaces every counter, other tests can't share its database
+    #[cfg(feature = "sqlite")]
+    #[tokio::test]
+    async fn imports_an_exported_log() {
+        let (coffee, tea) = (uuid::Uuid::new_v4().to_string(), uuid::Uuid::new_v4().to_string());
+        let source = event_log().await;
+        let events = [created(&coffee, 0), incremented(&coffee), incremented(&coffee), created(&tea, 5)];
+        source.store.clone().save_events(coffee.clone(), &events).await.unwrap();
+
+        let mut exported = vec![];
+        assert_eq!(source.export(&mut exported).await.unwrap(), 4);
+
+        let target = event_log().await;
+        let report = target.import(&exported[..]).await.unwrap();
+        assert_eq!((report.imported, report.duplicates), (4, 0));
+
+        let imported = target.store.load_events_after(0, 10).await.unwrap();
+        let ids: Vec<_> = imported.into_iter().map(|stored| stored.event.id).collect();
+        assert_eq!(ids, events.iter().map(|evt| evt.id.clone()).collect::<Vec<_>>());
+        assert_eq!(target.repo.find_counter_by_id(coffee.clone()).await.unwrap().value, 2);
+        assert_eq!(target.repo.find_counter_by_id(tea.clone()).await.unwrap().value, 5);
+        assert_eq!(target.repo.find_checkpoint(COUNTERS_PROJECTION).await.unwrap(), 4);
+
+        // Importing again, along with a new event, only adds the new one
+        let mut log = exported.clone();
+        log.extend(log_line(incremented(&tea)).into_bytes());
+        let report = target.import(&log[..]).await.unwrap();
+        assert_eq!((report.imported, report.duplicates), (1, 4));
+        assert_eq!(target.repo.find_counter_by_id(coffee).await.unwrap().value, 2);
+        assert_eq!(target.repo.find_counter_by_id(tea).await.unwrap().value, 6);
+    }
+
+    #[tokio::test]
+    async fn rejects_events_before_the_creation_of_their_counter() {
+        let id = uuid::Uuid::new_v4().to_string();
+        let log = log_line(incremented(&id)) + &log_line(created(&id, 0));
+
+        let target = event_log().await;
+        let err = target.import(log.as_bytes()).await.unwrap_err();
+        assert!(matches!(err, DbError::Invalid(message) if message.starts_with("line 1:")));
+
+        // Nothing is stored
+        assert_eq!(target.store.last_sequence().await.unwrap(), 0);
+    }
+
+    #[test]
+    fn accepts_counter_events() {
+        let data = json!({"CounterIncremented": {"aggregate_id": COUNTER_ID, "amount": 1}});
+
+        let event = validate(entry("CounterIncremented", data)).unwrap();
+
+        assert_eq!(event.id, ID);
+        assert_eq!(
+            event.get_payload::<CounterEvent>(),
+            CounterEvent::CounterIncremented {
+                aggregate_id: COUNTER_ID.to_string(),
+                amount: 1
+            }
+        );
+    }
+
+    #[test]
+    fn rejects_inconsistent_entries() {
+        let data = json!({"CounterIncremented": {"aggregate_id": COUNTER_ID, "amount": 1}});
+
+        assert!(validate(entry("CounterDecremented", data.clone())).is_err());
+        assert!(validate(LogEntry {
+            aggregate_id: ID.to_string(),
+            ..entry("CounterIncremented", data.clone())
+        })
+        .is_err());
+        assert!(validate(LogEntry {
+            id: "not-a-uuid".to_string(),
+            ..entry("CounterIncremented", data.clone())
+        })
+        .is_err());
+        assert!(validate(LogEntry {
+            schema_version: 2,
+            ..entry("CounterIncremented", data)
+        })
+        .is_err());
+        assert!(validate(entry("CounterIncremented", json!({"amount": 1}))).is_err());
+    }
+}
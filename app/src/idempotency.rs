@@ -0,0 +1,192 @@
+use std::future::Future;
+
+use chrono::{Duration, Utc};
+use serde::{de::DeserializeOwned, Serialize};
+use sha2::{Digest, Sha256};
+use tracing::warn;
+
+use kountr_db::{error::DbError, repository::Repository};
+
+// How long a key is remembered, retries are expected to happen way earlier.
+const KEY_TTL_HOURS: i64 = 24;
+// How often expired keys are deleted.
+const CLEANUP_INTERVAL: std::time::Duration = std::time::Duration::from_secs(3600);
+
+/// A client supplied key (eg. the `Idempotency-Key` header), along with the
+/// request it has been sent with, so that it can't be reused for another one.
+#[derive(Clone, Debug)]
+pub struct IdempotencyKey {
+    pub key: String,
+    pub request: String,
+}
+
+impl IdempotencyKey {
+    /// Keys are scoped to the `client` that sent them, so that clients can't get the results
+    /// of each other. `request` identifies what was asked, eg. the method, path and body digest.
+    pub fn new(client: &str, key: &str, request: String) -> Self {
+        // Hashed, so that stored keys have the same length and don't reveal clients
+        let scoped = Sha256::new()
+            .chain_update(client)
+            .chain_update("\n")
+            .chain_update(key)
+            .finalize();
+
+        Self {
+            key: hex::encode(scoped),
+            request,
+        }
+    }
+}
+
+/// Deletes the expired keys every hour, in background.
+pub fn spawn_cleanup(repo: &Repository) {
+    let repo = repo.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(CLEANUP_INTERVAL);
+        loop {
+            interval.tick().await;
+            if let Err(err) = delete_expired(&repo).await {
+                warn!("Cannot delete expired idempotency keys: {}", err);
+            }
+        }
+    });
+}
+
+async fn delete_expired(repo: &Repository) -> Result<(), DbError> {
+    repo.delete_idempotency_keys_before(Utc::now() - Duration::hours(KEY_TTL_HOURS))
+        .await
+}
+
+/// Runs `operation` once per key: repeated calls with the same key return the
+/// stored result of the first one, without running it again.
+pub async fn run<T, F>(repo: &Repository, key: Option<&IdempotencyKey>, operation: F) -> Result<T, DbError>
+where
+    T: Serialize + DeserializeOwned,
+    F: Future<Output = Result<T, DbError>>,
+{
+    let Some(key) = key else {
+        return operation.await;
+    };
+
+    if !repo.insert_idempotency_key(&key.key, &key.request).await? {
+        return stored_result(repo, key).await;
+    }
+
+    match operation.await {
+        Ok(result) => {
+            let response =
+                serde_json::to_value(&result).map_err(|err| DbError::Invalid(err.to_string()))?;
+            repo.complete_idempotency_key(&key.key, response).await?;
+
+            Ok(result)
+        }
+        Err(err) => {
+            // Failed operations can be retried with the same key
+            _ = repo.delete_idempotency_key(&key.key).await;
+            Err(err)
+        }
+    }
+}
+
+async fn stored_result<T: DeserializeOwned>(repo: &Repository, key: &IdempotencyKey) -> Result<T, DbError> {
+    let in_progress = || DbError::Conflict("a request with this key is in progress".to_string());
+
+    let stored = match repo.find_idempotency_key(&key.key).await {
+        Ok(stored) => stored,
+        // the first request just failed and released the key
+        Err(DbError::NotFound) => return Err(in_progress()),
+        Err(err) => return Err(err),
+    };
+
+    if stored.request != key.request {
+        return Err(DbError::Conflict(
+            "the key has already been used for a different request".to_string(),
+        ));
+    }
+
+    let response = stored.response.ok_or_else(in_progress)?;
+    serde_json::from_value(response).map_err(|err| DbError::Invalid(err.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+    use crate::test_repo;
+
+    fn key(client: &str, request: &str) -> IdempotencyKey {
+        IdempotencyKey::new(client, "k1", request.to_string())
+    }
+
+    async fn count(calls: &AtomicUsize) -> Result<usize, DbError> {
+        Ok(calls.fetch_add(1, Ordering::SeqCst) + 1)
+    }
+
+    #[test]
+    fn scopes_keys_to_clients() {
+        let key = key("client-a", "POST /");
+
+        assert_eq!(key.key.len(), 64);
+        assert_eq!(key.key, IdempotencyKey::new("client-a", "k1", String::new()).key);
+        assert_ne!(key.key, IdempotencyKey::new("client-b", "k1", String::new()).key);
+        assert_ne!(key.key, IdempotencyKey::new("client-a", "k2", String::new()).key);
+    }
+
+    #[tokio::test]
+    async fn runs_once_per_key() {
+        let repo = test_repo().await;
+        let calls = AtomicUsize::new(0);
+        let first = key(&uuid::Uuid::new_v4().to_string(), "POST / abc");
+
+        assert_eq!(run(&repo, Some(&first), count(&calls)).await.unwrap(), 1);
+        assert_eq!(run(&repo, Some(&first), count(&calls)).await.unwrap(), 1);
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+        // Without a key, or from another client, it runs again
+        assert_eq!(run(&repo, None, count(&calls)).await.unwrap(), 2);
+        let other = key(&uuid::Uuid::new_v4().to_string(), "POST / abc");
+        assert_eq!(run(&repo, Some(&other), count(&calls)).await.unwrap(), 3);
+    }
+
+    #[tokio::test]
+    async fn rejects_a_key_reused_for_another_request() {
+        let repo = test_repo().await;
+        let calls = AtomicUsize::new(0);
+        let client = uuid::Uuid::new_v4().to_string();
+
+        run(&repo, Some(&key(&client, "POST / abc")), count(&calls)).await.unwrap();
+        let reused = run(&repo, Some(&key(&client, "POST / def")), count(&calls)).await;
+
+        assert!(matches!(reused, Err(DbError::Conflict(_))));
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn releases_the_key_of_failed_requests() {
+        let repo = test_repo().await;
+        let calls = AtomicUsize::new(0);
+        let key = key(&uuid::Uuid::new_v4().to_string(), "POST / abc");
+
+        let failed = run(&repo, Some(&key), async { Err::<usize, _>(DbError::NotFound) }).await;
+        assert!(matches!(failed, Err(DbError::NotFound)));
+
+        assert_eq!(run(&repo, Some(&key), count(&calls)).await.unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn deletes_expired_keys() {
+        let repo = test_repo().await;
+        let calls = AtomicUsize::new(0);
+        let key = key(&uuid::Uuid::new_v4().to_string(), "POST / abc");
+        run(&repo, Some(&key), count(&calls)).await.unwrap();
+
+        delete_expired(&repo).await.unwrap();
+        assert!(repo.find_idempotency_key(&key.key).await.is_ok());
+
+        repo.delete_idempotency_keys_before(Utc::now() + Duration::hours(1))
+            .await
+            .unwrap();
+        assert_eq!(run(&repo, Some(&key), count(&calls)).await.unwrap(), 2);
+    }
+}
@@ -0,0 +1,498 @@
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use tokio::sync::{mpsc, oneshot, Mutex};
+use tracing::{debug, error, warn, Instrument, Span};
+
+use kountr_db::{
+    error::DbError,
+    event_store::{EventStore, SequencedEvent},
+};
+
+// How many events are loaded at once while catching up.
+const BATCH_SIZE: u64 = 500;
+// Catch up regularly, in case events were stored by someone else (eg. an import).
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+// How long a missing sequence is waited for, before it's taken for a rolled back append.
+const GAP_TIMEOUT: Duration = Duration::from_secs(2);
+const GAP_RETRY_INTERVAL: Duration = Duration::from_millis(20);
+
+/// A read model built from the event log, which keeps track of how far it got.
+#[async_trait]
+pub trait Projection: Send + Sync {
+    /// The sequence of the last event applied, 0 before the first one.
+    async fn checkpoint(&self, name: &str) -> Result<i64, DbError>;
+    /// Applies the event and moves the checkpoint from `after` to its sequence, all at once.
+    /// Fails with `DbError::Conflict` when the checkpoint has moved meanwhile.
+    async fn apply(&self, name: &str, after: i64, stored: &SequencedEvent) -> Result<(), DbError>;
+}
+
+/// Feeds a projection with the stored events, in order, starting after its checkpoint.
+///
+/// Each event is applied along with its checkpoint, so it's applied exactly once, even
+/// after a crash. When it fails, the worker stops there and retries on the next catch up.
+///
+/// Sequences are handed out before the appends are committed, so an event can show up
+/// before the ones preceding it: the worker waits for them, up to `GAP_TIMEOUT`.
+pub struct ProjectionWorker<P: Projection> {
+    name: String,
+    store: EventStore,
+    projection: P,
+}
+
+/// Wakes up a `ProjectionWorker`, see `ProjectionWorker::spawn` and `ProjectionWorker::inline`.
+#[derive(Clone)]
+pub struct ProjectionHandle {
+    runner: Runner,
+}
+
+#[derive(Clone)]
+enum Runner {
+    /// The worker runs in background.
+    Spawned(mpsc::Sender<SyncRequest>),
+    /// The worker runs in the task of whoever syncs.
+    Inline(Arc<Mutex<dyn CatchUp>>),
+}
+
+/// Someone waiting for a `ProjectionWorker` in background.
+struct SyncRequest {
+    ack: oneshot::Sender<()>,
+    /// Where the catch up is traced, eg. under the command that saved the events.
+    span: Span,
+}
+
+impl ProjectionHandle {
+    /// Waits until the worker has processed every event stored so far.
+    pub async fn sync(&self) {
+        match &self.runner {
+            Runner::Spawned(tx) => {
+                let (ack, done) = oneshot::channel();
+                let request = SyncRequest {
+                    ack,
+                    span: Span::current(),
+                };
+                if tx.send(request).await.is_ok() {
+                    _ = done.await;
+                }
+            }
+            Runner::Inline(worker) => worker.lock().await.run_once(&[Span::current()]).await,
+        }
+    }
+}
+
+/// A `ProjectionWorker`, whatever its projection.
+#[async_trait]
+trait CatchUp: Send {
+    /// Catches up in a span under the first of `waiting`, linked to the others.
+    async fn run_once(&mut self, waiting: &[Span]);
+}
+
+#[async_trait]
+impl<P: Projection + 'static> CatchUp for ProjectionWorker<P> {
+    async fn run_once(&mut self, waiting: &[Span]) {
+        let span = match waiting.split_first() {
+            Some((parent, others)) => {
+                let span = tracing::info_span!(parent: parent, "projection", projection = %self.name);
+                for other in others {
+                    span.follows_from(other);
+                }
+                span
+            }
+            // Regular catch ups, there's rarely anything new
+            None => tracing::debug_span!("projection", projection = %self.name),
+        };
+
+        match self.catch_up().instrument(span).await {
+            Ok(0) => {}
+            Ok(count) => debug!("Projection {} processed {} events", self.name, count),
+            Err(err) => {
+                error!("Projection {} failed to catch up: {}", self.name, err);
+                metrics::increment_counter!("kountr_consumer_errors_total", "consumer" => self.name.clone());
+            }
+        }
+    }
+}
+
+impl<P: Projection + 'static> ProjectionWorker<P> {
+    pub fn new(name: &str, store: EventStore, projection: P) -> Self {
+        Self {
+            name: name.to_string(),
+            store,
+            projection,
+        }
+    }
+
+    /// Starts the worker in background, it resumes from its checkpoint right away.
+    pub fn spawn(self) -> ProjectionHandle {
+        let (tx, rx) = mpsc::channel(64);
+        tokio::spawn(self.run(rx));
+
+        ProjectionHandle {
+            runner: Runner::Spawned(tx),
+        }
+    }
+
+    /// Doesn't start anything: the worker catches up whenever it's synced, and only
+    /// then, eg. for one-off commands.
+    pub fn inline(self) -> ProjectionHandle {
+        ProjectionHandle {
+            runner: Runner::Inline(Arc::new(Mutex::new(self))),
+        }
+    }
+
+    async fn run(mut self, mut rx: mpsc::Receiver<SyncRequest>) {
+        let mut poll = tokio::time::interval(POLL_INTERVAL);
+
+        loop {
+            let mut requests = vec![];
+            tokio::select! {
+                request = rx.recv() => match request {
+                    Some(request) => requests.push(request),
+                    None => break,
+                },
+                _ = poll.tick() => {}
+            }
+            // Serve everyone who's waiting with a single catch up
+            while let Ok(request) = rx.try_recv() {
+                requests.push(request);
+            }
+
+            let waiting: Vec<Span> = requests.iter().map(|request| request.span.clone()).collect();
+            self.run_once(&waiting).await;
+
+            for request in requests {
+                _ = request.ack.send(());
+            }
+        }
+    }
+
+    /// Applies all the events after the checkpoint, returns how many they were.
+    pub async fn catch_up(&mut self) -> Result<usize, DbError> {
+        let mut count = 0;
+        let mut cursor = self.projection.checkpoint(&self.name).await?;
+        let mut gap_since = None;
+
+        'batches: loop {
+            let events = self.store.load_events_after(cursor, BATCH_SIZE).await?;
+            if events.is_empty() {
+                return Ok(count);
+            }
+
+            for stored in events {
+                if stored.sequence > cursor + 1 {
+                    let since = *gap_since.get_or_insert_with(Instant::now);
+                    if since.elapsed() < GAP_TIMEOUT {
+                        tokio::time::sleep(GAP_RETRY_INTERVAL).await;
+                        continue 'batches;
+                    }
+                    warn!(
+                        "Projection {} skipped the missing events {} to {}",
+                        self.name,
+                        cursor + 1,
+                        stored.sequence - 1
+                    );
+                }
+                gap_since = None;
+
+                match self.projection.apply(&self.name, cursor, &stored).await {
+                    Ok(()) => count += 1,
+                    // Another process (eg. a command line client) got there first
+                    Err(DbError::Conflict(_)) => {
+                        cursor = self.projection.checkpoint(&self.name).await?;
+                        continue 'batches;
+                    }
+                    Err(err) => return Err(err),
+                }
+                cursor = stored.sequence;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicI64, Ordering},
+        Arc,
+    };
+
+    use mini_cqrs::{Event, EventStore as _};
+
+    use super::*;
+    use crate::cqrs::{
+        CounterEvent, CounterProjection, CounterReadStore, CounterStore, InMemoryCounterStore,
+    };
+    use crate::test_repo;
+    use kountr_db::repository::CounterUpdate;
+
+    /// Fails once on the event with the given sequence, then behaves.
+    struct FailingProjection {
+        inner: CounterProjection<CounterStore>,
+        fail_on: Arc<AtomicI64>,
+    }
+
+    #[async_trait]
+    impl Projection for FailingProjection {
+        async fn checkpoint(&self, name: &str) -> Result<i64, DbError> {
+            self.inner.checkpoint(name).await
+        }
+
+        async fn apply(&self, name: &str, after: i64, stored: &SequencedEvent) -> Result<(), DbError> {
+            let fail = self
+                .fail_on
+                .compare_exchange(stored.sequence, 0, Ordering::SeqCst, Ordering::SeqCst);
+            if fail.is_ok() {
+                return Err(DbError::Invalid("boom".to_string()));
+            }
+            self.inner.apply(name, after, stored).await
+        }
+    }
+
+    async fn store_events(store: &EventStore, id: &str, increments: usize) {
+        let mut events: Vec<Event> = vec![CounterEvent::CounterCreated {
+            aggregate_id: id.to_string(),
+            name: "Coffee".to_string(),
+            value: 0,
+        }
+        .into()];
+        for _ in 0..increments {
+            events.push(
+                CounterEvent::CounterIncremented {
+                    aggregate_id: id.to_string(),
+                    amount: 1,
+                }
+                .into(),
+            );
+        }
+
+        store.clone().save_events(id.to_string(), &events).await.unwrap();
+    }
+
+    fn worker(
+        store: &EventStore,
+        counters: &CounterStore,
+    ) -> ProjectionWorker<CounterProjection<CounterStore>> {
+        ProjectionWorker::new("counters", store.clone(), CounterProjection::new(counters))
+    }
+
+    #[tokio::test]
+    async fn resumes_from_the_checkpoint() {
+        let store = EventStore::in_memory();
+        let counters = CounterStore::new(InMemoryCounterStore::default());
+        store_events(&store, "c1", 2).await;
+
+        assert_eq!(worker(&store, &counters).catch_up().await.unwrap(), 3);
+        assert_eq!(counters.find_checkpoint("counters").await.unwrap(), 3);
+
+        // A restarted worker doesn't apply the same events again
+        store_events(&store, "c2", 1).await;
+        let mut restarted = worker(&store, &counters);
+        assert_eq!(restarted.catch_up().await.unwrap(), 2);
+        assert_eq!(restarted.catch_up().await.unwrap(), 0);
+
+        assert_eq!(counters.find_counter("c1").await.unwrap().value, 2);
+        assert_eq!(counters.find_counter("c2").await.unwrap().value, 1);
+        assert_eq!(counters.find_checkpoint("counters").await.unwrap(), 5);
+    }
+
+    /// Pairs of span names.
+    type SpanEdges = Arc<std::sync::Mutex<Vec<(String, String)>>>;
+
+    /// Records the parent of every span, and which spans follow from which.
+    #[derive(Clone, Default)]
+    struct SpanTree {
+        parents: SpanEdges,
+        follows: SpanEdges,
+    }
+
+    impl<S> tracing_subscriber::Layer<S> for SpanTree
+    where
+        S: tracing::Subscriber + for<'span> tracing_subscriber::registry::LookupSpan<'span>,
+    {
+        fn on_new_span(
+            &self,
+            _attrs: &tracing::span::Attributes<'_>,
+            id: &tracing::span::Id,
+            ctx: tracing_subscriber::layer::Context<'_, S>,
+        ) {
+            let span = ctx.span(id).unwrap();
+            if let Some(parent) = span.parent() {
+                self.parents
+                    .lock()
+                    .unwrap()
+                    .push((span.name().to_string(), parent.name().to_string()));
+            }
+        }
+
+        fn on_follows_from(
+            &self,
+            id: &tracing::span::Id,
+            follows: &tracing::span::Id,
+            ctx: tracing_subscriber::layer::Context<'_, S>,
+        ) {
+            let (span, follows) = (ctx.span(id).unwrap(), ctx.span(follows).unwrap());
+            self.follows
+                .lock()
+                .unwrap()
+                .push((span.name().to_string(), follows.name().to_string()));
+        }
+    }
+
+    #[tokio::test]
+    async fn traces_catch_ups_under_whoever_waits_for_them() {
+        use tracing_subscriber::layer::SubscriberExt;
+
+        let spans = SpanTree::default();
+        let _tracing = tracing::subscriber::set_default(tracing_subscriber::registry().with(spans.clone()));
+
+        let store = EventStore::in_memory();
+        let counters = CounterStore::new(InMemoryCounterStore::default());
+        store_events(&store, "c1", 1).await;
+        let handle = worker(&store, &counters).spawn();
+
+        // Both wait for the same catch up
+        tokio::join!(
+            handle.sync().instrument(tracing::info_span!("first")),
+            handle.sync().instrument(tracing::info_span!("second")),
+        );
+        assert_eq!(counters.find_counter("c1").await.unwrap().value, 1);
+
+        let parents = spans.parents.lock().unwrap();
+        assert!(parents.contains(&("projection".to_string(), "first".to_string())));
+        let follows = spans.follows.lock().unwrap();
+        assert_eq!(*follows, vec![("projection".to_string(), "second".to_string())]);
+    }
+
+    /// An event log of its own, with sequences that can be moved around.
+    #[cfg(feature = "sqlite")]
+    async fn sql_event_store() -> (EventStore, sea_orm::DbConn) {
+        let mut options = sea_orm::ConnectOptions::new("sqlite::memory:");
+        options.max_connections(1).sqlx_logging(false);
+
+        let db = kountr_db::Database::connect(options).await.unwrap();
+        kountr_db::repository::Repository::new(&db).run_migrations().await.unwrap();
+
+        (EventStore::new(db.clone()), db)
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[tokio::test]
+    async fn waits_for_events_committed_late() {
+        use sea_orm::ConnectionTrait;
+
+        let (store, db) = sql_event_store().await;
+        let counters = CounterStore::new(InMemoryCounterStore::default());
+        store_events(&store, "c1", 2).await;
+
+        // The second event isn't visible yet, the third one is
+        db.execute_unprepared("UPDATE events SET sequence = 10 WHERE sequence = 2").await.unwrap();
+        let commit = tokio::spawn({
+            let db = db.clone();
+            async move {
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                db.execute_unprepared("UPDATE events SET sequence = 2 WHERE sequence = 10").await.unwrap();
+            }
+        });
+
+        assert_eq!(worker(&store, &counters).catch_up().await.unwrap(), 3);
+        commit.await.unwrap();
+        assert_eq!(counters.find_counter("c1").await.unwrap().value, 2);
+        assert_eq!(counters.find_checkpoint("counters").await.unwrap(), 3);
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[tokio::test]
+    async fn skips_events_missing_for_too_long() {
+        use sea_orm::ConnectionTrait;
+
+        let (store, db) = sql_event_store().await;
+        let counters = CounterStore::new(InMemoryCounterStore::default());
+        store_events(&store, "c1", 2).await;
+
+        // eg. a rolled back append, whose sequence is never used
+        db.execute_unprepared("DELETE FROM events WHERE sequence = 2").await.unwrap();
+
+        let started = Instant::now();
+        assert_eq!(worker(&store, &counters).catch_up().await.unwrap(), 2);
+        assert!(started.elapsed() >= GAP_TIMEOUT);
+        assert_eq!(counters.find_counter("c1").await.unwrap().value, 1);
+        assert_eq!(counters.find_checkpoint("counters").await.unwrap(), 3);
+    }
+
+    #[tokio::test]
+    async fn stops_at_a_failed_event_and_retries_it() {
+        let store = EventStore::in_memory();
+        let counters = CounterStore::new(InMemoryCounterStore::default());
+        store_events(&store, "c1", 3).await;
+
+        let mut worker = ProjectionWorker::new(
+            "counters",
+            store.clone(),
+            FailingProjection {
+                inner: CounterProjection::new(&counters),
+                fail_on: Arc::new(AtomicI64::new(3)),
+            },
+        );
+
+        assert!(matches!(worker.catch_up().await, Err(DbError::Invalid(_))));
+        assert_eq!(counters.find_checkpoint("counters").await.unwrap(), 2);
+        assert_eq!(counters.find_counter("c1").await.unwrap().value, 1);
+
+        assert_eq!(worker.catch_up().await.unwrap(), 2);
+        assert_eq!(counters.find_checkpoint("counters").await.unwrap(), 4);
+        assert_eq!(counters.find_counter("c1").await.unwrap().value, 3);
+    }
+
+    #[tokio::test]
+    async fn applies_events_once_with_concurrent_workers() {
+        let store = EventStore::in_memory();
+        let counters = CounterStore::new(test_repo().await);
+        let id = uuid::Uuid::new_v4().to_string();
+        store_events(&store, &id, 20).await;
+
+        let (first, second) = tokio::join!(
+            async { worker(&store, &counters).catch_up().await },
+            async { worker(&store, &counters).catch_up().await },
+        );
+
+        // Every event is applied by one worker or the other
+        assert_eq!(first.unwrap() + second.unwrap(), 21);
+        assert_eq!(counters.find_counter(&id).await.unwrap().value, 20);
+        assert_eq!(counters.find_checkpoint("counters").await.unwrap(), 21);
+    }
+
+    #[tokio::test]
+    async fn rejects_a_stale_checkpoint() {
+        let counters = CounterStore::new(test_repo().await);
+        let id = uuid::Uuid::new_v4().to_string();
+        let create = CounterUpdate::Create {
+            id: id.clone(),
+            name: "Coffee".to_string(),
+            value: 0,
+        };
+        let add = CounterUpdate::Add {
+            id: id.clone(),
+            amount: 1,
+        };
+
+        counters.project("counters", 0, 1, create.clone()).await.unwrap();
+        counters.project("counters", 1, 2, add.clone()).await.unwrap();
+
+        assert!(matches!(
+            counters.project("counters", 0, 1, create).await,
+            Err(DbError::Conflict(_))
+        ));
+        assert!(matches!(
+            counters.project("counters", 1, 2, add).await,
+            Err(DbError::Conflict(_))
+        ));
+        assert_eq!(counters.find_counter(&id).await.unwrap().value, 1);
+
+        // Changes to missing counters are skipped
+        let missing = uuid::Uuid::new_v4().to_string();
+        let add = CounterUpdate::Add { id: missing.clone(), amount: 1 };
+        counters.project("counters", 2, 3, add).await.unwrap();
+        assert!(matches!(counters.find_counter(&missing).await, Err(DbError::NotFound)));
+    }
+}
@@ -0,0 +1,115 @@
+use std::collections::VecDeque;
+
+use chrono::{NaiveDate, Utc};
+use futures::{stream, Stream};
+
+use crate::cqrs::CounterEvent;
+use crate::domain::models;
+use kountr_db::{
+    error::DbError,
+    event_store::{EventFilter, EventStore, SequencedEvent},
+};
+
+// How many events are loaded at once while walking through the days.
+const PAGE_SIZE: u64 = 500;
+
+/// Streams the value of a counter at the end of every day, from the day it was
+/// created until today (or until it was deleted), including days without changes.
+///
+/// Events are loaded a page at a time, so long histories aren't kept in memory.
+pub async fn daily_values(
+    store: &EventStore,
+    aggregate_id: &str,
+) -> Result<impl Stream<Item = Result<models::DailyValue, DbError>>, DbError> {
+    let mut series = DailySeries {
+        store: store.clone(),
+        filter: EventFilter {
+            limit: PAGE_SIZE,
+            aggregate_id: Some(aggregate_id.to_string()),
+            ..Default::default()
+        },
+        page: VecDeque::new(),
+        exhausted: false,
+        next: None,
+        until: Utc::now().date_naive(),
+        value: 0,
+        deleted: false,
+    };
+
+    series.next = Some(series.peek_date().await?.ok_or(DbError::NotFound)?);
+
+    Ok(stream::try_unfold(series, |mut series| async move {
+        let row = series.next_row().await?;
+        Ok(row.map(|row| (row, series)))
+    }))
+}
+
+struct DailySeries {
+    store: EventStore,
+    filter: EventFilter,
+    page: VecDeque<SequencedEvent>,
+    exhausted: bool,
+    /// The next day to emit, `None` when done.
+    next: Option<NaiveDate>,
+    until: NaiveDate,
+    value: i32,
+    deleted: bool,
+}
+
+impl DailySeries {
+    /// The day of the next event, loading another page when needed.
+    async fn peek_date(&mut self) -> Result<Option<NaiveDate>, DbError> {
+        if self.page.is_empty() && !self.exhausted {
+            let page = self.store.stream_events(&self.filter).await?;
+            self.exhausted = (page.len() as u64) < self.filter.limit;
+            if let Some(last) = page.last() {
+                self.filter.after = last.sequence;
+            }
+            self.page.extend(page);
+        }
+
+        Ok(self
+            .page
+            .front()
+            .map(|stored| stored.event.timestamp.date_naive()))
+    }
+
+    async fn next_row(&mut self) -> Result<Option<models::DailyValue>, DbError> {
+        let Some(day) = self.next else {
+            return Ok(None);
+        };
+        let opening = self.value;
+
+        while let Some(date) = self.peek_date().await? {
+            if date > day {
+                break;
+            }
+            if let Some(stored) = self.page.pop_front() {
+                self.apply(stored.event.get_payload());
+            }
+        }
+
+        let pending = self.peek_date().await?.is_some();
+        self.next = match (self.deleted, pending) {
+            (_, true) => day.succ_opt(),
+            (false, false) if day < self.until => day.succ_opt(),
+            _ => None,
+        };
+
+        Ok(Some(models::DailyValue {
+            date: day,
+            value: self.value,
+            change: self.value - opening,
+        }))
+    }
+
+    fn apply(&mut self, event: CounterEvent) {
+        match event {
+            CounterEvent::CounterCreated { value, .. } | CounterEvent::CounterUpdated { value, .. } => {
+                self.value = value
+            }
+            CounterEvent::CounterDeleted { .. } => self.deleted = true,
+            event => self.value += event.delta(),
+        }
+    }
+}
@@ -1,5 +1,11 @@
 use crate::domain::models::*;
+use kountr_db::entity::alert_rules::Model as ModelAlertRule;
+use kountr_db::entity::alerts::Model as ModelAlert;
 use kountr_db::entity::counters::Model as ModelCounter;
+use kountr_db::entity::notifications::Model as ModelNotification;
+use kountr_db::entity::webhook_deliveries::Model as ModelWebhookDelivery;
+use kountr_db::entity::webhooks::Model as ModelWebhook;
+use kountr_db::event_store::SequencedEvent;
 
 impl From<ModelCounter> for Counter {
     fn from(model: ModelCounter) -> Self {
@@ -20,3 +26,174 @@ impl Into<ModelCounter> for Counter {
         }
     }
 }
+
+impl From<ModelWebhook> for Webhook {
+    fn from(model: ModelWebhook) -> Self {
+        Webhook {
+            id: model.id,
+            url: model.url,
+            event_types: model
+                .event_types
+                .split(',')
+                .filter(|t| !t.is_empty())
+                .map(String::from)
+                .collect(),
+            secret: model.secret,
+            created_at: model.created_at,
+        }
+    }
+}
+
+impl Into<ModelWebhook> for Webhook {
+    fn into(self) -> ModelWebhook {
+        ModelWebhook {
+            id: self.id,
+            url: self.url,
+            event_types: self.event_types.join(","),
+            secret: self.secret,
+            created_at: self.created_at,
+        }
+    }
+}
+
+impl From<ModelWebhookDelivery> for WebhookDelivery {
+    fn from(model: ModelWebhookDelivery) -> Self {
+        WebhookDelivery {
+            id: model.id,
+            webhook_id: model.webhook_id,
+            event_id: model.event_id,
+            event_type: model.event_type,
+            attempt: model.attempt,
+            status_code: model.status_code,
+            error: model.error,
+            success: model.success,
+            timestamp: model.timestamp,
+        }
+    }
+}
+
+impl Into<ModelWebhookDelivery> for WebhookDelivery {
+    fn into(self) -> ModelWebhookDelivery {
+        ModelWebhookDelivery {
+            id: self.id,
+            webhook_id: self.webhook_id,
+            event_id: self.event_id,
+            event_type: self.event_type,
+            attempt: self.attempt,
+            status_code: self.status_code,
+            error: self.error,
+            success: self.success,
+            timestamp: self.timestamp,
+        }
+    }
+}
+
+impl TryFrom<ModelAlertRule> for AlertRule {
+    type Error = String;
+
+    fn try_from(model: ModelAlertRule) -> Result<Self, Self::Error> {
+        let condition = AlertCondition::parse(&model.condition, model.threshold)
+            .ok_or(format!("unknown alert condition: {}", model.condition))?;
+        let channel = AlertChannel::parse(&model.channel)
+            .ok_or(format!("unknown alert channel: {}", model.channel))?;
+
+        Ok(AlertRule {
+            id: model.id,
+            counter_id: model.counter_id,
+            name: model.name,
+            condition,
+            channel,
+            target: model.target,
+            debounce_secs: model.debounce_secs,
+            created_at: model.created_at,
+        })
+    }
+}
+
+impl Into<ModelAlertRule> for AlertRule {
+    fn into(self) -> ModelAlertRule {
+        ModelAlertRule {
+            id: self.id,
+            counter_id: self.counter_id,
+            name: self.name,
+            condition: self.condition.kind().to_string(),
+            threshold: self.condition.threshold(),
+            channel: self.channel.as_str().to_string(),
+            target: self.target,
+            debounce_secs: self.debounce_secs,
+            created_at: self.created_at,
+        }
+    }
+}
+
+impl TryFrom<ModelAlert> for Alert {
+    type Error = String;
+
+    fn try_from(model: ModelAlert) -> Result<Self, Self::Error> {
+        let channel = AlertChannel::parse(&model.channel)
+            .ok_or(format!("unknown alert channel: {}", model.channel))?;
+
+        Ok(Alert {
+            id: model.id,
+            rule_id: model.rule_id,
+            counter_id: model.counter_id,
+            message: model.message,
+            value: model.value,
+            channel,
+            error: model.error,
+            fired_at: model.fired_at,
+        })
+    }
+}
+
+impl Into<ModelAlert> for Alert {
+    fn into(self) -> ModelAlert {
+        ModelAlert {
+            id: self.id,
+            rule_id: self.rule_id,
+            counter_id: self.counter_id,
+            message: self.message,
+            value: self.value,
+            channel: self.channel.as_str().to_string(),
+            error: self.error,
+            fired_at: self.fired_at,
+        }
+    }
+}
+
+impl From<ModelNotification> for Notification {
+    fn from(model: ModelNotification) -> Self {
+        Notification {
+            id: model.id,
+            alert_id: model.alert_id,
+            message: model.message,
+            read: model.read,
+            created_at: model.created_at,
+        }
+    }
+}
+
+impl Into<ModelNotification> for Notification {
+    fn into(self) -> ModelNotification {
+        ModelNotification {
+            id: self.id,
+            alert_id: self.alert_id,
+            message: self.message,
+            read: self.read,
+            created_at: self.created_at,
+        }
+    }
+}
+
+impl From<SequencedEvent> for StoredEvent {
+    fn from(stored: SequencedEvent) -> Self {
+        Self {
+            sequence: stored.sequence,
+            id: stored.event.id,
+            event_type: stored.event.event_type,
+            aggregate_id: stored.event.aggregate_id,
+            timestamp: stored.event.timestamp,
+            data: stored.event.payload,
+        }
+    }
+}
@@ -0,0 +1,82 @@
+use opentelemetry::{
+    global,
+    sdk::{propagation::TraceContextPropagator, trace, Resource},
+    KeyValue,
+};
+use opentelemetry_otlp::WithExportConfig;
+use tracing::{metadata::LevelFilter, Subscriber};
+use tracing_subscriber::{
+    filter, layer::SubscriberExt, registry::LookupSpan, util::SubscriberInitExt, EnvFilter, Layer,
+};
+
+use crate::config::{AppEnv, AppOptions, LogFormat};
+
+pub fn init_app_tracing(opts: &AppOptions) {
+    let debug_filter = match opts.env {
+        AppEnv::Prod => LevelFilter::OFF,
+        _ => LevelFilter::DEBUG,
+    };
+
+    let tracing_filter = match &opts.log_filter {
+        // already validated, see `AppOptions`
+        Some(directives) => EnvFilter::new(directives).boxed(),
+        None => filter::Targets::new()
+            .with_target("tower_http::trace::on_response", opts.log_level)
+            .with_target("sea_orm_migration::migrator", debug_filter)
+            .with_target("sqlx::query", debug_filter)
+            .with_default(opts.log_level)
+            .boxed(),
+    };
+
+    let tracing_layer = match opts.log_format {
+        LogFormat::Compact => tracing_subscriber::fmt::layer().compact().boxed(),
+        LogFormat::Json => tracing_subscriber::fmt::layer()
+            .json()
+            .flatten_event(true)
+            .with_current_span(false)
+            .with_span_list(true)
+            .boxed(),
+    };
+
+    let otlp_layer = opts.otlp_endpoint.as_deref().and_then(|endpoint| {
+        otlp_layer(endpoint)
+            .map_err(|err| eprintln!("Cannot export traces to {}: {}", endpoint, err))
+            .ok()
+    });
+
+    tracing_subscriber::registry()
+        .with(tracing_filter)
+        .with(tracing_layer)
+        .with(otlp_layer)
+        .init();
+}
+
+/// Exports spans to an OpenTelemetry collector, with OTLP over HTTP (eg. `http://localhost:4318`).
+/// Incoming `traceparent` headers are honored, see `TraceContextPropagator`.
+pub fn otlp_layer<S>(endpoint: &str) -> Result<impl Layer<S>, opentelemetry::trace::TraceError>
+where
+    S: Subscriber + for<'span> LookupSpan<'span>,
+{
+    global::set_text_map_propagator(TraceContextPropagator::new());
+
+    let service_name = std::env::var("OTEL_SERVICE_NAME").unwrap_or("kountr".to_string());
+    let exporter = opentelemetry_otlp::new_exporter()
+        .http()
+        .with_endpoint(format!("{}/v1/traces", endpoint.trim_end_matches('/')));
+
+    let tracer = opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(exporter)
+        .with_trace_config(
+            trace::config().with_resource(Resource::new([KeyValue::new("service.name", service_name)])),
+        )
+        .install_batch(opentelemetry::runtime::Tokio)?;
+
+    Ok(tracing_opentelemetry::layer().with_tracer(tracer))
+}
+
+/// Sends the spans still buffered, if any.
+pub async fn shutdown_tracing() {
+    // It blocks until the exporter is done
+    _ = tokio::task::spawn_blocking(global::shutdown_tracer_provider).await;
+}
@@ -0,0 +1,327 @@
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use mini_cqrs::Event;
+use serde::Serialize;
+use sha2::Sha256;
+use tokio_util::task::TaskTracker;
+use tracing::{debug, warn, Instrument};
+
+use crate::domain::models::{Webhook, WebhookDelivery};
+use kountr_db::repository::Repository;
+
+pub const SIGNATURE_HEADER: &str = "X-Kountr-Signature";
+pub const EVENT_TYPE_HEADER: &str = "X-Kountr-Event";
+pub const MESSAGE_ID_HEADER: &str = "X-Kountr-Message-Id";
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How many times, and how often, a failed delivery is retried.
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    pub max_attempts: u32,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 5,
+            base_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(60),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Exponential backoff: waits `base_delay * 2^(attempt - 1)` after the given failed attempt.
+    pub fn delay_after(&self, attempt: u32) -> Duration {
+        let factor = 2u32.saturating_pow(attempt.saturating_sub(1));
+        self.base_delay.saturating_mul(factor).min(self.max_delay)
+    }
+}
+
+/// The JSON body posted to webhooks, along with the headers needed to route it.
+#[derive(Clone, Debug)]
+pub struct WebhookMessage {
+    pub id: String,
+    pub event_type: String,
+    pub body: String,
+}
+
+#[derive(Serialize)]
+struct EventPayload<'a> {
+    id: &'a str,
+    event_type: &'a str,
+    aggregate_id: &'a str,
+    timestamp: DateTime<Utc>,
+    data: &'a serde_json::Value,
+}
+
+impl From<&Event> for WebhookMessage {
+    fn from(event: &Event) -> Self {
+        let payload = EventPayload {
+            id: &event.id,
+            event_type: &event.event_type,
+            aggregate_id: &event.aggregate_id,
+            timestamp: event.timestamp,
+            data: &event.payload,
+        };
+
+        Self {
+            id: event.id.clone(),
+            event_type: event.event_type.clone(),
+            // it's already valid JSON, serialization can't fail
+            body: serde_json::to_string(&payload).unwrap(),
+        }
+    }
+}
+
+/// Hex encoded HMAC-SHA256 of `body`, sent as `sha256=<signature>` so that receivers can verify it.
+pub fn sign(secret: &str, body: &str) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any size");
+    mac.update(body.as_bytes());
+
+    hex::encode(mac.finalize().into_bytes())
+}
+
+/// Delivers messages to webhooks, retrying failures and logging every attempt.
+#[derive(Clone)]
+pub struct WebhookSender {
+    client: reqwest::Client,
+    repo: Repository,
+    policy: RetryPolicy,
+    tasks: TaskTracker,
+}
+
+impl WebhookSender {
+    pub fn new(repo: &Repository, policy: RetryPolicy) -> Self {
+        let client = reqwest::Client::builder()
+            .timeout(REQUEST_TIMEOUT)
+            .build()
+            .expect("Cannot build HTTP client");
+
+        Self {
+            client,
+            repo: repo.clone(),
+            policy,
+            tasks: TaskTracker::new(),
+        }
+    }
+
+    /// Delivers the message in background, see `drain`.
+    pub fn spawn_deliver(&self, webhook: Webhook, message: WebhookMessage) {
+        let sender = self.clone();
+        let delivery = async move { sender.deliver(&webhook, &message).await };
+        self.tasks.spawn(delivery.in_current_span());
+    }
+
+    /// Waits for the deliveries in progress, including their retries.
+    pub async fn drain(&self) {
+        self.tasks.close();
+        self.tasks.wait().await;
+    }
+
+    /// Returns whether the message has been delivered before running out of attempts.
+    pub async fn deliver(&self, webhook: &Webhook, message: &WebhookMessage) -> bool {
+        let signature = format!("sha256={}", sign(&webhook.secret, &message.body));
+
+        for attempt in 1..=self.policy.max_attempts {
+            let result = self.send(webhook, message, &signature).await;
+            let success = matches!(result, Ok(status) if (200..300).contains(&status));
+
+            let delivery = WebhookDelivery {
+                id: uuid::Uuid::new_v4().to_string(),
+                webhook_id: webhook.id.clone(),
+                event_id: message.id.clone(),
+                event_type: message.event_type.clone(),
+                attempt: attempt as i32,
+                status_code: result.as_ref().ok().map(|status| *status as i32),
+                error: result.err(),
+                success,
+                timestamp: Utc::now(),
+            };
+            if let Err(err) = self.repo.insert_webhook_delivery(delivery.into()).await {
+                warn!("Cannot log webhook delivery: {}", err);
+            }
+
+            if success {
+                debug!("Delivered {} to webhook {}", message.id, webhook.id);
+                return true;
+            }
+
+            if attempt < self.policy.max_attempts {
+                tokio::time::sleep(self.policy.delay_after(attempt)).await;
+            }
+        }
+
+        warn!("Giving up delivering {} to webhook {}", message.id, webhook.id);
+        false
+    }
+
+    async fn send(
+        &self,
+        webhook: &Webhook,
+        message: &WebhookMessage,
+        signature: &str,
+    ) -> Result<u16, String> {
+        let response = self
+            .client
+            .post(&webhook.url)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .header(SIGNATURE_HEADER, signature)
+            .header(EVENT_TYPE_HEADER, &message.event_type)
+            .header(MESSAGE_ID_HEADER, &message.id)
+            .body(message.body.clone())
+            .send()
+            .await
+            .map_err(|err| err.to_string())?;
+
+        Ok(response.status().as_u16())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::SocketAddr,
+        sync::{Arc, Mutex},
+    };
+
+    use axum::{http::HeaderMap, http::StatusCode, routing::post, Router};
+
+    use super::*;
+    use crate::test_repo;
+
+    fn message() -> WebhookMessage {
+        WebhookMessage {
+            id: "e1".to_string(),
+            event_type: "CounterIncremented".to_string(),
+            body: r#"{"id":"e1"}"#.to_string(),
+        }
+    }
+
+    fn fast_policy(max_attempts: u32) -> RetryPolicy {
+        RetryPolicy {
+            max_attempts,
+            base_delay: Duration::from_millis(5),
+            max_delay: Duration::from_millis(20),
+        }
+    }
+
+    /// A webhook receiver answering with the given statuses in turn, then 200,
+    /// which records the headers and bodies it gets.
+    async fn receiver(statuses: Vec<StatusCode>) -> (SocketAddr, Arc<Mutex<Vec<(HeaderMap, String)>>>) {
+        let received = Arc::new(Mutex::new(vec![]));
+        let statuses = Arc::new(Mutex::new(statuses.into_iter()));
+
+        let router = Router::new().route(
+            "/hook",
+            post({
+                let received = received.clone();
+                move |headers: HeaderMap, body: String| async move {
+                    received.lock().unwrap().push((headers, body));
+                    statuses.lock().unwrap().next().unwrap_or(StatusCode::OK)
+                }
+            }),
+        );
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = axum::Server::from_tcp(listener)
+            .unwrap()
+            .serve(router.into_make_service());
+        tokio::spawn(server);
+
+        (addr, received)
+    }
+
+    async fn new_webhook(repo: &Repository, addr: SocketAddr) -> Webhook {
+        let webhook = Webhook::new(format!("http://{}/hook", addr), vec![], "secret".to_string());
+        repo.insert_webhook(webhook.into()).await.unwrap().into()
+    }
+
+    #[test]
+    fn signs_with_hmac_sha256() {
+        // RFC 4231, test case 2
+        assert_eq!(
+            sign("Jefe", "what do ya want for nothing?"),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+
+    #[test]
+    fn backs_off_exponentially_up_to_the_max_delay() {
+        let policy = RetryPolicy::default();
+
+        let delays: Vec<u64> = (1..=8).map(|attempt| policy.delay_after(attempt).as_secs()).collect();
+        assert_eq!(delays, vec![1, 2, 4, 8, 16, 32, 60, 60]);
+        assert_eq!(policy.delay_after(0), Duration::from_secs(1));
+        assert_eq!(policy.delay_after(u32::MAX), Duration::from_secs(60));
+    }
+
+    #[tokio::test]
+    async fn retries_server_errors_and_records_deliveries() {
+        let repo = test_repo().await;
+        let (addr, received) = receiver(vec![StatusCode::INTERNAL_SERVER_ERROR]).await;
+        let webhook = new_webhook(&repo, addr).await;
+
+        let sender = WebhookSender::new(&repo, fast_policy(3));
+        assert!(sender.deliver(&webhook, &message()).await);
+
+        let received = received.lock().unwrap().clone();
+        assert_eq!(received.len(), 2);
+        let (headers, body) = &received[1];
+        assert_eq!(body, r#"{"id":"e1"}"#);
+        assert_eq!(
+            headers[SIGNATURE_HEADER],
+            format!("sha256={}", sign("secret", r#"{"id":"e1"}"#)).as_str()
+        );
+        assert_eq!(headers[EVENT_TYPE_HEADER], "CounterIncremented");
+        assert_eq!(headers[MESSAGE_ID_HEADER], "e1");
+        assert_eq!(headers["content-type"], "application/json");
+
+        let mut deliveries = repo.list_webhook_deliveries(webhook.id).await.unwrap();
+        deliveries.sort_by_key(|delivery| delivery.attempt);
+        let attempts: Vec<_> = deliveries
+            .iter()
+            .map(|d| (d.attempt, d.status_code, d.success, d.event_id.as_str()))
+            .collect();
+        assert_eq!(attempts, vec![(1, Some(500), false, "e1"), (2, Some(200), true, "e1")]);
+    }
+
+    #[tokio::test]
+    async fn gives_up_after_the_last_attempt() {
+        let repo = test_repo().await;
+        let (addr, received) = receiver(vec![StatusCode::BAD_GATEWAY; 3]).await;
+        let webhook = new_webhook(&repo, addr).await;
+
+        let sender = WebhookSender::new(&repo, fast_policy(2));
+        assert!(!sender.deliver(&webhook, &message()).await);
+
+        assert_eq!(received.lock().unwrap().len(), 2);
+        let deliveries = repo.list_webhook_deliveries(webhook.id).await.unwrap();
+        assert_eq!(deliveries.len(), 2);
+        assert!(deliveries.iter().all(|d| !d.success && d.status_code == Some(502)));
+    }
+
+    #[tokio::test]
+    async fn records_connection_errors() {
+        let repo = test_repo().await;
+        // Nothing listens there anymore
+        let addr = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let webhook = new_webhook(&repo, addr).await;
+
+        let sender = WebhookSender::new(&repo, fast_policy(1));
+        assert!(!sender.deliver(&webhook, &message()).await);
+
+        let deliveries = repo.list_webhook_deliveries(webhook.id).await.unwrap();
+        assert!(matches!(
+            deliveries.as_slice(),
+            [delivery] if delivery.status_code.is_none() && delivery.error.is_some()
+        ));
+    }
+}
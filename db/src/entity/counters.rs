@@ -6,6 +6,7 @@ use sea_orm::entity::prelude::*;
 #[sea_orm(table_name = "counters")]
 pub struct Model {
     #[sea_orm(primary_key, auto_increment = false)]
+    #[cfg_attr(feature = "postgres", sea_orm(select_as = "text", save_as = "uuid"))]
     pub id: String,
     pub name: String,
     pub value: i32,
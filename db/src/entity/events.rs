@@ -2,16 +2,22 @@
 
 use mini_cqrs::Event;
 use sea_orm::entity::prelude::*;
+use sea_orm::{NotSet, Set};
 
 #[derive(Clone, Debug, PartialEq, DeriveEntityModel, Eq)]
 #[sea_orm(table_name = "events")]
 pub struct Model {
-    #[sea_orm(primary_key, auto_increment = false)]
+    #[sea_orm(primary_key)]
+    pub sequence: i64,
+    #[sea_orm(unique)]
+    #[cfg_attr(feature = "postgres", sea_orm(select_as = "text", save_as = "uuid"))]
     pub id: String,
     pub name: String,
     pub payload: Json,
+    #[cfg_attr(feature = "postgres", sea_orm(select_as = "text", save_as = "uuid"))]
     pub aggregate_id: String,
     pub timestamp: ChronoDateTimeUtc,
+    pub schema_version: i32,
 }
 
 #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
@@ -19,14 +25,18 @@ pub enum Relation {}
 
 impl ActiveModelBehavior for ActiveModel {}
 
-impl From<Event> for Model {
+impl From<Event> for ActiveModel {
     fn from(event: Event) -> Self {
         Self {
-            id: event.id,
-            name: event.event_type,
-            payload: event.payload,
-            aggregate_id: event.aggregate_id,
-            timestamp: event.timestamp,
+            // assigned by the database
+            sequence: NotSet,
+            id: Set(event.id),
+            name: Set(event.event_type),
+            payload: Set(event.payload),
+            aggregate_id: Set(event.aggregate_id),
+            timestamp: Set(event.timestamp),
+            // known by the event store, see `Upcasters`
+            schema_version: NotSet,
         }
     }
 }
@@ -1,5 +1,12 @@
 pub mod events;
 pub mod counters;
+pub mod webhooks;
+pub mod webhook_deliveries;
+pub mod alert_rules;
+pub mod alerts;
+pub mod notifications;
+pub mod consumer_checkpoints;
+pub mod idempotency_keys;
 
 pub mod prelude;
 pub use prelude::*;
@@ -1,4 +1,11 @@
 //! `SeaORM` Entity. Generated by sea-orm-codegen 0.12.2
 
+pub use super::alert_rules::Entity as AlertRules;
+pub use super::alerts::Entity as Alerts;
+pub use super::consumer_checkpoints::Entity as ConsumerCheckpoints;
 pub use super::counters::Entity as Counters;
 pub use super::events::Entity as Events;
+pub use super::idempotency_keys::Entity as IdempotencyKeys;
+pub use super::notifications::Entity as Notifications;
+pub use super::webhook_deliveries::Entity as WebhookDeliveries;
+pub use super::webhooks::Entity as Webhooks;
@@ -7,6 +7,8 @@ pub enum DbError {
     #[error(transparent)]
     Db(#[from] sea_orm::DbErr),
     NotFound,
+    Invalid(String),
+    Conflict(String),
     #[error(transparent)]
     Cqrs(#[from] mini_cqrs::CqrsError),
 }
@@ -1,19 +1,240 @@
+use std::collections::HashSet;
+use std::sync::{Arc, RwLock};
+
 use async_trait::async_trait;
+use chrono::{DateTime, Utc};
 pub use mini_cqrs::{CqrsError, Event, EventStore as CqrsEventStore};
 
-use crate::{entity::events, DbConn};
+use crate::{entity::events, upcasting::Upcasters, DbConn};
 
 use sea_orm::*;
 
+// How many rows are looked up or inserted per query, to stay below parameter limits.
+const IDS_CHUNK_SIZE: usize = 500;
+
+/// A stored event, along with its position in the global log.
+#[derive(Clone, Debug)]
+pub struct SequencedEvent {
+    /// Assigned on save, it grows monotonically and is never reused.
+    pub sequence: i64,
+    pub event: Event,
+}
+
+impl From<events::Model> for SequencedEvent {
+    fn from(model: events::Model) -> Self {
+        Self {
+            sequence: model.sequence,
+            event: model.into(),
+        }
+    }
+}
+
+/// Selects a page of the global event log, see `EventStore::stream_events`.
+#[derive(Clone, Debug, Default)]
+pub struct EventFilter {
+    /// Only events with a greater sequence, 0 to start from the beginning.
+    pub after: i64,
+    pub limit: u64,
+    /// Only events of these types, any of them when empty.
+    pub event_types: Vec<String>,
+    pub aggregate_id: Option<String>,
+    pub from: Option<DateTime<Utc>>,
+    pub to: Option<DateTime<Utc>>,
+}
+
+impl EventFilter {
+    pub fn matches(&self, stored: &SequencedEvent) -> bool {
+        stored.sequence > self.after
+            && (self.event_types.is_empty() || self.event_types.contains(&stored.event.event_type))
+            && self
+                .aggregate_id
+                .as_ref()
+                .is_none_or(|id| &stored.event.aggregate_id == id)
+            && self.from.is_none_or(|from| stored.event.timestamp >= from)
+            && self.to.is_none_or(|to| stored.event.timestamp < to)
+    }
+}
+
 // Event Store
 #[derive(Clone)]
 pub struct EventStore {
-    db: DbConn,
+    backend: Backend,
+    upcasters: Arc<Upcasters>,
+}
+
+#[derive(Clone)]
+enum Backend {
+    Sql(DbConn),
+    Memory(InMemoryEventStore),
 }
 
 impl EventStore {
     pub fn new(db: DbConn) -> Self {
-        EventStore { db }
+        EventStore {
+            backend: Backend::Sql(db),
+            upcasters: Arc::default(),
+        }
+    }
+
+    /// Keeps the events in memory only, they're lost on exit.
+    pub fn in_memory() -> Self {
+        EventStore {
+            backend: Backend::Memory(InMemoryEventStore::default()),
+            upcasters: Arc::default(),
+        }
+    }
+
+    /// Sets how stored payloads are brought to their current schema version on load.
+    pub fn with_upcasters(mut self, upcasters: Upcasters) -> Self {
+        self.upcasters = Arc::new(upcasters);
+        self
+    }
+
+    fn upcast(&self, mut model: events::Model) -> Result<SequencedEvent, CqrsError> {
+        model.payload = self
+            .upcasters
+            .upcast(&model.name, model.schema_version, model.payload)
+            .map_err(CqrsError::new)?;
+
+        Ok(model.into())
+    }
+
+    /// Loads up to `limit` events of any aggregate, in order, following the `after` sequence (0 to start over).
+    pub async fn load_events_after(
+        &self,
+        after: i64,
+        limit: u64,
+    ) -> Result<Vec<SequencedEvent>, CqrsError> {
+        self.stream_events(&EventFilter {
+            after,
+            limit,
+            ..Default::default()
+        })
+        .await
+    }
+
+    /// Loads a page of events of any aggregate, in order, matching `filter`.
+    /// Pass the sequence of the last one as `after` to get the next page.
+    pub async fn stream_events(&self, filter: &EventFilter) -> Result<Vec<SequencedEvent>, CqrsError> {
+        let db = match &self.backend {
+            Backend::Sql(db) => db,
+            Backend::Memory(store) => return Ok(store.stream_events(filter)),
+        };
+
+        let mut query = events::Entity::find().filter(events::Column::Sequence.gt(filter.after));
+
+        if !filter.event_types.is_empty() {
+            query = query.filter(events::Column::Name.is_in(filter.event_types.clone()));
+        }
+        if let Some(from) = filter.from {
+            query = query.filter(events::Column::Timestamp.gte(from));
+        }
+        if let Some(to) = filter.to {
+            query = query.filter(events::Column::Timestamp.lt(to));
+        }
+        if let Some(aggregate_id) = &filter.aggregate_id {
+            query = query.filter(events::Column::AggregateId.eq(aggregate_id.clone()));
+        }
+
+        let models = query
+            .order_by_asc(events::Column::Sequence)
+            .limit(filter.limit)
+            .all(db)
+            .await
+            .map_err(|err| CqrsError::new(err.to_string()))?;
+
+        models.into_iter().map(|model| self.upcast(model)).collect()
+    }
+
+    /// The sequence of the last stored event, 0 when there are none.
+    pub async fn last_sequence(&self) -> Result<i64, CqrsError> {
+        let db = match &self.backend {
+            Backend::Sql(db) => db,
+            Backend::Memory(store) => return Ok(store.last_sequence()),
+        };
+
+        let last: Option<Option<i64>> = events::Entity::find()
+            .select_only()
+            .column_as(events::Column::Sequence.max(), "last")
+            .into_tuple()
+            .one(db)
+            .await
+            .map_err(|err| CqrsError::new(err.to_string()))?;
+
+        Ok(last.flatten().unwrap_or(0))
+    }
+
+    /// How many events are stored with a greater sequence, eg. not projected yet.
+    /// Sequences can have gaps, so it's a count, not a difference of sequences.
+    pub async fn count_events_after(&self, sequence: i64) -> Result<u64, CqrsError> {
+        let db = match &self.backend {
+            Backend::Sql(db) => db,
+            Backend::Memory(store) => return Ok(store.count_events_after(sequence)),
+        };
+
+        events::Entity::find()
+            .filter(events::Column::Sequence.gt(sequence))
+            .count(db)
+            .await
+            .map_err(|err| CqrsError::new(err.to_string()))
+    }
+
+    /// Returns which of the given event ids are already stored.
+    pub async fn find_existing_ids(&self, ids: &[String]) -> Result<HashSet<String>, CqrsError> {
+        let db = match &self.backend {
+            Backend::Sql(db) => db,
+            Backend::Memory(store) => return Ok(store.find_existing_ids(ids)),
+        };
+
+        let mut existing = HashSet::new();
+        for chunk in ids.chunks(IDS_CHUNK_SIZE) {
+            let found: Vec<String> = events::Entity::find()
+                .select_only()
+                .column(events::Column::Id)
+                .filter(events::Column::Id.is_in(chunk.to_vec()))
+                .into_tuple()
+                .all(db)
+                .await
+                .map_err(|err| CqrsError::new(err.to_string()))?;
+
+            existing.extend(found);
+        }
+
+        Ok(existing)
+    }
+
+    /// Appends events of any aggregate as they are, with their ids and timestamps
+    /// (eg. from an export): either all of them are stored or none.
+    pub async fn import_events(&self, events: &[Event]) -> Result<(), CqrsError> {
+        let db = match &self.backend {
+            Backend::Sql(db) => db,
+            Backend::Memory(store) => {
+                store.append(events);
+                return Ok(());
+            }
+        };
+
+        let result: Result<(), TransactionError<DbErr>> = db
+            .transaction(|txn| {
+                let models: Vec<events::ActiveModel> = events
+                    .iter()
+                    .map(|evt| {
+                        let mut model: events::ActiveModel = evt.clone().into();
+                        model.schema_version = Set(self.upcasters.current_version(&evt.event_type));
+                        model
+                    })
+                    .collect();
+
+                Box::pin(async move {
+                    for chunk in models.chunks(IDS_CHUNK_SIZE) {
+                        events::Entity::insert_many(chunk.to_vec()).exec(txn).await?;
+                    }
+                    Ok(())
+                })
+            })
+            .await;
+
+        result.map_err(|err| CqrsError::new(err.to_string()))
     }
 }
 
@@ -21,42 +242,135 @@ impl EventStore {
 impl CqrsEventStore for EventStore {
     type AggregateId = String;
 
+    #[tracing::instrument(name = "event_store.save", skip_all, fields(aggregate_id = %aggregate_id, events = events.len()))]
     async fn save_events(
         &mut self,
-        _aggregate_id: Self::AggregateId,
+        aggregate_id: Self::AggregateId,
         events: &[Event],
     ) -> Result<(), CqrsError> {
-        for evt in events.iter() {
-            let model: events::ActiveModel =
-                <Event as Into<events::Model>>::into(evt.clone()).into();
-            let db = self.db.clone();
-            let _ = model.insert(&db).await;
+        let db = match &mut self.backend {
+            Backend::Sql(db) => db.clone(),
+            Backend::Memory(store) => return store.save_events(aggregate_id, events).await,
+        };
+
+        if events.is_empty() {
+            return Ok(());
         }
 
+        // A single statement, so either all the events of the command are stored or none
+        let models: Vec<events::ActiveModel> = events
+            .iter()
+            .map(|evt| {
+                let mut model: events::ActiveModel = evt.clone().into();
+                model.schema_version = Set(self.upcasters.current_version(&evt.event_type));
+                model
+            })
+            .collect();
+
+        events::Entity::insert_many(models)
+            .exec(&db)
+            .await
+            .map_err(|err| CqrsError::new(err.to_string()))?;
+        metrics::counter!("kountr_events_appended_total", events.len() as u64);
+
         Ok(())
     }
 
+    #[tracing::instrument(name = "event_store.load", skip_all, fields(aggregate_id = %aggregate_id))]
     async fn load_events(&self, aggregate_id: Self::AggregateId) -> Result<Vec<Event>, CqrsError> {
-        if let Ok(models) = events::Entity::find()
+        let db = match &self.backend {
+            Backend::Sql(db) => db.clone(),
+            Backend::Memory(store) => return store.load_events(aggregate_id).await,
+        };
+
+        let models = events::Entity::find()
             .filter(events::Column::AggregateId.eq(aggregate_id.clone().to_string()))
-            .all(&self.db.clone())
+            .order_by_asc(events::Column::Sequence)
+            .all(&db)
             .await
-        {
-            let events = models
-                .into_iter()
-                .map(|model| Event {
-                    id: model.id,
-                    aggregate_id: aggregate_id.clone(),
-                    event_type: model.name,
-                    payload: model.payload,
-                    timestamp: model.timestamp,
-                    version: 1,
-                })
-                .collect();
+            .map_err(|err| CqrsError::new(err.to_string()))?;
+
+        models
+            .into_iter()
+            .map(|model| Ok(self.upcast(model)?.event))
+            .collect()
+    }
+}
+
+/// Keeps events in memory only, eg. for tests and ephemeral runs. Clones share the same events.
+#[derive(Clone, Default)]
+pub struct InMemoryEventStore {
+    events: Arc<RwLock<Vec<SequencedEvent>>>,
+}
+
+impl InMemoryEventStore {
+    pub fn stream_events(&self, filter: &EventFilter) -> Vec<SequencedEvent> {
+        let events = self.events.read().unwrap();
+
+        events
+            .iter()
+            .filter(|stored| filter.matches(stored))
+            .take(filter.limit as usize)
+            .cloned()
+            .collect()
+    }
+
+    pub fn last_sequence(&self) -> i64 {
+        self.events.read().unwrap().len() as i64
+    }
+
+    pub fn count_events_after(&self, sequence: i64) -> u64 {
+        let events = self.events.read().unwrap();
+
+        events.iter().filter(|stored| stored.sequence > sequence).count() as u64
+    }
+
+    pub fn find_existing_ids(&self, ids: &[String]) -> HashSet<String> {
+        let events = self.events.read().unwrap();
+
+        events
+            .iter()
+            .map(|stored| &stored.event.id)
+            .filter(|id| ids.contains(id))
+            .cloned()
+            .collect()
+    }
 
-            return Ok(events);
+    fn append(&self, events: &[Event]) {
+        let mut stored = self.events.write().unwrap();
+        metrics::counter!("kountr_events_appended_total", events.len() as u64);
+
+        for evt in events.iter() {
+            let sequence = stored.len() as i64 + 1;
+            stored.push(SequencedEvent {
+                sequence,
+                event: evt.clone(),
+            });
         }
+    }
+}
+
+#[async_trait]
+impl CqrsEventStore for InMemoryEventStore {
+    type AggregateId = String;
+
+    async fn save_events(
+        &mut self,
+        _aggregate_id: Self::AggregateId,
+        events: &[Event],
+    ) -> Result<(), CqrsError> {
+        self.append(events);
+
+        Ok(())
+    }
+
+    async fn load_events(&self, aggregate_id: Self::AggregateId) -> Result<Vec<Event>, CqrsError> {
+        let stored = self.events.read().unwrap();
 
-        Ok(vec![])
+        Ok(stored
+            .iter()
+            .filter(|stored| stored.event.aggregate_id == aggregate_id)
+            .map(|stored| stored.event.clone())
+            .collect())
     }
 }
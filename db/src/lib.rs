@@ -3,5 +3,16 @@ pub mod migrations;
 pub mod repository;
 pub mod event_store;
 pub mod error;
+pub mod upcasting;
+
+#[cfg(any(
+    all(feature = "sqlite", feature = "postgres"),
+    all(feature = "sqlite", feature = "mysql"),
+    all(feature = "postgres", feature = "mysql"),
+))]
+compile_error!("Only one database backend can be enabled, eg. `--no-default-features --features postgres`");
+
+#[cfg(not(any(feature = "sqlite", feature = "postgres", feature = "mysql")))]
+compile_error!("A database backend must be enabled, either `sqlite`, `postgres` or `mysql`");
 
 pub use sea_orm::{Database, DatabaseConnection, DbConn, DbErr};
@@ -1,5 +1,7 @@
 use sea_orm_migration::prelude::*;
 
+use super::{json_column, timestamp_column, uuid_column};
+
 #[derive(DeriveMigrationName)]
 pub struct Migration;
 
@@ -11,19 +13,11 @@ impl MigrationTrait for Migration {
                 Table::create()
                     .table(Events::Table)
                     .if_not_exists()
-                    .col(ColumnDef::new(Events::Id).uuid().not_null().primary_key())
+                    .col(uuid_column(manager, Events::Id).not_null().primary_key())
                     .col(ColumnDef::new(Events::Name).string().not_null())
-                    .col(
-                        ColumnDef::new(Events::Payload)
-                            .json()
-                            .not_null(),
-                    )
-                    .col(ColumnDef::new(Events::AggregateId).uuid().not_null())
-                    .col(
-                        ColumnDef::new(Events::Timestamp)
-                            .timestamp_with_time_zone()
-                            .not_null(),
-                    )
+                    .col(json_column(manager, Events::Payload).not_null())
+                    .col(uuid_column(manager, Events::AggregateId).not_null())
+                    .col(timestamp_column(manager, Events::Timestamp).not_null())
                     .to_owned(),
             )
             .await?;
@@ -1,5 +1,7 @@
 use sea_orm_migration::prelude::*;
 
+use super::uuid_column;
+
 #[derive(DeriveMigrationName)]
 pub struct Migration;
 
@@ -11,7 +13,7 @@ impl MigrationTrait for Migration {
                 Table::create()
                     .table(Counters::Table)
                     .if_not_exists()
-                    .col(ColumnDef::new(Counters::Id).uuid().not_null().primary_key())
+                    .col(uuid_column(manager, Counters::Id).not_null().primary_key())
                     .col(ColumnDef::new(Counters::Name).string().not_null())
                     .col(
                         ColumnDef::new(Counters::Value)
@@ -0,0 +1,91 @@
+use sea_orm_migration::prelude::*;
+
+use super::{timestamp_column, uuid_column};
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .create_table(
+                Table::create()
+                    .table(Webhooks::Table)
+                    .if_not_exists()
+                    .col(uuid_column(manager, Webhooks::Id).not_null().primary_key())
+                    .col(ColumnDef::new(Webhooks::Url).string().not_null())
+                    .col(ColumnDef::new(Webhooks::EventTypes).string().not_null())
+                    .col(ColumnDef::new(Webhooks::Secret).string().not_null())
+                    .col(timestamp_column(manager, Webhooks::CreatedAt).not_null())
+                    .to_owned(),
+            )
+            .await?;
+
+        manager
+            .create_table(
+                Table::create()
+                    .table(WebhookDeliveries::Table)
+                    .if_not_exists()
+                    .col(
+                        uuid_column(manager, WebhookDeliveries::Id)
+                            .not_null()
+                            .primary_key(),
+                    )
+                    .col(uuid_column(manager, WebhookDeliveries::WebhookId).not_null())
+                    .col(uuid_column(manager, WebhookDeliveries::EventId).not_null())
+                    .col(ColumnDef::new(WebhookDeliveries::EventType).string().not_null())
+                    .col(ColumnDef::new(WebhookDeliveries::Attempt).integer().not_null())
+                    .col(ColumnDef::new(WebhookDeliveries::StatusCode).integer())
+                    .col(ColumnDef::new(WebhookDeliveries::Error).text())
+                    .col(ColumnDef::new(WebhookDeliveries::Success).boolean().not_null())
+                    .col(timestamp_column(manager, WebhookDeliveries::Timestamp).not_null())
+                    .to_owned(),
+            )
+            .await?;
+
+        manager
+            .create_index(
+                Index::create()
+                    .name("idx_webhook_deliveries_webhook_id")
+                    .table(WebhookDeliveries::Table)
+                    .col(WebhookDeliveries::WebhookId)
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_table(Table::drop().table(WebhookDeliveries::Table).to_owned())
+            .await?;
+
+        manager
+            .drop_table(Table::drop().table(Webhooks::Table).to_owned())
+            .await
+    }
+}
+
+#[derive(DeriveIden)]
+enum Webhooks {
+    Table,
+    Id,
+    Url,
+    EventTypes,
+    Secret,
+    CreatedAt,
+}
+
+#[derive(DeriveIden)]
+enum WebhookDeliveries {
+    Table,
+    Id,
+    WebhookId,
+    EventId,
+    EventType,
+    Attempt,
+    StatusCode,
+    Error,
+    Success,
+    Timestamp,
+}
@@ -0,0 +1,145 @@
+use sea_orm_migration::prelude::*;
+
+use super::{timestamp_column, uuid_column};
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .create_table(
+                Table::create()
+                    .table(AlertRules::Table)
+                    .if_not_exists()
+                    .col(uuid_column(manager, AlertRules::Id).not_null().primary_key())
+                    .col(uuid_column(manager, AlertRules::CounterId).not_null())
+                    .col(ColumnDef::new(AlertRules::Name).string().not_null())
+                    .col(ColumnDef::new(AlertRules::Condition).string().not_null())
+                    .col(ColumnDef::new(AlertRules::Threshold).integer().not_null())
+                    .col(ColumnDef::new(AlertRules::Channel).string().not_null())
+                    .col(ColumnDef::new(AlertRules::Target).string().not_null())
+                    .col(
+                        ColumnDef::new(AlertRules::DebounceSecs)
+                            .integer()
+                            .default(0)
+                            .not_null(),
+                    )
+                    .col(timestamp_column(manager, AlertRules::CreatedAt).not_null())
+                    .to_owned(),
+            )
+            .await?;
+
+        manager
+            .create_index(
+                Index::create()
+                    .name("idx_alert_rules_counter_id")
+                    .table(AlertRules::Table)
+                    .col(AlertRules::CounterId)
+                    .to_owned(),
+            )
+            .await?;
+
+        manager
+            .create_table(
+                Table::create()
+                    .table(Alerts::Table)
+                    .if_not_exists()
+                    .col(uuid_column(manager, Alerts::Id).not_null().primary_key())
+                    .col(uuid_column(manager, Alerts::RuleId).not_null())
+                    .col(uuid_column(manager, Alerts::CounterId).not_null())
+                    .col(ColumnDef::new(Alerts::Message).text().not_null())
+                    .col(ColumnDef::new(Alerts::Value).integer().not_null())
+                    .col(ColumnDef::new(Alerts::Channel).string().not_null())
+                    .col(ColumnDef::new(Alerts::Error).text())
+                    .col(timestamp_column(manager, Alerts::FiredAt).not_null())
+                    .to_owned(),
+            )
+            .await?;
+
+        manager
+            .create_index(
+                Index::create()
+                    .name("idx_alerts_rule_id")
+                    .table(Alerts::Table)
+                    .col(Alerts::RuleId)
+                    .to_owned(),
+            )
+            .await?;
+
+        manager
+            .create_table(
+                Table::create()
+                    .table(Notifications::Table)
+                    .if_not_exists()
+                    .col(
+                        uuid_column(manager, Notifications::Id)
+                            .not_null()
+                            .primary_key(),
+                    )
+                    .col(uuid_column(manager, Notifications::AlertId).not_null())
+                    .col(ColumnDef::new(Notifications::Message).text().not_null())
+                    .col(
+                        ColumnDef::new(Notifications::Read)
+                            .boolean()
+                            .default(false)
+                            .not_null(),
+                    )
+                    .col(timestamp_column(manager, Notifications::CreatedAt).not_null())
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_table(Table::drop().table(Notifications::Table).to_owned())
+            .await?;
+
+        manager
+            .drop_table(Table::drop().table(Alerts::Table).to_owned())
+            .await?;
+
+        manager
+            .drop_table(Table::drop().table(AlertRules::Table).to_owned())
+            .await
+    }
+}
+
+#[derive(DeriveIden)]
+enum AlertRules {
+    Table,
+    Id,
+    CounterId,
+    Name,
+    Condition,
+    Threshold,
+    Channel,
+    Target,
+    DebounceSecs,
+    CreatedAt,
+}
+
+#[derive(DeriveIden)]
+enum Alerts {
+    Table,
+    Id,
+    RuleId,
+    CounterId,
+    Message,
+    Value,
+    Channel,
+    Error,
+    FiredAt,
+}
+
+#[derive(DeriveIden)]
+enum Notifications {
+    Table,
+    Id,
+    AlertId,
+    Message,
+    Read,
+    CreatedAt,
+}
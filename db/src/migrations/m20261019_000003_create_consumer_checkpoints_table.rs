@@ -0,0 +1,80 @@
+use sea_orm_migration::prelude::*;
+
+use super::{timestamp_column, uuid_column};
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .create_table(
+                Table::create()
+                    .table(ConsumerCheckpoints::Table)
+                    .if_not_exists()
+                    .col(
+                        ColumnDef::new(ConsumerCheckpoints::Consumer)
+                            .string()
+                            .not_null()
+                            .primary_key(),
+                    )
+                    .col(
+                        timestamp_column(manager, ConsumerCheckpoints::LastEventTimestamp)
+                            .not_null(),
+                    )
+                    .col(uuid_column(manager, ConsumerCheckpoints::LastEventId).not_null())
+                    .col(timestamp_column(manager, ConsumerCheckpoints::UpdatedAt).not_null())
+                    .to_owned(),
+            )
+            .await?;
+
+        // Until now the counters projection has been fed inline, so it's up to
+        // date with the existing events: don't replay them on the next start.
+        let checkpoint = Query::insert()
+            .into_table(ConsumerCheckpoints::Table)
+            .columns([
+                ConsumerCheckpoints::Consumer,
+                ConsumerCheckpoints::LastEventTimestamp,
+                ConsumerCheckpoints::LastEventId,
+                ConsumerCheckpoints::UpdatedAt,
+            ])
+            .select_from(
+                Query::select()
+                    .expr(Expr::val("counters"))
+                    .columns([Events::Timestamp, Events::Id])
+                    .expr(Expr::col(Events::Timestamp))
+                    .from(Events::Table)
+                    .order_by(Events::Timestamp, Order::Desc)
+                    .order_by(Events::Id, Order::Desc)
+                    .limit(1)
+                    .to_owned(),
+            )
+            .map_err(|err| DbErr::Migration(err.to_string()))?
+            .to_owned();
+
+        manager.exec_stmt(checkpoint).await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_table(Table::drop().table(ConsumerCheckpoints::Table).to_owned())
+            .await
+    }
+}
+
+#[derive(DeriveIden)]
+enum ConsumerCheckpoints {
+    Table,
+    Consumer,
+    LastEventTimestamp,
+    LastEventId,
+    UpdatedAt,
+}
+
+#[derive(DeriveIden)]
+enum Events {
+    Table,
+    Id,
+    Timestamp,
+}
@@ -0,0 +1,237 @@
+use sea_orm_migration::prelude::*;
+
+use super::{json_column, timestamp_column, uuid_column};
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        // Not every backend can add an auto increment column to an existing
+        // table, so the events are copied to a new one, in their current order.
+        recreate_events_table(manager, true).await?;
+
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(ConsumerCheckpoints::Table)
+                    .add_column(
+                        ColumnDef::new(ConsumerCheckpoints::LastEventSequence)
+                            .big_integer()
+                            .not_null()
+                            .default(0),
+                    )
+                    .to_owned(),
+            )
+            .await?;
+
+        let sequence = Query::select()
+            .column((Events::Table, Events::Sequence))
+            .from(Events::Table)
+            .and_where(
+                Expr::col((Events::Table, Events::Id))
+                    .equals((ConsumerCheckpoints::Table, ConsumerCheckpoints::LastEventId)),
+            )
+            .to_owned();
+
+        manager
+            .exec_stmt(
+                Query::update()
+                    .table(ConsumerCheckpoints::Table)
+                    .value(
+                        ConsumerCheckpoints::LastEventSequence,
+                        SimpleExpr::SubQuery(None, Box::new(sequence.into_sub_query_statement())),
+                    )
+                    .to_owned(),
+            )
+            .await?;
+
+        // SQLite can only drop one column at a time
+        for column in [
+            ConsumerCheckpoints::LastEventTimestamp,
+            ConsumerCheckpoints::LastEventId,
+        ] {
+            manager
+                .alter_table(
+                    Table::alter()
+                        .table(ConsumerCheckpoints::Table)
+                        .drop_column(column)
+                        .to_owned(),
+                )
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(ConsumerCheckpoints::Table)
+                    .add_column(&mut timestamp_column(
+                        manager,
+                        ConsumerCheckpoints::LastEventTimestamp,
+                    ))
+                    .to_owned(),
+            )
+            .await?;
+
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(ConsumerCheckpoints::Table)
+                    .add_column(&mut uuid_column(manager, ConsumerCheckpoints::LastEventId))
+                    .to_owned(),
+            )
+            .await?;
+
+        for (column, target) in [
+            (Events::Timestamp, ConsumerCheckpoints::LastEventTimestamp),
+            (Events::Id, ConsumerCheckpoints::LastEventId),
+        ] {
+            let value = Query::select()
+                .column((Events::Table, column))
+                .from(Events::Table)
+                .and_where(
+                    Expr::col((Events::Table, Events::Sequence)).equals((
+                        ConsumerCheckpoints::Table,
+                        ConsumerCheckpoints::LastEventSequence,
+                    )),
+                )
+                .to_owned();
+
+            manager
+                .exec_stmt(
+                    Query::update()
+                        .table(ConsumerCheckpoints::Table)
+                        .value(
+                            target,
+                            SimpleExpr::SubQuery(None, Box::new(value.into_sub_query_statement())),
+                        )
+                        .to_owned(),
+                )
+                .await?;
+        }
+
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(ConsumerCheckpoints::Table)
+                    .drop_column(ConsumerCheckpoints::LastEventSequence)
+                    .to_owned(),
+            )
+            .await?;
+
+        recreate_events_table(manager, false).await
+    }
+}
+
+/// Moves the events to a new table, with or without the `sequence` column.
+async fn recreate_events_table(manager: &SchemaManager<'_>, with_sequence: bool) -> Result<(), DbErr> {
+    let mut table = Table::create();
+    table.table(EventsCopy::Table);
+
+    if with_sequence {
+        table.col(
+            ColumnDef::new(Events::Sequence)
+                .big_integer()
+                .not_null()
+                .auto_increment()
+                .primary_key(),
+        );
+        table.col(uuid_column(manager, Events::Id).not_null().unique_key());
+    } else {
+        table.col(uuid_column(manager, Events::Id).not_null().primary_key());
+    }
+
+    table
+        .col(ColumnDef::new(Events::Name).string().not_null())
+        .col(json_column(manager, Events::Payload).not_null())
+        .col(uuid_column(manager, Events::AggregateId).not_null())
+        .col(timestamp_column(manager, Events::Timestamp).not_null());
+
+    manager.create_table(table.to_owned()).await?;
+
+    let columns = [
+        Events::Id,
+        Events::Name,
+        Events::Payload,
+        Events::AggregateId,
+        Events::Timestamp,
+    ];
+
+    let mut existing = Query::select();
+    existing.columns(columns.clone()).from(Events::Table);
+    if with_sequence {
+        existing
+            .order_by(Events::Timestamp, Order::Asc)
+            .order_by(Events::Id, Order::Asc);
+    }
+
+    let copy = Query::insert()
+        .into_table(EventsCopy::Table)
+        .columns(columns)
+        .select_from(existing.to_owned())
+        .map_err(|err| DbErr::Migration(err.to_string()))?
+        .to_owned();
+
+    manager.exec_stmt(copy).await?;
+
+    manager
+        .drop_table(Table::drop().table(Events::Table).to_owned())
+        .await?;
+
+    manager
+        .rename_table(
+            Table::rename()
+                .table(EventsCopy::Table, Events::Table)
+                .to_owned(),
+        )
+        .await?;
+
+    manager
+        .create_index(
+            Index::create()
+                .name("idx_events_aggregate_id")
+                .table(Events::Table)
+                .col(Events::AggregateId)
+                .to_owned(),
+        )
+        .await?;
+
+    manager
+        .create_index(
+            Index::create()
+                .name("idx_events_name")
+                .table(Events::Table)
+                .col(Events::Name)
+                .to_owned(),
+        )
+        .await
+}
+
+#[derive(DeriveIden, Clone)]
+enum Events {
+    Table,
+    Sequence,
+    Id,
+    Name,
+    Payload,
+    AggregateId,
+    Timestamp,
+}
+
+#[derive(DeriveIden)]
+enum EventsCopy {
+    Table,
+}
+
+#[derive(DeriveIden)]
+enum ConsumerCheckpoints {
+    Table,
+    LastEventSequence,
+    LastEventTimestamp,
+    LastEventId,
+}
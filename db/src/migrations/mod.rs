@@ -1,7 +1,14 @@
 pub use sea_orm_migration::prelude::*;
+use sea_orm_migration::sea_orm::DbBackend;
 
 mod m20230831_000001_create_events_table;
 mod m20230901_155059_create_counters_table;
+mod m20261019_000001_create_webhooks_tables;
+mod m20261019_000002_create_alerts_tables;
+mod m20261019_000003_create_consumer_checkpoints_table;
+mod m20261019_000004_create_idempotency_keys_table;
+mod m20261019_000005_add_events_sequence;
+mod m20261019_000006_add_events_schema_version;
 
 pub struct Migrator;
 
@@ -11,6 +18,47 @@ impl MigratorTrait for Migrator {
         vec![
             Box::new(m20230831_000001_create_events_table::Migration),
             Box::new(m20230901_155059_create_counters_table::Migration),
+            Box::new(m20261019_000001_create_webhooks_tables::Migration),
+            Box::new(m20261019_000002_create_alerts_tables::Migration),
+            Box::new(m20261019_000003_create_consumer_checkpoints_table::Migration),
+            Box::new(m20261019_000004_create_idempotency_keys_table::Migration),
+            Box::new(m20261019_000005_add_events_sequence::Migration),
+            Box::new(m20261019_000006_add_events_schema_version::Migration),
         ]
     }
 }
+
+/// A uuid column, stored as `char(36)` on MySQL where `uuid` would be `binary(16)`
+/// and couldn't hold the hyphenated strings the entities use.
+pub(crate) fn uuid_column<T: IntoIden>(manager: &SchemaManager, name: T) -> ColumnDef {
+    let mut column = ColumnDef::new(name);
+    match manager.get_database_backend() {
+        DbBackend::MySql => column.char_len(36),
+        _ => column.uuid(),
+    };
+
+    column
+}
+
+/// A UTC timestamp column. MySQL has no time zone aware type and its `timestamp`
+/// only has seconds precision, so a `datetime(6)` is used there instead.
+pub(crate) fn timestamp_column<T: IntoIden>(manager: &SchemaManager, name: T) -> ColumnDef {
+    let mut column = ColumnDef::new(name);
+    match manager.get_database_backend() {
+        DbBackend::MySql => column.custom(Alias::new("datetime(6)")),
+        _ => column.timestamp_with_time_zone(),
+    };
+
+    column
+}
+
+/// A JSON column, stored as `jsonb` on PostgreSQL (MariaDB aliases `json` to `longtext`).
+pub(crate) fn json_column<T: IntoIden>(manager: &SchemaManager, name: T) -> ColumnDef {
+    let mut column = ColumnDef::new(name);
+    match manager.get_database_backend() {
+        DbBackend::Postgres => column.json_binary(),
+        _ => column.json(),
+    };
+
+    column
+}
@@ -1,12 +1,32 @@
 use mini_cqrs::Repository as CqrsRepository;
 use sea_orm::*;
 
+use chrono::{DateTime, Utc};
+
 use crate::{
-    entity::counters,
+    entity::{
+        alert_rules, alerts, consumer_checkpoints, counters, idempotency_keys, notifications,
+        webhook_deliveries, webhooks,
+    },
     error::DbError,
     migrations::{Migrator, MigratorTrait},
 };
 
+/// A change of the counters read model, stored along with the checkpoint of its
+/// projection (see `Repository::project_counter`). Changes to missing counters are
+/// skipped, eg. increments of a deleted counter.
+#[derive(Clone, Debug, PartialEq)]
+pub enum CounterUpdate {
+    /// Inserts the counter, or replaces it.
+    Create { id: String, name: String, value: i32 },
+    Add { id: String, amount: i32 },
+    Update { id: String, name: String, value: i32 },
+    Delete { id: String },
+}
+
+// How many counters are inserted at once, below the bind parameters limit of SQLite.
+const COUNTERS_CHUNK_SIZE: usize = 250;
+
 #[derive(Clone)]
 pub struct Repository {
     pub db: DbConn,
@@ -22,6 +42,35 @@ impl Repository {
         Ok(())
     }
 
+    /// Checks that the database can be reached.
+    pub async fn ping(&self) -> Result<(), DbError> {
+        self.db.ping().await?;
+        Ok(())
+    }
+
+    pub async fn count_pending_migrations(&self) -> Result<usize, DbError> {
+        let pending = Migrator::get_pending_migrations(&self.db).await?;
+        Ok(pending.len())
+    }
+
+    /// How many connections the pool has open, and how many of them are idle.
+    pub fn pool_status(&self) -> (u32, usize) {
+        #[cfg(feature = "sqlite")]
+        let pool = self.db.get_sqlite_connection_pool();
+        #[cfg(feature = "postgres")]
+        let pool = self.db.get_postgres_connection_pool();
+        #[cfg(feature = "mysql")]
+        let pool = self.db.get_mysql_connection_pool();
+
+        (pool.size(), pool.num_idle())
+    }
+
+    /// Closes the connection pool, shared with every clone of this repository.
+    pub async fn close(self) -> Result<(), DbError> {
+        self.db.close().await?;
+        Ok(())
+    }
+
     pub async fn insert_counter(&self, model: counters::Model) -> Result<counters::Model, DbError> {
         let counter = counters::ActiveModel {
             id: Set(model.id.to_owned()),
@@ -41,7 +90,24 @@ impl Repository {
         Ok(counters)
     }
 
+    /// Up to `limit` counters ordered by id, following the `after` one (`None` to start over).
+    pub async fn list_counters_page(
+        &self,
+        after: Option<String>,
+        limit: u64,
+    ) -> Result<Vec<counters::Model>, DbError> {
+        let mut query = counters::Entity::find()
+            .order_by_asc(counters::Column::Id)
+            .limit(limit);
+        if let Some(after) = after {
+            query = query.filter(counters::Column::Id.gt(after));
+        }
+
+        Ok(query.all(&self.db).await?)
+    }
+
     pub async fn find_counter_by_id(&self, id: String) -> Result<counters::Model, DbError> {
+        ensure_uuid(&id)?;
         if let Some(counter) = counters::Entity::find_by_id(id).one(&self.db).await? {
             return Ok(counter);
         }
@@ -111,6 +177,7 @@ impl Repository {
     }
 
     pub async fn delete_counter(&self, id: String) -> Result<(), DbError> {
+        ensure_uuid(&id)?;
         let txn = self.db.begin().await?;
 
         if let Some(counter) = counters::Entity::find_by_id(id).one(&txn).await? {
@@ -124,6 +191,418 @@ impl Repository {
         txn.rollback().await?;
         Err(DbError::NotFound)
     }
+
+    pub async fn delete_all_counters(&self) -> Result<(), DbError> {
+        counters::Entity::delete_many().exec(&self.db).await?;
+
+        Ok(())
+    }
+
+    /// Replaces every counter, and sets the checkpoint of `consumer` to `sequence`,
+    /// in a single transaction (eg. with the counters rebuilt from the event log).
+    pub async fn replace_counters(
+        &self,
+        consumer: &str,
+        sequence: i64,
+        models: Vec<counters::Model>,
+    ) -> Result<(), DbError> {
+        let txn = self.db.begin().await?;
+
+        counters::Entity::delete_many().exec(&txn).await?;
+        for chunk in models.chunks(COUNTERS_CHUNK_SIZE) {
+            let chunk = chunk.iter().cloned().map(counters::ActiveModel::from);
+            counters::Entity::insert_many(chunk).exec(&txn).await?;
+        }
+        upsert_checkpoint(&txn, consumer, sequence).await?;
+
+        txn.commit().await?;
+        Ok(())
+    }
+
+    pub async fn insert_webhook(&self, model: webhooks::Model) -> Result<webhooks::Model, DbError> {
+        let webhook: webhooks::ActiveModel = model.into();
+        let webhook = webhook.insert(&self.db).await?;
+
+        Ok(webhook)
+    }
+
+    pub async fn list_webhooks(&self) -> Result<Vec<webhooks::Model>, DbError> {
+        let webhooks = webhooks::Entity::find()
+            .order_by_asc(webhooks::Column::CreatedAt)
+            .all(&self.db)
+            .await?;
+
+        Ok(webhooks)
+    }
+
+    pub async fn find_webhook_by_id(&self, id: String) -> Result<webhooks::Model, DbError> {
+        ensure_uuid(&id)?;
+        if let Some(webhook) = webhooks::Entity::find_by_id(id).one(&self.db).await? {
+            return Ok(webhook);
+        }
+
+        Err(DbError::NotFound)
+    }
+
+    pub async fn delete_webhook(&self, id: String) -> Result<(), DbError> {
+        ensure_uuid(&id)?;
+        let result = webhooks::Entity::delete_by_id(id).exec(&self.db).await?;
+        if result.rows_affected == 0 {
+            return Err(DbError::NotFound);
+        }
+
+        Ok(())
+    }
+
+    pub async fn insert_webhook_delivery(
+        &self,
+        model: webhook_deliveries::Model,
+    ) -> Result<webhook_deliveries::Model, DbError> {
+        let delivery: webhook_deliveries::ActiveModel = model.into();
+        let delivery = delivery.insert(&self.db).await?;
+
+        Ok(delivery)
+    }
+
+    pub async fn list_webhook_deliveries(
+        &self,
+        webhook_id: String,
+    ) -> Result<Vec<webhook_deliveries::Model>, DbError> {
+        ensure_uuid(&webhook_id)?;
+        let deliveries = webhook_deliveries::Entity::find()
+            .filter(webhook_deliveries::Column::WebhookId.eq(webhook_id))
+            .order_by_desc(webhook_deliveries::Column::Timestamp)
+            .all(&self.db)
+            .await?;
+
+        Ok(deliveries)
+    }
+
+    pub async fn insert_alert_rule(
+        &self,
+        model: alert_rules::Model,
+    ) -> Result<alert_rules::Model, DbError> {
+        let rule: alert_rules::ActiveModel = model.into();
+        let rule = rule.insert(&self.db).await?;
+
+        Ok(rule)
+    }
+
+    pub async fn list_alert_rules(&self) -> Result<Vec<alert_rules::Model>, DbError> {
+        let rules = alert_rules::Entity::find()
+            .order_by_asc(alert_rules::Column::CreatedAt)
+            .all(&self.db)
+            .await?;
+
+        Ok(rules)
+    }
+
+    pub async fn list_alert_rules_by_counter(
+        &self,
+        counter_id: String,
+    ) -> Result<Vec<alert_rules::Model>, DbError> {
+        let rules = alert_rules::Entity::find()
+            .filter(alert_rules::Column::CounterId.eq(counter_id))
+            .all(&self.db)
+            .await?;
+
+        Ok(rules)
+    }
+
+    pub async fn delete_alert_rule(&self, id: String) -> Result<(), DbError> {
+        ensure_uuid(&id)?;
+        let result = alert_rules::Entity::delete_by_id(id).exec(&self.db).await?;
+        if result.rows_affected == 0 {
+            return Err(DbError::NotFound);
+        }
+
+        Ok(())
+    }
+
+    pub async fn insert_alert(&self, model: alerts::Model) -> Result<alerts::Model, DbError> {
+        let alert: alerts::ActiveModel = model.into();
+        let alert = alert.insert(&self.db).await?;
+
+        Ok(alert)
+    }
+
+    /// Records why an alert couldn't be sent, once its sink gave up.
+    pub async fn update_alert_error(&self, id: String, error: String) -> Result<(), DbError> {
+        let result = alerts::Entity::update_many()
+            .col_expr(alerts::Column::Error, sea_query::Expr::value(error))
+            .filter(alerts::Column::Id.eq(id))
+            .exec(&self.db)
+            .await?;
+        if result.rows_affected == 0 {
+            return Err(DbError::NotFound);
+        }
+
+        Ok(())
+    }
+
+    pub async fn find_last_alert_by_rule(
+        &self,
+        rule_id: String,
+    ) -> Result<Option<alerts::Model>, DbError> {
+        let alert = alerts::Entity::find()
+            .filter(alerts::Column::RuleId.eq(rule_id))
+            .order_by_desc(alerts::Column::FiredAt)
+            .one(&self.db)
+            .await?;
+
+        Ok(alert)
+    }
+
+    pub async fn list_alerts(&self) -> Result<Vec<alerts::Model>, DbError> {
+        let alerts = alerts::Entity::find()
+            .order_by_desc(alerts::Column::FiredAt)
+            .all(&self.db)
+            .await?;
+
+        Ok(alerts)
+    }
+
+    pub async fn insert_notification(
+        &self,
+        model: notifications::Model,
+    ) -> Result<notifications::Model, DbError> {
+        let notification: notifications::ActiveModel = model.into();
+        let notification = notification.insert(&self.db).await?;
+
+        Ok(notification)
+    }
+
+    pub async fn list_notifications(
+        &self,
+        unread_only: bool,
+    ) -> Result<Vec<notifications::Model>, DbError> {
+        let mut query = notifications::Entity::find();
+        if unread_only {
+            query = query.filter(notifications::Column::Read.eq(false));
+        }
+        let notifications = query
+            .order_by_desc(notifications::Column::CreatedAt)
+            .all(&self.db)
+            .await?;
+
+        Ok(notifications)
+    }
+
+    pub async fn mark_notification_read(&self, id: String) -> Result<(), DbError> {
+        ensure_uuid(&id)?;
+        let result = notifications::Entity::update_many()
+            .col_expr(notifications::Column::Read, sea_query::Expr::value(true))
+            .filter(notifications::Column::Id.eq(id))
+            .exec(&self.db)
+            .await?;
+        if result.rows_affected == 0 {
+            return Err(DbError::NotFound);
+        }
+
+        Ok(())
+    }
+
+    /// The sequence of the last event processed by `consumer`, 0 when it hasn't started yet.
+    pub async fn find_checkpoint(&self, consumer: &str) -> Result<i64, DbError> {
+        let checkpoint = consumer_checkpoints::Entity::find_by_id(consumer)
+            .one(&self.db)
+            .await?;
+
+        Ok(checkpoint.map_or(0, |c| c.last_event_sequence))
+    }
+
+    pub async fn save_checkpoint(&self, consumer: &str, sequence: i64) -> Result<(), DbError> {
+        upsert_checkpoint(&self.db, consumer, sequence).await
+    }
+
+    /// Applies the change of the event `sequence` and moves the checkpoint of `consumer`
+    /// from `after` to it, in a single transaction. Fails with `DbError::Conflict` when
+    /// the checkpoint isn't at `after` anymore, eg. another process projected the event.
+    pub async fn project_counter(
+        &self,
+        consumer: &str,
+        after: i64,
+        sequence: i64,
+        update: CounterUpdate,
+    ) -> Result<(), DbError> {
+        let txn = self.db.begin().await?;
+
+        // First, so that concurrent projections wait for each other on the checkpoint
+        move_checkpoint(&txn, consumer, after, sequence).await?;
+
+        match update {
+            CounterUpdate::Create { id, name, value } => {
+                let counter = counters::ActiveModel {
+                    id: Set(id),
+                    name: Set(name),
+                    value: Set(value),
+                };
+                counters::Entity::insert(counter)
+                    .on_conflict(
+                        sea_query::OnConflict::column(counters::Column::Id)
+                            .update_columns([counters::Column::Name, counters::Column::Value])
+                            .to_owned(),
+                    )
+                    .exec(&txn)
+                    .await?;
+            }
+            CounterUpdate::Add { id, amount } => {
+                counters::Entity::update_many()
+                    .col_expr(
+                        counters::Column::Value,
+                        sea_query::Expr::col(counters::Column::Value).add(amount),
+                    )
+                    .filter(counters::Column::Id.eq(id))
+                    .exec(&txn)
+                    .await?;
+            }
+            CounterUpdate::Update { id, name, value } => {
+                counters::Entity::update_many()
+                    .col_expr(counters::Column::Name, sea_query::Expr::value(name))
+                    .col_expr(counters::Column::Value, sea_query::Expr::value(value))
+                    .filter(counters::Column::Id.eq(id))
+                    .exec(&txn)
+                    .await?;
+            }
+            CounterUpdate::Delete { id } => {
+                counters::Entity::delete_many()
+                    .filter(counters::Column::Id.eq(id))
+                    .exec(&txn)
+                    .await?;
+            }
+        }
+
+        txn.commit().await?;
+        Ok(())
+    }
+
+    /// Reserves an idempotency key, returns `false` when it already exists.
+    pub async fn insert_idempotency_key(&self, key: &str, request: &str) -> Result<bool, DbError> {
+        let model = idempotency_keys::ActiveModel {
+            key: Set(key.to_string()),
+            request: Set(request.to_string()),
+            response: Set(None),
+            created_at: Set(Utc::now()),
+        };
+
+        // A plain insert, MySQL has no `ON CONFLICT DO NOTHING` to tell apart a skipped row
+        match idempotency_keys::Entity::insert(model).exec(&self.db).await {
+            Ok(_) => Ok(true),
+            Err(err) if matches!(err.sql_err(), Some(SqlErr::UniqueConstraintViolation(_))) => {
+                Ok(false)
+            }
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    pub async fn find_idempotency_key(&self, key: &str) -> Result<idempotency_keys::Model, DbError> {
+        if let Some(model) = idempotency_keys::Entity::find_by_id(key).one(&self.db).await? {
+            return Ok(model);
+        }
+
+        Err(DbError::NotFound)
+    }
+
+    pub async fn complete_idempotency_key(&self, key: &str, response: JsonValue) -> Result<(), DbError> {
+        idempotency_keys::Entity::update_many()
+            .col_expr(idempotency_keys::Column::Response, sea_query::Expr::value(response))
+            .filter(idempotency_keys::Column::Key.eq(key))
+            .exec(&self.db)
+            .await?;
+
+        Ok(())
+    }
+
+    pub async fn delete_idempotency_key(&self, key: &str) -> Result<(), DbError> {
+        idempotency_keys::Entity::delete_by_id(key).exec(&self.db).await?;
+
+        Ok(())
+    }
+
+    pub async fn delete_idempotency_keys_before(&self, before: DateTime<Utc>) -> Result<(), DbError> {
+        idempotency_keys::Entity::delete_many()
+            .filter(idempotency_keys::Column::CreatedAt.lt(before))
+            .exec(&self.db)
+            .await?;
+
+        Ok(())
+    }
 }
 
 impl CqrsRepository for Repository {}
+
+async fn upsert_checkpoint(
+    db: &impl ConnectionTrait,
+    consumer: &str,
+    sequence: i64,
+) -> Result<(), DbError> {
+    let checkpoint = consumer_checkpoints::ActiveModel {
+        consumer: Set(consumer.to_string()),
+        last_event_sequence: Set(sequence),
+        updated_at: Set(Utc::now()),
+    };
+
+    consumer_checkpoints::Entity::insert(checkpoint)
+        .on_conflict(
+            sea_query::OnConflict::column(consumer_checkpoints::Column::Consumer)
+                .update_columns([
+                    consumer_checkpoints::Column::LastEventSequence,
+                    consumer_checkpoints::Column::UpdatedAt,
+                ])
+                .to_owned(),
+        )
+        .exec(db)
+        .await?;
+
+    Ok(())
+}
+
+/// Compare and set: moves the checkpoint of `consumer` to `sequence` only when it's at `after`.
+async fn move_checkpoint(
+    txn: &DatabaseTransaction,
+    consumer: &str,
+    after: i64,
+    sequence: i64,
+) -> Result<(), DbError> {
+    let moved = consumer_checkpoints::Entity::update_many()
+        .col_expr(
+            consumer_checkpoints::Column::LastEventSequence,
+            sea_query::Expr::value(sequence),
+        )
+        .col_expr(consumer_checkpoints::Column::UpdatedAt, sea_query::Expr::value(Utc::now()))
+        .filter(consumer_checkpoints::Column::Consumer.eq(consumer))
+        .filter(consumer_checkpoints::Column::LastEventSequence.eq(after))
+        .exec(txn)
+        .await?;
+    if moved.rows_affected > 0 {
+        return Ok(());
+    }
+
+    // The first event of the projection, unless another process got there first
+    if after == 0 {
+        let checkpoint = consumer_checkpoints::ActiveModel {
+            consumer: Set(consumer.to_string()),
+            last_event_sequence: Set(sequence),
+            updated_at: Set(Utc::now()),
+        };
+        match consumer_checkpoints::Entity::insert(checkpoint).exec(txn).await {
+            Ok(_) => return Ok(()),
+            Err(err) if matches!(err.sql_err(), Some(SqlErr::UniqueConstraintViolation(_))) => {}
+            Err(err) => return Err(err.into()),
+        }
+    }
+
+    Err(DbError::Conflict(format!(
+        "the checkpoint of {} is not at {} anymore",
+        consumer, after
+    )))
+}
+
+/// Ids are UUIDs, anything else can't be found. Native `uuid` columns (eg. on
+/// PostgreSQL) would fail the query instead.
+fn ensure_uuid(id: &str) -> Result<(), DbError> {
+    match uuid::Uuid::parse_str(id) {
+        Ok(_) => Ok(()),
+        Err(_) => Err(DbError::NotFound),
+    }
+}
@@ -0,0 +1,134 @@
+use std::collections::HashMap;
+
+use sea_orm::JsonValue;
+
+/// Turns a stored payload into the shape of the next schema version.
+pub type Upcast = fn(JsonValue) -> JsonValue;
+
+/// Chains of upcasters by event type, applied when loading events so that old
+/// payloads always deserialize into the current shape.
+///
+/// Events are stored with the current schema version of their type, that is
+/// the number of upcasters registered for it, plus one.
+#[derive(Clone, Default)]
+pub struct Upcasters {
+    chains: HashMap<String, Vec<Upcast>>,
+}
+
+impl Upcasters {
+    /// Adds the next step for `event_type`: the first one turns version 1
+    /// payloads into version 2, the second one version 2 into 3, and so on.
+    pub fn with(mut self, event_type: &str, upcast: Upcast) -> Self {
+        self.chains
+            .entry(event_type.to_string())
+            .or_default()
+            .push(upcast);
+        self
+    }
+
+    /// The schema version new events of `event_type` are stored with.
+    pub fn current_version(&self, event_type: &str) -> i32 {
+        self.chains.get(event_type).map_or(0, Vec::len) as i32 + 1
+    }
+
+    /// Brings a payload stored with `version` to the current version.
+    pub fn upcast(
+        &self,
+        event_type: &str,
+        version: i32,
+        payload: JsonValue,
+    ) -> Result<JsonValue, String> {
+        let current = self.current_version(event_type);
+        if version < 1 || version > current {
+            return Err(format!(
+                "unknown schema version {} of {}, the current one is {}",
+                version, event_type, current
+            ));
+        }
+
+        let steps = self
+            .chains
+            .get(event_type)
+            .map_or(&[][..], |chain| &chain[(version - 1) as usize..]);
+
+        Ok(steps.iter().fold(payload, |payload, upcast| upcast(payload)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn add_step(mut payload: JsonValue) -> JsonValue {
+        payload["Incremented"]["step"] = json!(1);
+        payload
+    }
+
+    fn rename_by(mut payload: JsonValue) -> JsonValue {
+        let actor = payload["Incremented"]["by"].take();
+        payload["Incremented"]["actor"] = actor;
+        payload["Incremented"].as_object_mut().unwrap().remove("by");
+        payload
+    }
+
+    fn upcasters() -> Upcasters {
+        Upcasters::default()
+            .with("Incremented", add_step)
+            .with("Incremented", rename_by)
+    }
+
+    #[test]
+    fn current_version_counts_the_steps() {
+        let upcasters = upcasters();
+
+        assert_eq!(upcasters.current_version("Incremented"), 3);
+        assert_eq!(upcasters.current_version("Deleted"), 1);
+    }
+
+    #[test]
+    fn upcasts_through_the_whole_chain() {
+        let v1 = json!({"Incremented": {"id": "c1", "by": "ann"}});
+
+        let current = upcasters().upcast("Incremented", 1, v1).unwrap();
+
+        assert_eq!(
+            current,
+            json!({"Incremented": {"id": "c1", "step": 1, "actor": "ann"}})
+        );
+    }
+
+    #[test]
+    fn upcasts_from_intermediate_versions() {
+        let v2 = json!({"Incremented": {"id": "c1", "step": 5, "by": "bob"}});
+
+        let current = upcasters().upcast("Incremented", 2, v2).unwrap();
+
+        assert_eq!(
+            current,
+            json!({"Incremented": {"id": "c1", "step": 5, "actor": "bob"}})
+        );
+    }
+
+    #[test]
+    fn leaves_current_payloads_alone() {
+        let v3 = json!({"Incremented": {"id": "c1", "step": 1, "actor": "ann"}});
+        let deleted = json!({"Deleted": {"id": "c1"}});
+
+        let upcasters = upcasters();
+
+        assert_eq!(
+            upcasters.upcast("Incremented", 3, v3.clone()).unwrap(),
+            v3
+        );
+        assert_eq!(upcasters.upcast("Deleted", 1, deleted.clone()).unwrap(), deleted);
+    }
+
+    #[test]
+    fn rejects_unknown_versions() {
+        let upcasters = upcasters();
+
+        assert!(upcasters.upcast("Incremented", 4, json!({})).is_err());
+        assert!(upcasters.upcast("Incremented", 0, json!({})).is_err());
+    }
+}
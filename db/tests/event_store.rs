@@ -0,0 +1,147 @@
+mod common;
+
+use chrono::{Duration, Utc};
+use kountr_db::event_store::{CqrsEventStore, EventFilter, EventStore};
+use serde_json::json;
+
+use common::{event, micros, TestDatabase};
+
+fn new_id() -> String {
+    uuid::Uuid::new_v4().to_string()
+}
+
+#[tokio::test]
+async fn appends_and_loads_events_in_order() {
+    let database = TestDatabase::migrated().await;
+    let mut store = EventStore::new(database.db.clone());
+    let (first, second) = (new_id(), new_id());
+
+    let created = event(&first, "CounterCreated", json!({"value": 0}));
+    store.save_events(first.clone(), std::slice::from_ref(&created)).await.unwrap();
+    store
+        .save_events(second.clone(), &[event(&second, "CounterCreated", json!({"value": 5}))])
+        .await
+        .unwrap();
+    let increments = [
+        event(&first, "CounterIncremented", json!({"amount": 1})),
+        event(&first, "CounterIncremented", json!({"amount": 2})),
+    ];
+    store.save_events(first.clone(), &increments).await.unwrap();
+
+    let loaded = store.load_events(first.clone()).await.unwrap();
+    let payloads: Vec<_> = loaded.iter().map(|evt| evt.payload.clone()).collect();
+    assert_eq!(
+        payloads,
+        vec![json!({"value": 0}), json!({"amount": 1}), json!({"amount": 2})]
+    );
+    assert_eq!(loaded[0].id, created.id);
+    assert_eq!(loaded[0].aggregate_id, first);
+    assert_eq!(micros(loaded[0].timestamp), micros(created.timestamp));
+
+    let all = store.load_events_after(0, 10).await.unwrap();
+    let sequences: Vec<i64> = all.iter().map(|stored| stored.sequence).collect();
+    assert_eq!(sequences, vec![1, 2, 3, 4]);
+    assert_eq!(all[1].event.aggregate_id, second);
+    assert_eq!(store.last_sequence().await.unwrap(), 4);
+    assert_eq!(store.count_events_after(1).await.unwrap(), 3);
+    assert_eq!(store.count_events_after(4).await.unwrap(), 0);
+
+    let page = store.load_events_after(2, 1).await.unwrap();
+    assert_eq!(page.len(), 1);
+    assert_eq!(page[0].sequence, 3);
+
+    database.close().await;
+}
+
+#[tokio::test]
+async fn filters_the_event_log() {
+    let database = TestDatabase::migrated().await;
+    let mut store = EventStore::new(database.db.clone());
+    let (first, second) = (new_id(), new_id());
+    let now = micros(Utc::now());
+
+    let mut events = vec![
+        event(&first, "CounterCreated", json!({})),
+        event(&second, "CounterCreated", json!({})),
+        event(&first, "CounterIncremented", json!({})),
+        event(&first, "CounterDeleted", json!({})),
+    ];
+    for (minutes, evt) in (0..).zip(events.iter_mut()) {
+        evt.timestamp = now - Duration::minutes(30 - minutes * 10);
+    }
+    for evt in &events {
+        store.save_events(evt.aggregate_id.clone(), std::slice::from_ref(evt)).await.unwrap();
+    }
+
+    let sequences = |filter: EventFilter| {
+        let store = store.clone();
+        async move {
+            let page = store.stream_events(&filter).await.unwrap();
+            page.iter().map(|stored| stored.sequence).collect::<Vec<_>>()
+        }
+    };
+
+    let all = EventFilter {
+        limit: 10,
+        ..Default::default()
+    };
+    assert_eq!(sequences(all.clone()).await, vec![1, 2, 3, 4]);
+
+    let created = EventFilter {
+        event_types: vec!["CounterCreated".to_string()],
+        ..all.clone()
+    };
+    assert_eq!(sequences(created).await, vec![1, 2]);
+
+    let of_first = EventFilter {
+        aggregate_id: Some(first.clone()),
+        after: 1,
+        ..all.clone()
+    };
+    assert_eq!(sequences(of_first).await, vec![3, 4]);
+
+    let window = EventFilter {
+        from: Some(now - Duration::minutes(20)),
+        to: Some(now),
+        ..all
+    };
+    assert_eq!(sequences(window).await, vec![2, 3]);
+
+    database.close().await;
+}
+
+#[tokio::test]
+async fn imports_all_events_or_none() {
+    let database = TestDatabase::migrated().await;
+    let store = EventStore::new(database.db.clone());
+    let id = new_id();
+
+    let events = vec![
+        event(&id, "CounterCreated", json!({})),
+        event(&id, "CounterIncremented", json!({})),
+    ];
+    store.import_events(&events).await.unwrap();
+
+    let ids: Vec<String> = vec![events[0].id.clone(), new_id()];
+    let existing = store.find_existing_ids(&ids).await.unwrap();
+    assert_eq!(existing.into_iter().collect::<Vec<_>>(), vec![events[0].id.clone()]);
+
+    // The second event is already there, so the first isn't stored either
+    let again = vec![event(&id, "CounterIncremented", json!({})), events[1].clone()];
+    assert!(store.import_events(&again).await.is_err());
+    assert_eq!(store.last_sequence().await.unwrap(), 2);
+    assert!(store.find_existing_ids(&[again[0].id.clone()]).await.unwrap().is_empty());
+
+    database.close().await;
+}
+
+#[tokio::test]
+async fn fails_to_load_events_without_the_table() {
+    let database = TestDatabase::empty().await;
+    let store = EventStore::new(database.db.clone());
+
+    // Not mistaken for a counter without events
+    assert!(store.load_events(new_id()).await.is_err());
+
+    database.close().await;
+}
@@ -0,0 +1,93 @@
+mod common;
+
+use chrono::{Duration, Utc};
+use kountr_db::{
+    event_store::EventStore,
+    migrations::{Migrator, MigratorTrait},
+    repository::Repository,
+    DbConn,
+};
+use sea_orm::{ConnectionTrait, DbBackend};
+use sea_query::{Alias, Expr, Query, SimpleExpr};
+use serde_json::json;
+
+use common::TestDatabase;
+
+#[tokio::test]
+async fn migrates_up_and_down() {
+    let database = TestDatabase::empty().await;
+    let count = Migrator::migrations().len();
+
+    assert_eq!(Migrator::get_pending_migrations(&database.db).await.unwrap().len(), count);
+    Migrator::up(&database.db, None).await.unwrap();
+    assert!(Migrator::get_pending_migrations(&database.db).await.unwrap().is_empty());
+
+    // Every migration can be reverted, then applied again
+    Migrator::down(&database.db, None).await.unwrap();
+    assert_eq!(Migrator::get_pending_migrations(&database.db).await.unwrap().len(), count);
+    Migrator::up(&database.db, None).await.unwrap();
+    assert!(Migrator::get_pending_migrations(&database.db).await.unwrap().is_empty());
+
+    database.close().await;
+}
+
+/// A uuid value, as the columns of the migrations expect it.
+fn uuid_value(db: &DbConn, id: &str) -> SimpleExpr {
+    match db.get_database_backend() {
+        DbBackend::Postgres => Expr::val(id).cast_as(Alias::new("uuid")),
+        _ => Expr::val(id).into(),
+    }
+}
+
+#[tokio::test]
+async fn numbers_existing_events_in_order() {
+    let database = TestDatabase::empty().await;
+    let db = &database.db;
+    // Up to m20261019_000004, the events and checkpoints without sequences
+    Migrator::up(db, Some(6)).await.unwrap();
+
+    let aggregate_id = uuid::Uuid::new_v4().to_string();
+    let ids: Vec<String> = (0..3).map(|_| uuid::Uuid::new_v4().to_string()).collect();
+    let start = Utc::now() - Duration::minutes(1);
+
+    // Stored out of order, numbered by their timestamps
+    for i in [2, 0, 1] {
+        let insert = Query::insert()
+            .into_table(Alias::new("events"))
+            .columns(["id", "name", "payload", "aggregate_id", "timestamp"].map(Alias::new))
+            .values_panic([
+                uuid_value(db, &ids[i]),
+                Expr::val("CounterIncremented").into(),
+                Expr::val(json!({ "aggregate_id": aggregate_id, "amount": i })).into(),
+                uuid_value(db, &aggregate_id),
+                Expr::val(start + Duration::seconds(i as i64)).into(),
+            ])
+            .to_owned();
+        db.execute(db.get_database_backend().build(&insert)).await.unwrap();
+    }
+
+    let checkpoint = Query::insert()
+        .into_table(Alias::new("consumer_checkpoints"))
+        .columns(["consumer", "last_event_timestamp", "last_event_id", "updated_at"].map(Alias::new))
+        .values_panic([
+            Expr::val("counters").into(),
+            Expr::val(start + Duration::seconds(1)).into(),
+            uuid_value(db, &ids[1]),
+            Expr::val(Utc::now()).into(),
+        ])
+        .to_owned();
+    db.execute(db.get_database_backend().build(&checkpoint)).await.unwrap();
+
+    Migrator::up(db, None).await.unwrap();
+
+    let events = EventStore::new(db.clone()).load_events_after(0, 10).await.unwrap();
+    let sequences: Vec<_> = events.iter().map(|stored| stored.sequence).collect();
+    let loaded: Vec<_> = events.iter().map(|stored| stored.event.id.clone()).collect();
+    assert_eq!(sequences, vec![1, 2, 3]);
+    assert_eq!(loaded, ids);
+
+    // The checkpoint points to the same event
+    assert_eq!(Repository::new(db).find_checkpoint("counters").await.unwrap(), 2);
+
+    database.close().await;
+}
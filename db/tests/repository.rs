@@ -0,0 +1,262 @@
+mod common;
+
+use chrono::{Duration, Utc};
+use kountr_db::{
+    entity::{alerts, counters, webhook_deliveries, webhooks},
+    error::DbError,
+    repository::{CounterUpdate, Repository},
+};
+use serde_json::json;
+
+use common::{micros, TestDatabase};
+
+fn new_id() -> String {
+    uuid::Uuid::new_v4().to_string()
+}
+
+fn counter(name: &str, value: i32) -> counters::Model {
+    counters::Model {
+        id: new_id(),
+        name: name.to_string(),
+        value,
+    }
+}
+
+#[tokio::test]
+async fn stores_counters() {
+    let database = TestDatabase::migrated().await;
+    let repo = Repository::new(&database.db);
+
+    let coffee = repo.insert_counter(counter("Coffee", 1)).await.unwrap();
+    let tea = repo.insert_counter(counter("Tea", 0)).await.unwrap();
+    assert_eq!(repo.find_counter_by_id(coffee.id.clone()).await.unwrap(), coffee);
+    assert_eq!(repo.list_counters().await.unwrap().len(), 2);
+
+    // Pages are ordered by id
+    let (first, second) = if coffee.id < tea.id { (&coffee, &tea) } else { (&tea, &coffee) };
+    assert_eq!(repo.list_counters_page(None, 1).await.unwrap(), vec![first.clone()]);
+    assert_eq!(
+        repo.list_counters_page(Some(first.id.clone()), 5).await.unwrap(),
+        vec![second.clone()]
+    );
+    assert!(repo.list_counters_page(Some(second.id.clone()), 5).await.unwrap().is_empty());
+
+    let updated = repo.update_counter_value(coffee.id.clone(), -3).await.unwrap();
+    assert_eq!(updated.value, -2);
+
+    let renamed = counters::Model {
+        name: "Espresso".to_string(),
+        ..updated
+    };
+    repo.insert_or_update_counter(renamed.clone()).await.unwrap();
+    assert_eq!(repo.find_counter_by_id(coffee.id.clone()).await.unwrap(), renamed);
+
+    repo.delete_counter(tea.id.clone()).await.unwrap();
+    assert!(matches!(repo.delete_counter(tea.id.clone()).await, Err(DbError::NotFound)));
+    assert!(matches!(repo.find_counter_by_id(tea.id).await, Err(DbError::NotFound)));
+    // Not even looked up
+    assert!(matches!(
+        repo.find_counter_by_id("not-a-uuid".to_string()).await,
+        Err(DbError::NotFound)
+    ));
+
+    repo.delete_all_counters().await.unwrap();
+    assert!(repo.list_counters().await.unwrap().is_empty());
+
+    database.close().await;
+}
+
+#[tokio::test]
+async fn projects_counters_with_their_checkpoint() {
+    let database = TestDatabase::migrated().await;
+    let repo = Repository::new(&database.db);
+    let id = new_id();
+
+    assert_eq!(repo.find_checkpoint("counters").await.unwrap(), 0);
+
+    let updates = [
+        CounterUpdate::Create {
+            id: id.clone(),
+            name: "Coffee".to_string(),
+            value: 1,
+        },
+        CounterUpdate::Add {
+            id: id.clone(),
+            amount: 2,
+        },
+        CounterUpdate::Add {
+            id: id.clone(),
+            amount: -1,
+        },
+        CounterUpdate::Update {
+            id: id.clone(),
+            name: "Tea".to_string(),
+            value: 10,
+        },
+    ];
+    for (sequence, update) in (1..).zip(updates) {
+        repo.project_counter("counters", sequence - 1, sequence, update)
+            .await
+            .unwrap();
+    }
+
+    let projected = repo.find_counter_by_id(id.clone()).await.unwrap();
+    assert_eq!((projected.name.as_str(), projected.value), ("Tea", 10));
+    assert_eq!(repo.find_checkpoint("counters").await.unwrap(), 4);
+
+    // A stale checkpoint changes nothing, neither the counter nor the checkpoint
+    let add = CounterUpdate::Add {
+        id: id.clone(),
+        amount: 1,
+    };
+    for after in [0, 3] {
+        assert!(matches!(
+            repo.project_counter("counters", after, 5, add.clone()).await,
+            Err(DbError::Conflict(_))
+        ));
+    }
+    assert_eq!(repo.find_counter_by_id(id.clone()).await.unwrap().value, 10);
+    assert_eq!(repo.find_checkpoint("counters").await.unwrap(), 4);
+
+    repo.project_counter("counters", 4, 5, CounterUpdate::Delete { id: id.clone() })
+        .await
+        .unwrap();
+    repo.project_counter("counters", 5, 6, add).await.unwrap();
+    assert!(matches!(repo.find_counter_by_id(id).await, Err(DbError::NotFound)));
+
+    // Checkpoints are per consumer
+    repo.save_checkpoint("other", 2).await.unwrap();
+    repo.save_checkpoint("other", 3).await.unwrap();
+    assert_eq!(repo.find_checkpoint("other").await.unwrap(), 3);
+    assert_eq!(repo.find_checkpoint("counters").await.unwrap(), 6);
+
+    database.close().await;
+}
+
+#[tokio::test]
+async fn replaces_counters_with_their_checkpoint() {
+    let database = TestDatabase::migrated().await;
+    let repo = Repository::new(&database.db);
+    repo.insert_counter(counter("Coffee", 1)).await.unwrap();
+    repo.save_checkpoint("counters", 3).await.unwrap();
+
+    let rebuilt: Vec<_> = (0..300).map(|value| counter("Tea", value)).collect();
+    repo.replace_counters("counters", 300, rebuilt.clone()).await.unwrap();
+
+    let mut stored = repo.list_counters().await.unwrap();
+    stored.sort_by_key(|counter| counter.value);
+    assert_eq!(stored, rebuilt);
+    assert_eq!(repo.find_checkpoint("counters").await.unwrap(), 300);
+
+    database.close().await;
+}
+
+#[tokio::test]
+async fn stores_idempotency_keys() {
+    let database = TestDatabase::migrated().await;
+    let repo = Repository::new(&database.db);
+
+    assert!(repo.insert_idempotency_key("k1", "POST /").await.unwrap());
+    assert!(!repo.insert_idempotency_key("k1", "POST /").await.unwrap());
+    assert_eq!(repo.find_idempotency_key("k1").await.unwrap().response, None);
+
+    repo.complete_idempotency_key("k1", json!({"value": 1}))
+        .await
+        .unwrap();
+    let stored = repo.find_idempotency_key("k1").await.unwrap();
+    assert_eq!(stored.request, "POST /");
+    assert_eq!(stored.response, Some(json!({"value": 1})));
+
+    repo.delete_idempotency_keys_before(Utc::now() - Duration::hours(1))
+        .await
+        .unwrap();
+    assert!(repo.find_idempotency_key("k1").await.is_ok());
+    repo.delete_idempotency_keys_before(Utc::now() + Duration::hours(1))
+        .await
+        .unwrap();
+    assert!(matches!(repo.find_idempotency_key("k1").await, Err(DbError::NotFound)));
+
+    database.close().await;
+}
+
+#[tokio::test]
+async fn stores_webhooks_and_deliveries() {
+    let database = TestDatabase::migrated().await;
+    let repo = Repository::new(&database.db);
+
+    let webhook = webhooks::Model {
+        id: new_id(),
+        url: "http://localhost/hook".to_string(),
+        event_types: "CounterCreated,CounterDeleted".to_string(),
+        secret: "secret".to_string(),
+        created_at: micros(Utc::now()),
+    };
+    repo.insert_webhook(webhook.clone()).await.unwrap();
+    assert_eq!(repo.find_webhook_by_id(webhook.id.clone()).await.unwrap(), webhook);
+    assert_eq!(repo.list_webhooks().await.unwrap(), vec![webhook.clone()]);
+
+    let now = micros(Utc::now());
+    for (attempt, status_code) in [(1, Some(500)), (2, None)] {
+        let delivery = webhook_deliveries::Model {
+            id: new_id(),
+            webhook_id: webhook.id.clone(),
+            event_id: new_id(),
+            event_type: "CounterCreated".to_string(),
+            attempt,
+            status_code,
+            error: status_code.map_or(Some("timed out".to_string()), |_| None),
+            success: false,
+            timestamp: now + Duration::seconds(attempt as i64),
+        };
+        repo.insert_webhook_delivery(delivery).await.unwrap();
+    }
+
+    let deliveries = repo.list_webhook_deliveries(webhook.id.clone()).await.unwrap();
+    let attempts: Vec<_> = deliveries.iter().map(|d| (d.attempt, d.status_code)).collect();
+    assert_eq!(attempts, vec![(2, None), (1, Some(500))]);
+    assert_eq!(deliveries[0].timestamp, now + Duration::seconds(2));
+
+    repo.delete_webhook(webhook.id.clone()).await.unwrap();
+    assert!(matches!(repo.delete_webhook(webhook.id).await, Err(DbError::NotFound)));
+
+    database.close().await;
+}
+
+#[tokio::test]
+async fn finds_the_last_alert_of_a_rule() {
+    let database = TestDatabase::migrated().await;
+    let repo = Repository::new(&database.db);
+    let rule_id = new_id();
+    let now = micros(Utc::now());
+
+    for minutes in [10, 5, 20] {
+        let alert = alerts::Model {
+            id: new_id(),
+            rule_id: rule_id.clone(),
+            counter_id: new_id(),
+            message: format!("{} minutes ago", minutes),
+            value: 1,
+            channel: "in_app".to_string(),
+            error: None,
+            fired_at: now - Duration::minutes(minutes),
+        };
+        repo.insert_alert(alert).await.unwrap();
+    }
+
+    let last = repo.find_last_alert_by_rule(rule_id.clone()).await.unwrap().unwrap();
+    assert_eq!(last.message, "5 minutes ago");
+    assert_eq!(last.fired_at, now - Duration::minutes(5));
+    assert!(repo.find_last_alert_by_rule(new_id()).await.unwrap().is_none());
+
+    repo.update_alert_error(last.id.clone(), "timed out".to_string())
+        .await
+        .unwrap();
+    let last = repo.find_last_alert_by_rule(rule_id).await.unwrap().unwrap();
+    assert_eq!(last.error.as_deref(), Some("timed out"));
+    assert!(matches!(
+        repo.update_alert_error(new_id(), "timed out".to_string()).await,
+        Err(DbError::NotFound)
+    ));
+
+    database.close().await;
+}
@@ -0,0 +1,310 @@
+use anyhow::{anyhow, Context};
+use reqwest::{Method, RequestBuilder, Response, StatusCode};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use kountr_app::domain::models::{Counter, StoredEvent};
+use kountr_app::{AppState, DbError, EventFilter};
+
+// How many events are fetched at once for the history of a counter.
+const HISTORY_PAGE_SIZE: u64 = 1000;
+
+/// Manages counters either directly in the database, or through the JSON API of a running server.
+pub enum Client {
+    Local(Box<AppState>),
+    Remote { http: reqwest::Client, url: String },
+}
+
+#[derive(Serialize)]
+struct CounterParams<'a> {
+    name: &'a str,
+    value: i32,
+}
+
+#[derive(Deserialize)]
+struct EventsPage {
+    events: Vec<StoredEvent>,
+    next: i64,
+}
+
+#[derive(Deserialize)]
+struct ErrorBody {
+    error: String,
+}
+
+impl Client {
+    /// Works on the database directly, see `close`.
+    pub async fn local(db_url: &str) -> anyhow::Result<Self> {
+        let app = kountr_app::connect_app_inline(db_url).await?;
+
+        Ok(Client::Local(Box::new(app)))
+    }
+
+    /// `url` is where the server listens, eg. `http://localhost:8000`.
+    pub fn remote(url: &str) -> Self {
+        Client::Remote {
+            http: reqwest::Client::new(),
+            url: url.trim_end_matches('/').to_string(),
+        }
+    }
+
+    pub async fn list(&self) -> anyhow::Result<Vec<Counter>> {
+        match self {
+            Client::Local(app) => Ok(kountr_app::list_all_counters(app).await?),
+            Client::Remote { .. } => self.send(self.request(Method::GET, "/api/v1/counters")).await,
+        }
+    }
+
+    pub async fn show(&self, id: &str) -> anyhow::Result<Counter> {
+        match self {
+            Client::Local(app) => local(kountr_app::find_counter(app, id.to_string()).await),
+            Client::Remote { .. } => {
+                let path = format!("/api/v1/counters/{}", id);
+                self.send(self.request(Method::GET, &path)).await
+            }
+        }
+    }
+
+    pub async fn create(&self, name: &str, value: i32) -> anyhow::Result<Counter> {
+        match self {
+            Client::Local(app) => {
+                let counter = Counter::new(name.to_string(), value);
+                Ok(kountr_app::add_counter(app, counter).await?)
+            }
+            Client::Remote { .. } => {
+                let request = self.request(Method::POST, "/api/v1/counters");
+                self.send(request.json(&CounterParams { name, value })).await
+            }
+        }
+    }
+
+    pub async fn increment(&self, id: &str) -> anyhow::Result<Counter> {
+        match self {
+            Client::Local(app) => local(kountr_app::increment_counter(app, id.to_string()).await),
+            Client::Remote { .. } => {
+                let path = format!("/api/v1/counters/{}/increment", id);
+                self.send(self.request(Method::POST, &path)).await
+            }
+        }
+    }
+
+    pub async fn decrement(&self, id: &str) -> anyhow::Result<Counter> {
+        match self {
+            Client::Local(app) => local(kountr_app::decrement_counter(app, id.to_string()).await),
+            Client::Remote { .. } => {
+                let path = format!("/api/v1/counters/{}/decrement", id);
+                self.send(self.request(Method::POST, &path)).await
+            }
+        }
+    }
+
+    /// Sets the value of a counter, and its name when given.
+    pub async fn set(&self, id: &str, value: i32, name: Option<&str>) -> anyhow::Result<Counter> {
+        let current = self.show(id).await?;
+        let name = name.unwrap_or(&current.name);
+
+        match self {
+            Client::Local(app) => {
+                let counter = Counter::new_with_id(id.to_string(), name.to_string(), value);
+                local(kountr_app::update_counter(app, counter).await)
+            }
+            Client::Remote { .. } => {
+                let path = format!("/api/v1/counters/{}", id);
+                let request = self.request(Method::PUT, &path);
+                self.send(request.json(&CounterParams { name, value })).await
+            }
+        }
+    }
+
+    pub async fn delete(&self, id: &str) -> anyhow::Result<()> {
+        match self {
+            Client::Local(app) => local(kountr_app::delete_counter(app, id.to_string()).await),
+            Client::Remote { .. } => {
+                let path = format!("/api/v1/counters/{}", id);
+                check(self.request(Method::DELETE, &path).send().await?).await?;
+                Ok(())
+            }
+        }
+    }
+
+    /// Every event of a counter, in order, even when it has been deleted.
+    pub async fn history(&self, id: &str) -> anyhow::Result<Vec<StoredEvent>> {
+        let mut events = vec![];
+        let mut after = 0;
+
+        loop {
+            let page = match self {
+                Client::Local(app) => {
+                    let filter = EventFilter {
+                        after,
+                        limit: HISTORY_PAGE_SIZE,
+                        aggregate_id: Some(id.to_string()),
+                        ..Default::default()
+                    };
+                    let events = kountr_app::list_events(app, filter).await?;
+                    let next = events.last().map_or(after, |evt| evt.sequence);
+                    EventsPage { events, next }
+                }
+                Client::Remote { .. } => {
+                    let request = self.request(Method::GET, "/api/v1/events").query(&[
+                        ("aggregate_id", id.to_string()),
+                        ("after", after.to_string()),
+                        ("limit", HISTORY_PAGE_SIZE.to_string()),
+                    ]);
+                    self.send(request).await?
+                }
+            };
+
+            if page.events.is_empty() {
+                break;
+            }
+            after = page.next;
+            events.extend(page.events);
+        }
+
+        if events.is_empty() {
+            return Err(anyhow!("counter {} not found", id));
+        }
+
+        Ok(events)
+    }
+
+    /// Waits for the webhook deliveries of local commands, then closes the database.
+    pub async fn close(self) -> anyhow::Result<()> {
+        match self {
+            Client::Local(app) => Ok(kountr_app::shutdown(*app).await?),
+            Client::Remote { .. } => Ok(()),
+        }
+    }
+
+    fn request(&self, method: Method, path: &str) -> RequestBuilder {
+        match self {
+            Client::Remote { http, url } => http.request(method, format!("{}{}", url, path)),
+            Client::Local(_) => unreachable!("local clients don't send requests"),
+        }
+    }
+
+    async fn send<T: DeserializeOwned>(&self, request: RequestBuilder) -> anyhow::Result<T> {
+        let response = check(request.send().await?).await?;
+
+        response
+            .json()
+            .await
+            .context("unexpected response from the server")
+    }
+}
+
+fn local<T>(result: Result<T, DbError>) -> anyhow::Result<T> {
+    result.map_err(|err| match err {
+        DbError::NotFound => anyhow!("counter not found"),
+        err => err.into(),
+    })
+}
+
+/// Turns error responses into errors, with the message given by the server.
+async fn check(response: Response) -> anyhow::Result<Response> {
+    let status = response.status();
+    if status.is_success() {
+        return Ok(response);
+    }
+    if status == StatusCode::NOT_FOUND {
+        return Err(anyhow!("counter not found"));
+    }
+
+    let message = match response.json::<ErrorBody>().await {
+        Ok(body) => body.error,
+        Err(_) => status.to_string(),
+    };
+
+    Err(anyhow!("the server replied {}: {}", status, message))
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    /// A server answering every request with the same raw HTTP `status` and `body`.
+    async fn server(status: &'static str, body: &'static str) -> Client {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+
+        tokio::spawn(async move {
+            while let Ok((mut socket, _)) = listener.accept().await {
+                let mut request = [0; 4096];
+                _ = socket.read(&mut request).await;
+                let response = format!(
+                    "HTTP/1.1 {}\r\ncontent-type: application/json\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{}",
+                    status,
+                    body.len(),
+                    body
+                );
+                _ = socket.write_all(response.as_bytes()).await;
+            }
+        });
+
+        Client::remote(&url)
+    }
+
+    #[tokio::test]
+    async fn reads_counters_from_the_server() {
+        let body = r#"{"id": "c1", "name": "Coffee", "value": 3}"#;
+        let client = server("200 OK", body).await;
+
+        let counter = client.show("c1").await.unwrap();
+        assert_eq!((counter.id.as_str(), counter.name.as_str(), counter.value), ("c1", "Coffee", 3));
+    }
+
+    #[tokio::test]
+    async fn maps_server_errors() {
+        let cases = [
+            ("404 Not Found", "", "counter not found"),
+            (
+                "409 Conflict",
+                r#"{"error": "key reused"}"#,
+                "the server replied 409 Conflict: key reused",
+            ),
+            (
+                "500 Internal Server Error",
+                "oops",
+                "the server replied 500 Internal Server Error: 500 Internal Server Error",
+            ),
+            ("200 OK", "[]", "unexpected response from the server"),
+        ];
+
+        for (status, body, message) in cases {
+            let client = server(status, body).await;
+            assert_eq!(client.increment("c1").await.unwrap_err().to_string(), message);
+        }
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[tokio::test]
+    async fn manages_counters_in_the_database() {
+        let path = std::env::temp_dir().join(format!("kountr-client-{}.db", std::process::id()));
+        let client = Client::local(&format!("sqlite://{}?mode=rwc", path.display())).await.unwrap();
+
+        let counter = client.create("Coffee", 1).await.unwrap();
+        assert_eq!(client.increment(&counter.id).await.unwrap().value, 2);
+        assert_eq!(client.decrement(&counter.id).await.unwrap().value, 1);
+        let renamed = client.set(&counter.id, 5, Some("Tea")).await.unwrap();
+        assert_eq!((renamed.name.as_str(), renamed.value), ("Tea", 5));
+        assert_eq!(client.list().await.unwrap().len(), 1);
+
+        client.delete(&counter.id).await.unwrap();
+        let err = client.increment(&counter.id).await.unwrap_err();
+        assert_eq!(err.to_string(), "counter not found");
+        assert_eq!(client.show(&counter.id).await.unwrap_err().to_string(), "counter not found");
+
+        let history = client.history(&counter.id).await.unwrap();
+        let types: Vec<&str> = history.iter().map(|evt| evt.event_type.as_str()).collect();
+        assert_eq!(
+            types,
+            ["CounterCreated", "CounterIncremented", "CounterDecremented", "CounterUpdated", "CounterDeleted"]
+        );
+
+        client.close().await.unwrap();
+        _ = std::fs::remove_file(path);
+    }
+}
@@ -1,11 +1,261 @@
+mod client;
+mod output;
+
+use std::{
+    fs::File,
+    io::{self, BufReader, BufWriter, Write},
+    path::PathBuf,
+    process::ExitCode,
+};
+
+use anyhow::Context;
+use clap::{Parser, Subcommand};
+use futures::{pin_mut, StreamExt};
+use kountr_app::{event_log::EventLog, Settings};
 use kountr_web::{Server, AppOptions, dotenv};
 
+use client::Client;
+use output::Format;
+
+#[derive(Parser)]
+#[command(version, about = "Simple app to manage counters")]
+struct Cli {
+    /// Starts the server when omitted
+    #[command(subcommand)]
+    command: Option<Command>,
+    /// Manages counters through the server at this URL, instead of the database
+    #[arg(long, global = true, env = "KOUNTR_URL")]
+    remote: Option<String>,
+    #[arg(long, global = true, value_enum, default_value_t = Format::Table)]
+    format: Format,
+    #[command(flatten)]
+    settings: SettingsFlags,
+}
+
+/// Override the config file and the environment variables.
+#[derive(clap::Args)]
+struct SettingsFlags {
+    /// Defaults to `kountr.toml`, when it exists
+    #[arg(long, global = true, env = "KOUNTR_CONFIG")]
+    config: Option<PathBuf>,
+    #[arg(long, global = true)]
+    database_url: Option<String>,
+    #[arg(long, global = true)]
+    host: Option<String>,
+    #[arg(long, global = true)]
+    port: Option<u16>,
+    /// `dev` or `prod`
+    #[arg(long, global = true)]
+    env: Option<String>,
+}
+
+impl SettingsFlags {
+    fn load(self) -> Result<AppOptions, kountr_app::ConfigError> {
+        let flags = Settings {
+            database_url: self.database_url,
+            host: self.host,
+            port: self.port,
+            env: self.env,
+            ..Default::default()
+        };
+
+        let settings = Settings::load(self.config.as_deref())?.merge(flags);
+        AppOptions::from_settings(settings)
+    }
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Starts the server (the default)
+    Serve,
+    /// Brings the database schema up to date
+    Migrate,
+    /// Rebuilds the counters from the event log, the server should be stopped
+    Replay,
+    /// Checks the configuration and shows the settings in use
+    CheckConfig,
+    /// Backs up or restores the event log, as JSON Lines
+    #[command(subcommand)]
+    Events(EventsCommand),
+    /// Exports the counters
+    #[command(subcommand)]
+    Counters(CountersCommand),
+    /// Lists the counters
+    List,
+    /// Shows a counter
+    Show { id: String },
+    /// Creates a counter
+    Create {
+        name: String,
+        #[arg(long, default_value_t = 0, allow_negative_numbers = true)]
+        value: i32,
+    },
+    /// Increments a counter by one
+    Inc { id: String },
+    /// Decrements a counter by one
+    Dec { id: String },
+    /// Sets the value of a counter
+    Set {
+        id: String,
+        #[arg(allow_negative_numbers = true)]
+        value: i32,
+        /// Renames the counter too
+        #[arg(long)]
+        name: Option<String>,
+    },
+    /// Deletes a counter
+    Rm { id: String },
+    /// Lists the events of a counter, in order
+    History { id: String },
+}
+
+#[derive(Subcommand)]
+enum EventsCommand {
+    /// Writes all the stored events, in order
+    Export {
+        /// Writes to stdout when omitted
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+    /// Stores the events that aren't there yet, then rebuilds the counters
+    Import {
+        /// Reads from stdin when omitted
+        file: Option<PathBuf>,
+    },
+}
+
+#[derive(Subcommand)]
+enum CountersCommand {
+    /// Writes the current counters as CSV
+    Export {
+        /// Writes to stdout when omitted
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+}
 
 #[tokio::main]
-async fn main() -> anyhow::Result<()> {
+async fn main() -> anyhow::Result<ExitCode> {
     dotenv().ok();
 
-    let opts = AppOptions::new_from_envs();
+    let cli = Cli::parse();
+    let opts = match cli.settings.load() {
+        Ok(opts) => opts,
+        Err(err) => {
+            eprintln!("Configuration error: {}", err);
+            return Ok(ExitCode::FAILURE);
+        }
+    };
+
+    match cli.command {
+        None | Some(Command::Serve) => Server::start(&opts).await?,
+        Some(Command::Migrate) => {
+            kountr_app::run_migrations(database_url(&opts)?).await?;
+            eprintln!("The database is up to date");
+        }
+        Some(Command::Replay) => {
+            let log = EventLog::connect(database_url(&opts)?).await?;
+            let count = log.rebuild_counters().await?;
+            eprintln!("Rebuilt the counters from {} events", count);
+        }
+        Some(Command::CheckConfig) => {
+            println!("{}", opts);
+            eprintln!("The configuration is valid");
+        }
+        Some(Command::Events(command)) => run_events_command(command, &opts).await?,
+        Some(Command::Counters(command)) => run_counters_command(command, &opts).await?,
+        Some(command) => {
+            let client = match &cli.remote {
+                Some(url) => Client::remote(url),
+                None => Client::local(database_url(&opts)?).await?,
+            };
+
+            let result = run_client_command(command, &client, cli.format).await;
+            client.close().await?;
+            result?
+        }
+    }
+
+    Ok(ExitCode::SUCCESS)
+}
+
+fn database_url(opts: &AppOptions) -> anyhow::Result<&str> {
+    opts.db_url
+        .as_deref()
+        .context("no database is configured (see `database_url`), there's no data to read or write")
+}
+
+fn output_file(output: Option<PathBuf>) -> anyhow::Result<Box<dyn Write>> {
+    Ok(match output {
+        Some(path) => Box::new(BufWriter::new(File::create(path)?)),
+        None => Box::new(BufWriter::new(io::stdout().lock())),
+    })
+}
+
+async fn run_events_command(command: EventsCommand, opts: &AppOptions) -> anyhow::Result<()> {
+    let log = EventLog::connect(database_url(opts)?).await?;
+
+    match command {
+        EventsCommand::Export { output } => {
+            let mut out = output_file(output)?;
+
+            let count = log.export(&mut out).await?;
+            out.flush()?;
+            eprintln!("Exported {} events", count);
+        }
+        EventsCommand::Import { file } => {
+            let report = match file {
+                Some(path) => log.import(BufReader::new(File::open(path)?)).await?,
+                None => log.import(io::stdin().lock()).await?,
+            };
+
+            eprintln!(
+                "Imported {} events, skipped {} duplicates",
+                report.imported, report.duplicates
+            );
+        }
+    }
+
+    Ok(())
+}
+
+async fn run_counters_command(command: CountersCommand, opts: &AppOptions) -> anyhow::Result<()> {
+    let app = kountr_app::connect_app_inline(database_url(opts)?).await?;
+
+    match command {
+        CountersCommand::Export { output } => {
+            let mut out = output_file(output)?;
+
+            let chunks = kountr_app::export_counters_csv(&app).await?;
+            pin_mut!(chunks);
+            while let Some(chunk) = chunks.next().await {
+                out.write_all(&chunk?)?;
+            }
+            out.flush()?;
+        }
+    }
+
+    Ok(kountr_app::shutdown(app).await?)
+}
 
-    Server::start(&opts).await
+async fn run_client_command(command: Command, client: &Client, format: Format) -> anyhow::Result<()> {
+    match command {
+        Command::List => output::print_counters(format, &client.list().await?),
+        Command::Show { id } => output::print_counter(format, &client.show(&id).await?),
+        Command::Create { name, value } => {
+            output::print_counter(format, &client.create(&name, value).await?)
+        }
+        Command::Inc { id } => output::print_counter(format, &client.increment(&id).await?),
+        Command::Dec { id } => output::print_counter(format, &client.decrement(&id).await?),
+        Command::Set { id, value, name } => {
+            output::print_counter(format, &client.set(&id, value, name.as_deref()).await?)
+        }
+        Command::Rm { id } => {
+            client.delete(&id).await?;
+            eprintln!("Deleted counter {}", id);
+            Ok(())
+        }
+        Command::History { id } => output::print_events(format, &client.history(&id).await?),
+        _ => unreachable!("not a client command"),
+    }
 }
@@ -0,0 +1,146 @@
+use clap::ValueEnum;
+use serde::Serialize;
+use serde_json::Value;
+
+use kountr_app::domain::models::{Counter, StoredEvent};
+
+#[derive(Clone, Copy, ValueEnum)]
+pub enum Format {
+    Table,
+    Json,
+}
+
+pub fn print_counters(format: Format, counters: &[Counter]) -> anyhow::Result<()> {
+    match format {
+        Format::Json => print_json(counters),
+        Format::Table => {
+            let rows = counters
+                .iter()
+                .map(|c| vec![c.id.clone(), c.name.clone(), c.value.to_string()])
+                .collect();
+
+            print_table(&["ID", "NAME", "VALUE"], rows);
+            Ok(())
+        }
+    }
+}
+
+pub fn print_counter(format: Format, counter: &Counter) -> anyhow::Result<()> {
+    match format {
+        Format::Json => print_json(counter),
+        Format::Table => print_counters(format, std::slice::from_ref(counter)),
+    }
+}
+
+pub fn print_events(format: Format, events: &[StoredEvent]) -> anyhow::Result<()> {
+    match format {
+        Format::Json => print_json(events),
+        Format::Table => {
+            let rows = events
+                .iter()
+                .map(|e| {
+                    vec![
+                        e.sequence.to_string(),
+                        e.timestamp.format("%Y-%m-%d %H:%M:%S").to_string(),
+                        e.event_type.clone(),
+                        details(&e.data),
+                    ]
+                })
+                .collect();
+
+            print_table(&["SEQUENCE", "TIMESTAMP (UTC)", "EVENT", "DETAILS"], rows);
+            Ok(())
+        }
+    }
+}
+
+fn print_json<T: Serialize + ?Sized>(value: &T) -> anyhow::Result<()> {
+    println!("{}", serde_json::to_string_pretty(value)?);
+
+    Ok(())
+}
+
+fn print_table(headers: &[&str], rows: Vec<Vec<String>>) {
+    print!("{}", table(headers, rows));
+}
+
+/// Aligns the cells in columns, one line per row after the headers.
+fn table(headers: &[&str], rows: Vec<Vec<String>>) -> String {
+    let mut widths: Vec<usize> = headers.iter().map(|h| h.chars().count()).collect();
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+
+    let headers = headers.iter().map(|h| h.to_string()).collect();
+    let mut out = String::new();
+    for row in std::iter::once(headers).chain(rows) {
+        let line: Vec<String> = row
+            .iter()
+            .zip(&widths)
+            .map(|(cell, width)| format!("{:width$}", cell, width = width))
+            .collect();
+
+        out.push_str(line.join("  ").trim_end());
+        out.push('\n');
+    }
+
+    out
+}
+
+/// The fields of an event payload, eg. `{"CounterIncremented": {"amount": 1, ...}}`
+/// becomes `amount=1`, leaving out the counter id.
+fn details(data: &Value) -> String {
+    let fields = data
+        .as_object()
+        .and_then(|variant| variant.values().next())
+        .and_then(Value::as_object);
+
+    let Some(fields) = fields else {
+        return data.to_string();
+    };
+
+    fields
+        .iter()
+        .filter(|(name, _)| name.as_str() != "aggregate_id")
+        .map(|(name, value)| match value.as_str() {
+            Some(text) => format!("{}={}", name, text),
+            None => format!("{}={}", name, value),
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn aligns_columns() {
+        let rows = vec![
+            vec!["c1".to_string(), "Coffee".to_string(), "3".to_string()],
+            vec!["c2".to_string(), "Tè".to_string(), "-12".to_string()],
+        ];
+
+        assert_eq!(
+            table(&["ID", "NAME", "VALUE"], rows),
+            "ID  NAME    VALUE\nc1  Coffee  3\nc2  Tè      -12\n"
+        );
+        assert_eq!(table(&["ID"], vec![]), "ID\n");
+    }
+
+    #[test]
+    fn shows_event_fields_but_the_counter_id() {
+        let data = json!({"CounterUpdated": {"aggregate_id": "c1", "name": "Tea", "value": 3}});
+        assert_eq!(details(&data), "name=Tea value=3");
+
+        let data = json!({"CounterDeleted": {"aggregate_id": "c1"}});
+        assert_eq!(details(&data), "");
+
+        // Anything else is shown as is
+        assert_eq!(details(&json!(["unexpected"])), r#"["unexpected"]"#);
+    }
+}
@@ -0,0 +1,17 @@
+use std::process::Command;
+
+// Exposes the git commit being built to `/version`, when available.
+fn main() {
+    let commit = Command::new("git")
+        .args(["rev-parse", "--short", "HEAD"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .map(|commit| commit.trim().to_string())
+        .unwrap_or_default();
+
+    println!("cargo:rustc-env=KOUNTR_GIT_COMMIT={}", commit);
+    println!("cargo:rerun-if-changed=../.git/HEAD");
+    println!("cargo:rerun-if-changed=../.git/refs/heads");
+}
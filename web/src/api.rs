@@ -0,0 +1,364 @@
+use axum::{
+    extract::{Json, Path, Query, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
+use serde::{Deserialize, Serialize};
+use tracing::error;
+
+use chrono::{DateTime, Utc};
+use kountr_app::domain::models::{
+    AlertChannel, AlertCondition, AlertRule, Counter, StoredEvent, Webhook,
+};
+use kountr_app::{AppState, DbError, EventFilter};
+
+use crate::handlers::IdempotencyKeyHeader;
+
+// ====================== ERRORS ==============================================
+
+pub struct ApiError(DbError);
+
+impl From<DbError> for ApiError {
+    fn from(err: DbError) -> Self {
+        ApiError(err)
+    }
+}
+
+#[derive(Serialize)]
+struct ErrorBody {
+    error: String,
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let (status, error) = match self.0 {
+            DbError::NotFound => (StatusCode::NOT_FOUND, "not found".to_string()),
+            DbError::Invalid(msg) => (StatusCode::UNPROCESSABLE_ENTITY, msg),
+            DbError::Conflict(msg) => (StatusCode::CONFLICT, msg),
+            // Database and driver errors aren't for clients
+            err => {
+                error!("Internal error: {}", err);
+                (StatusCode::INTERNAL_SERVER_ERROR, "internal error".to_string())
+            }
+        };
+
+        (status, Json(ErrorBody { error })).into_response()
+    }
+}
+
+// ====================== PARAMS ==============================================
+
+#[derive(Deserialize)]
+pub struct CounterParams {
+    name: String,
+    #[serde(default)]
+    value: i32,
+}
+
+#[derive(Deserialize)]
+pub struct NewWebhookParams {
+    url: String,
+    #[serde(default)]
+    event_types: Vec<String>,
+    secret: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct CreatedWebhook {
+    #[serde(flatten)]
+    webhook: Webhook,
+    /// Only shown on creation, needed by receivers to verify signatures.
+    secret: String,
+}
+
+#[derive(Deserialize)]
+pub struct NewAlertRuleParams {
+    counter_id: String,
+    name: String,
+    condition: String,
+    threshold: i32,
+    channel: String,
+    #[serde(default)]
+    target: String,
+    #[serde(default)]
+    debounce_secs: i32,
+}
+
+impl TryFrom<NewAlertRuleParams> for AlertRule {
+    type Error = DbError;
+
+    fn try_from(params: NewAlertRuleParams) -> Result<Self, Self::Error> {
+        let condition = AlertCondition::parse(&params.condition, params.threshold)
+            .ok_or(DbError::Invalid(format!("unknown condition: {}", params.condition)))?;
+        let channel = AlertChannel::parse(&params.channel)
+            .ok_or(DbError::Invalid(format!("unknown channel: {}", params.channel)))?;
+
+        Ok(AlertRule::new(
+            params.counter_id,
+            params.name,
+            condition,
+            channel,
+            params.target,
+            params.debounce_secs,
+        ))
+    }
+}
+
+#[derive(Deserialize)]
+pub struct NotificationsParams {
+    #[serde(default)]
+    unread: bool,
+}
+
+const DEFAULT_EVENTS_LIMIT: u64 = 100;
+const MAX_EVENTS_LIMIT: u64 = 1000;
+
+#[derive(Deserialize)]
+pub struct EventsParams {
+    #[serde(default)]
+    after: i64,
+    limit: Option<u64>,
+    /// Comma separated event types, eg. `CounterIncremented,CounterDecremented`.
+    #[serde(rename = "type")]
+    event_type: Option<String>,
+    /// Only the events of a counter.
+    aggregate_id: Option<String>,
+    from: Option<DateTime<Utc>>,
+    to: Option<DateTime<Utc>>,
+}
+
+impl From<EventsParams> for EventFilter {
+    fn from(params: EventsParams) -> Self {
+        EventFilter {
+            after: params.after,
+            limit: params
+                .limit
+                .unwrap_or(DEFAULT_EVENTS_LIMIT)
+                .clamp(1, MAX_EVENTS_LIMIT),
+            event_types: params
+                .event_type
+                .map(|types| {
+                    types
+                        .split(',')
+                        .map(|t| t.trim().to_string())
+                        .filter(|t| !t.is_empty())
+                        .collect()
+                })
+                .unwrap_or_default(),
+            aggregate_id: params.aggregate_id,
+            from: params.from,
+            to: params.to,
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct EventsPage {
+    events: Vec<StoredEvent>,
+    /// The `after` value for the next page, the same as the request when there are no new events.
+    next: i64,
+}
+
+// ====================== HANDLERS ============================================
+
+pub async fn list_counters(state: State<AppState>) -> Result<impl IntoResponse, ApiError> {
+    let counters = kountr_app::list_all_counters(&state).await?;
+
+    Ok(Json(counters))
+}
+
+pub async fn add_counter(
+    state: State<AppState>,
+    IdempotencyKeyHeader(key): IdempotencyKeyHeader,
+    Json(params): Json<CounterParams>,
+) -> Result<impl IntoResponse, ApiError> {
+    let command = kountr_app::add_counter(&state, Counter::new(params.name, params.value));
+    let counter = kountr_app::idempotent(&state, key.as_ref(), command).await?;
+
+    Ok((StatusCode::CREATED, Json(counter)))
+}
+
+pub async fn find_counter(
+    Path(id): Path<String>,
+    state: State<AppState>,
+) -> Result<impl IntoResponse, ApiError> {
+    let counter = kountr_app::find_counter(&state, id).await?;
+
+    Ok(Json(counter))
+}
+
+pub async fn update_counter(
+    Path(id): Path<String>,
+    state: State<AppState>,
+    IdempotencyKeyHeader(key): IdempotencyKeyHeader,
+    Json(params): Json<CounterParams>,
+) -> Result<impl IntoResponse, ApiError> {
+    let counter = Counter::new_with_id(id, params.name, params.value);
+    let command = kountr_app::update_counter(&state, counter);
+    let counter = kountr_app::idempotent(&state, key.as_ref(), command).await?;
+
+    Ok(Json(counter))
+}
+
+pub async fn delete_counter(
+    Path(id): Path<String>,
+    state: State<AppState>,
+    IdempotencyKeyHeader(key): IdempotencyKeyHeader,
+) -> Result<impl IntoResponse, ApiError> {
+    let command = kountr_app::delete_counter(&state, id);
+    kountr_app::idempotent(&state, key.as_ref(), command).await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn increment_counter(
+    Path(id): Path<String>,
+    state: State<AppState>,
+    IdempotencyKeyHeader(key): IdempotencyKeyHeader,
+) -> Result<impl IntoResponse, ApiError> {
+    let command = kountr_app::increment_counter(&state, id);
+    let counter = kountr_app::idempotent(&state, key.as_ref(), command).await?;
+
+    Ok(Json(counter))
+}
+
+pub async fn decrement_counter(
+    Path(id): Path<String>,
+    state: State<AppState>,
+    IdempotencyKeyHeader(key): IdempotencyKeyHeader,
+) -> Result<impl IntoResponse, ApiError> {
+    let command = kountr_app::decrement_counter(&state, id);
+    let counter = kountr_app::idempotent(&state, key.as_ref(), command).await?;
+
+    Ok(Json(counter))
+}
+
+pub async fn list_webhooks(state: State<AppState>) -> Result<impl IntoResponse, ApiError> {
+    let webhooks = kountr_app::list_webhooks(&state).await?;
+
+    Ok(Json(webhooks))
+}
+
+pub async fn add_webhook(
+    state: State<AppState>,
+    Json(params): Json<NewWebhookParams>,
+) -> Result<impl IntoResponse, ApiError> {
+    let webhook =
+        kountr_app::add_webhook(&state, params.url, params.event_types, params.secret).await?;
+
+    let created = CreatedWebhook {
+        secret: webhook.secret.clone(),
+        webhook,
+    };
+
+    Ok((StatusCode::CREATED, Json(created)))
+}
+
+pub async fn delete_webhook(
+    Path(id): Path<String>,
+    state: State<AppState>,
+) -> Result<impl IntoResponse, ApiError> {
+    kountr_app::delete_webhook(&state, id).await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn list_webhook_deliveries(
+    Path(id): Path<String>,
+    state: State<AppState>,
+) -> Result<impl IntoResponse, ApiError> {
+    let deliveries = kountr_app::list_webhook_deliveries(&state, id).await?;
+
+    Ok(Json(deliveries))
+}
+
+pub async fn list_alert_rules(state: State<AppState>) -> Result<impl IntoResponse, ApiError> {
+    let rules = kountr_app::list_alert_rules(&state).await?;
+
+    Ok(Json(rules))
+}
+
+pub async fn add_alert_rule(
+    state: State<AppState>,
+    Json(params): Json<NewAlertRuleParams>,
+) -> Result<impl IntoResponse, ApiError> {
+    let rule = kountr_app::add_alert_rule(&state, params.try_into()?).await?;
+
+    Ok((StatusCode::CREATED, Json(rule)))
+}
+
+pub async fn delete_alert_rule(
+    Path(id): Path<String>,
+    state: State<AppState>,
+) -> Result<impl IntoResponse, ApiError> {
+    kountr_app::delete_alert_rule(&state, id).await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn list_alerts(state: State<AppState>) -> Result<impl IntoResponse, ApiError> {
+    let alerts = kountr_app::list_alerts(&state).await?;
+
+    Ok(Json(alerts))
+}
+
+pub async fn list_notifications(
+    state: State<AppState>,
+    Query(params): Query<NotificationsParams>,
+) -> Result<impl IntoResponse, ApiError> {
+    let notifications = kountr_app::list_notifications(&state, params.unread).await?;
+
+    Ok(Json(notifications))
+}
+
+pub async fn mark_notification_read(
+    Path(id): Path<String>,
+    state: State<AppState>,
+) -> Result<impl IntoResponse, ApiError> {
+    kountr_app::mark_notification_read(&state, id).await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn list_events(
+    state: State<AppState>,
+    Query(params): Query<EventsParams>,
+) -> Result<impl IntoResponse, ApiError> {
+    let after = params.after;
+    let events = kountr_app::list_events(&state, params.into()).await?;
+    let next = events.last().map_or(after, |evt| evt.sequence);
+
+    Ok(Json(EventsPage { events, next }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn error_response(err: DbError) -> (StatusCode, String) {
+        let response = ApiError(err).into_response();
+        let status = response.status();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+
+        (status, String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    #[tokio::test]
+    async fn hides_internal_errors_from_clients() {
+        // A driver error, with its details
+        let err = kountr_app::run_migrations("unknown://db").await.unwrap_err();
+        assert_eq!(
+            error_response(err).await,
+            (StatusCode::INTERNAL_SERVER_ERROR, r#"{"error":"internal error"}"#.to_string())
+        );
+
+        assert_eq!(
+            error_response(DbError::NotFound).await,
+            (StatusCode::NOT_FOUND, r#"{"error":"not found"}"#.to_string())
+        );
+        assert_eq!(
+            error_response(DbError::Invalid("unknown channel: sms".to_string())).await,
+            (StatusCode::UNPROCESSABLE_ENTITY, r#"{"error":"unknown channel: sms"}"#.to_string())
+        );
+    }
+}
@@ -0,0 +1,92 @@
+use std::{collections::HashMap, path::Path, sync::OnceLock};
+
+use anyhow::{bail, Context};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use sha2::{Digest, Sha384};
+
+/// Built by `pnpm release`, see `package.json`.
+pub const ASSETS_PATH: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/assets");
+
+/// The published hash of `htmx.min.js` 1.9.2, the version in `package.json`.
+const HTMX_INTEGRITY: &str = "sha384-L6OqL9pRWyyFU3+/bjdSri+iIphTN/bvYyM37tICVyOJkWZLpP2vGn6VUEXgzg6h";
+
+// Loaded by `base.html`, with subresource integrity. Scripts with a published hash must match it.
+const SCRIPTS: &[(&str, Option<&str>)] = &[("htmx.min.js", Some(HTMX_INTEGRITY)), ("app.js", None)];
+
+static INTEGRITY: OnceLock<HashMap<&'static str, String>> = OnceLock::new();
+
+/// Hashes the scripts, once. Fails when one is missing or isn't the published release.
+pub fn load() -> anyhow::Result<()> {
+    if INTEGRITY.get().is_none() {
+        _ = INTEGRITY.set(hash_scripts(Path::new(ASSETS_PATH), SCRIPTS)?);
+    }
+
+    Ok(())
+}
+
+fn hash_scripts(
+    dir: &Path,
+    scripts: &[(&'static str, Option<&str>)],
+) -> anyhow::Result<HashMap<&'static str, String>> {
+    scripts
+        .iter()
+        .map(|&(script, published)| {
+            let content = std::fs::read(dir.join(script))
+                .with_context(|| format!("Cannot read assets/{}, run `pnpm release`", script))?;
+            let hash = format!("sha384-{}", STANDARD.encode(Sha384::digest(content)));
+
+            match published {
+                Some(published) if hash != published => {
+                    bail!("assets/{} isn't the published release, its hash is {}", script, hash)
+                }
+                _ => Ok((script, hash)),
+            }
+        })
+        .collect()
+}
+
+/// The `integrity` attribute of a script, see `load`.
+pub fn integrity(script: &str) -> &'static str {
+    INTEGRITY
+        .get()
+        .and_then(|hashes| hashes.get(script))
+        .map(String::as_str)
+        .expect("Scripts are hashed at startup")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assets_dir(files: &[(&str, &str)]) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("kountr-assets-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir(&dir).unwrap();
+        for (name, content) in files {
+            std::fs::write(dir.join(name), content).unwrap();
+        }
+        dir
+    }
+
+    #[test]
+    fn hashes_scripts_and_checks_published_ones() {
+        // echo -n 'console.log(1)' | openssl dgst -sha384 -binary | base64
+        let published = "sha384-vuz+yO71bcb30P4dMUNzy6/D2y+6d/n0KcOnt5clJtTBxEDoKAqGay0stFlC8Dpr";
+        let dir = assets_dir(&[("lib.js", "console.log(1)"), ("app.js", "")]);
+
+        let hashes = hash_scripts(&dir, &[("lib.js", Some(published)), ("app.js", None)]).unwrap();
+        assert_eq!(hashes["lib.js"], published);
+        assert_eq!(
+            hashes["app.js"],
+            "sha384-OLBgp1GsljhM2TJ+sbHjaiH9txEUvgdDTAzHv2P24donTt6/529l+9Ua0vFImLlb"
+        );
+
+        std::fs::write(dir.join("lib.js"), "console.log(2)").unwrap();
+        let err = hash_scripts(&dir, &[("lib.js", Some(published))]).unwrap_err();
+        assert!(err.to_string().starts_with("assets/lib.js isn't the published release"), "{}", err);
+
+        let err = hash_scripts(&dir, &[("missing.js", None)]).unwrap_err();
+        assert_eq!(err.to_string(), "Cannot read assets/missing.js, run `pnpm release`");
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
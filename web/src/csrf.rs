@@ -0,0 +1,375 @@
+use std::sync::Arc;
+
+use axum::{
+    async_trait,
+    body::Body,
+    extract::{FromRequestParts, State},
+    http::{header, request::Parts, HeaderMap, Method, Request, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use uuid::Uuid;
+
+use kountr_app::{webhooks::sign, AppOptions};
+
+use crate::handlers::read_body;
+use crate::views::error_response;
+
+const SESSION_COOKIE: &str = "kountr_session";
+const CSRF_HEADER: &str = "X-CSRF-Token";
+const CSRF_FIELD: &str = "csrf_token";
+
+/// Signs the session ids into CSRF tokens, so they don't need to be stored.
+#[derive(Clone)]
+pub struct Csrf {
+    secret: Arc<String>,
+    /// Whether the session cookie is only sent over HTTPS.
+    secure: bool,
+}
+
+impl Csrf {
+    pub fn new(opts: &AppOptions) -> Self {
+        let secret = opts.session_secret.clone().unwrap_or_else(|| {
+            format!("{}{}", Uuid::new_v4().simple(), Uuid::new_v4().simple())
+        });
+
+        Self {
+            secret: Arc::new(secret),
+            secure: opts.tls.is_some(),
+        }
+    }
+
+    fn token(&self, session: &str) -> String {
+        sign(&self.secret, session)
+    }
+
+    fn verify(&self, session: &str, token: &str) -> bool {
+        let expected = self.token(session);
+
+        // Constant time, not to leak how much of the token is right
+        expected.len() == token.len()
+            && expected
+                .bytes()
+                .zip(token.bytes())
+                .fold(0, |diff, (a, b)| diff | (a ^ b))
+                == 0
+    }
+}
+
+/// The CSRF token of the session, to render in pages and forms.
+#[derive(Clone)]
+pub struct CsrfToken(pub String);
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for CsrfToken {
+    type Rejection = (StatusCode, &'static str);
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        parts.extensions.get::<CsrfToken>().cloned().ok_or((
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "CSRF protection is not enabled on this route",
+        ))
+    }
+}
+
+/// Starts a session when there's none yet, and rejects changes without the token of
+/// the session, either in the `X-CSRF-Token` header (HTMX) or the `csrf_token` form field.
+pub async fn protect(State(csrf): State<Csrf>, mut req: Request<Body>, next: Next<Body>) -> Response {
+    let session = session_id(req.headers());
+    let safe = matches!(*req.method(), Method::GET | Method::HEAD | Method::OPTIONS);
+
+    let Some(session) = session else {
+        if !safe {
+            return forbidden(req.headers());
+        }
+
+        let session = Uuid::new_v4().to_string();
+        req.extensions_mut().insert(CsrfToken(csrf.token(&session)));
+
+        let mut cookie = format!(
+            "{}={}; Path=/; HttpOnly; SameSite=Lax",
+            SESSION_COOKIE, session
+        );
+        if csrf.secure {
+            cookie.push_str("; Secure");
+        }
+        return ([(header::SET_COOKIE, cookie)], next.run(req).await).into_response();
+    };
+
+    req.extensions_mut().insert(CsrfToken(csrf.token(&session)));
+    if safe {
+        return next.run(req).await;
+    }
+
+    let header_token = req
+        .headers()
+        .get(CSRF_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string);
+
+    let (token, req) = match header_token {
+        Some(token) => (Some(token), req),
+        None => match form_token(req).await {
+            Ok(found) => found,
+            Err(response) => return response,
+        },
+    };
+
+    match token {
+        Some(token) if csrf.verify(&session, &token) => next.run(req).await,
+        _ => forbidden(req.headers()),
+    }
+}
+
+pub fn session_id(headers: &HeaderMap) -> Option<String> {
+    headers
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .filter_map(|cookie| cookie.trim().split_once('='))
+        .find(|(name, _)| *name == SESSION_COOKIE)
+        .map(|(_, value)| value.to_string())
+        .filter(|value| !value.is_empty())
+}
+
+/// Looks for the token in a form body, then puts the body back for the handler.
+async fn form_token(req: Request<Body>) -> Result<(Option<String>, Request<Body>), Response> {
+    let is_form = req
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with("application/x-www-form-urlencoded"));
+    if !is_form {
+        return Ok((None, req));
+    }
+
+    let (parts, body) = req.into_parts();
+    let bytes = read_body(body).await?;
+
+    let token = form_urlencoded::parse(&bytes)
+        .find(|(name, _)| name == CSRF_FIELD)
+        .map(|(_, value)| value.into_owned());
+
+    Ok((token, Request::from_parts(parts, Body::from(bytes))))
+}
+
+fn forbidden(headers: &HeaderMap) -> Response {
+    let message = "Invalid CSRF token, reload the page and try again";
+    error_response(headers, StatusCode::FORBIDDEN, message)
+}
+
+/// Rejects changes made from pages of other sites, for the JSON API which has no sessions.
+/// Browsers tell where requests come from, other clients (eg. the command line) don't.
+pub async fn same_origin(req: Request<Body>, next: Next<Body>) -> Response {
+    let safe = matches!(*req.method(), Method::GET | Method::HEAD | Method::OPTIONS);
+    if !safe && is_cross_site(req.headers()) {
+        return cross_site_forbidden(req.headers());
+    }
+
+    next.run(req).await
+}
+
+/// Whether a browser sent the request from a page of another site, according to its
+/// `Sec-Fetch-Site` header or else to its `Origin` header, compared to `Host`.
+pub fn is_cross_site(headers: &HeaderMap) -> bool {
+    let value = |name| headers.get(name).and_then(|value| value.to_str().ok());
+
+    if let Some(site) = value(header::HeaderName::from_static("sec-fetch-site")) {
+        return !matches!(site, "same-origin" | "none");
+    }
+    let Some(origin) = value(header::ORIGIN) else {
+        return false;
+    };
+
+    // eg. `null` for sandboxed pages, never the same origin
+    let origin_host = origin.split_once("://").map(|(_, host)| host);
+    match (origin_host, value(header::HOST)) {
+        (Some(origin_host), Some(host)) => !origin_host.eq_ignore_ascii_case(host),
+        _ => true,
+    }
+}
+
+pub fn cross_site_forbidden(headers: &HeaderMap) -> Response {
+    error_response(headers, StatusCode::FORBIDDEN, "Cross-site requests are not allowed")
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{extract::Extension, middleware, routing::post, Router};
+    use tower::ServiceExt;
+
+    use super::*;
+
+    fn csrf() -> Csrf {
+        Csrf {
+            secret: Arc::new("s".repeat(32)),
+            secure: false,
+        }
+    }
+
+    fn headers(pairs: &[(header::HeaderName, &str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.append(name, value.parse().unwrap());
+        }
+        headers
+    }
+
+    async fn body_text(response: Response) -> String {
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        String::from_utf8(body.to_vec()).unwrap()
+    }
+
+    #[test]
+    fn verifies_the_token_of_the_session() {
+        let csrf = csrf();
+        let token = csrf.token("s1");
+
+        assert!(csrf.verify("s1", &token));
+        assert!(!csrf.verify("s2", &token));
+        assert!(!csrf.verify("s1", &token[1..]));
+        assert!(!csrf.verify("s1", ""));
+
+        // Another secret, another token
+        let other = Csrf {
+            secret: Arc::new("t".repeat(32)),
+            secure: false,
+        };
+        assert!(!other.verify("s1", &token));
+    }
+
+    #[test]
+    fn finds_the_session_among_the_cookies() {
+        let sent = headers(&[
+            (header::COOKIE, "theme=dark; kountr_session=s1"),
+            (header::COOKIE, "other=1"),
+        ]);
+        assert_eq!(session_id(&sent).as_deref(), Some("s1"));
+
+        assert_eq!(session_id(&headers(&[(header::COOKIE, "kountr_session=")])), None);
+        assert_eq!(session_id(&headers(&[(header::COOKIE, "kountr_sessions=s1")])), None);
+        assert_eq!(session_id(&HeaderMap::new()), None);
+    }
+
+    #[tokio::test]
+    async fn reads_the_token_of_forms_and_keeps_the_request() {
+        let req = Request::post("/counters")
+            .header(header::CONTENT_TYPE, "application/x-www-form-urlencoded")
+            .extension(CsrfToken("kept".to_string()))
+            .body(Body::from("name=Coffee&csrf_token=t1"))
+            .unwrap();
+
+        let (token, req) = form_token(req).await.unwrap();
+        assert_eq!(token.as_deref(), Some("t1"));
+        assert_eq!(req.extensions().get::<CsrfToken>().unwrap().0, "kept");
+        let body = hyper::body::to_bytes(req.into_body()).await.unwrap();
+        assert_eq!(&body[..], b"name=Coffee&csrf_token=t1");
+
+        // Other bodies aren't read
+        let req = Request::post("/counters").body(Body::from("csrf_token=t1")).unwrap();
+        let (token, req) = form_token(req).await.unwrap();
+        assert_eq!(token, None);
+        let body = hyper::body::to_bytes(req.into_body()).await.unwrap();
+        assert_eq!(&body[..], b"csrf_token=t1");
+    }
+
+    #[tokio::test]
+    async fn protects_changes_of_the_session() {
+        let csrf = csrf();
+        let router = Router::new()
+            .route(
+                "/counters",
+                post(|body: String| async move { body })
+                    .get(|Extension(CsrfToken(token)): Extension<CsrfToken>| async move { token }),
+            )
+            .layer(middleware::from_fn_with_state(csrf.clone(), protect));
+
+        // A session starts on the first page
+        let response = router
+            .clone()
+            .oneshot(Request::get("/counters").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        let cookie = response.headers()[header::SET_COOKIE].to_str().unwrap().to_string();
+        assert!(cookie.ends_with("; Path=/; HttpOnly; SameSite=Lax"), "{}", cookie);
+        let session = cookie.split(';').next().unwrap().to_string();
+        let token = body_text(response).await;
+        assert!(csrf.verify(session.strip_prefix("kountr_session=").unwrap(), &token));
+
+        let change = |token: Option<&str>, form: Option<&str>| {
+            let mut req = Request::post("/counters").header(header::COOKIE, session.as_str());
+            if let Some(token) = token {
+                req = req.header(CSRF_HEADER, token);
+            }
+            let body = match form {
+                Some(form) => {
+                    req = req.header(header::CONTENT_TYPE, "application/x-www-form-urlencoded");
+                    Body::from(form.to_string())
+                }
+                None => Body::empty(),
+            };
+            router.clone().oneshot(req.body(body).unwrap())
+        };
+
+        let response = change(Some(&token), None).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let form = format!("value=1&csrf_token={}", token);
+        let response = change(None, Some(&form)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(body_text(response).await, form);
+
+        for (token, form) in [(None, None), (Some("wrong"), None), (None, Some("csrf_token=wrong"))] {
+            let response = change(token, form).await.unwrap();
+            assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        }
+
+        // Changes without a session are rejected too
+        let req = Request::post("/counters").header(CSRF_HEADER, token).body(Body::empty());
+        let response = router.oneshot(req.unwrap()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[test]
+    fn tells_cross_site_requests_apart() {
+        let host = || (header::HOST, "kountr.example:8080");
+        let fetch_site = |site| (header::HeaderName::from_static("sec-fetch-site"), site);
+
+        // eg. the command line, curl
+        assert!(!is_cross_site(&headers(&[host()])));
+        assert!(!is_cross_site(&headers(&[host(), (header::ORIGIN, "https://kountr.example:8080")])));
+        assert!(!is_cross_site(&headers(&[fetch_site("same-origin")])));
+        assert!(!is_cross_site(&headers(&[fetch_site("none")])));
+
+        assert!(is_cross_site(&headers(&[host(), (header::ORIGIN, "https://evil.example")])));
+        assert!(is_cross_site(&headers(&[host(), (header::ORIGIN, "https://kountr.example")])));
+        assert!(is_cross_site(&headers(&[host(), (header::ORIGIN, "null")])));
+        assert!(is_cross_site(&headers(&[(header::ORIGIN, "https://kountr.example:8080")])));
+        assert!(is_cross_site(&headers(&[fetch_site("cross-site")])));
+        assert!(is_cross_site(&headers(&[fetch_site("same-site"), host()])));
+    }
+
+    #[tokio::test]
+    async fn rejects_api_changes_from_other_sites() {
+        let router = Router::new()
+            .route("/api/v1/counters", post(|| async { "changed" }).get(|| async { "read" }))
+            .layer(middleware::from_fn(same_origin));
+        let request = |method: Method, origin: &str| {
+            Request::builder()
+                .method(method)
+                .uri("/api/v1/counters")
+                .header(header::HOST, "kountr.example")
+                .header(header::ORIGIN, origin)
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        let response = router.clone().oneshot(request(Method::POST, "https://evil.example")).await;
+        assert_eq!(response.unwrap().status(), StatusCode::FORBIDDEN);
+        let response = router.clone().oneshot(request(Method::POST, "https://kountr.example")).await;
+        assert_eq!(response.unwrap().status(), StatusCode::OK);
+        // Reads can't change anything
+        let response = router.oneshot(request(Method::GET, "https://evil.example")).await;
+        assert_eq!(response.unwrap().status(), StatusCode::OK);
+    }
+}
@@ -1,15 +1,99 @@
+use std::{convert::Infallible, net::SocketAddr};
+
 use axum::{
-    extract::{Form, Path, State},
-    http::StatusCode,
-    response::{IntoResponse, Redirect},
+    async_trait,
+    body::{Body, Bytes},
+    extract::{ConnectInfo, Form, FromRequestParts, Path, State},
+    http::{header, request::Parts, Request, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Redirect, Response},
 };
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 
 use kountr_app::domain::models::Counter;
-use kountr_app::AppState;
+use kountr_app::{idempotency::IdempotencyKey, AppState};
 
+use crate::api::ApiError;
+use crate::csrf::{session_id, CsrfToken};
 use crate::views::*;
 
+// ====================== EXTRACTORS ==========================================
+
+pub const IDEMPOTENCY_KEY_HEADER: &str = "Idempotency-Key";
+
+// The same as the default limit of the axum body extractors.
+pub const BODY_LIMIT: usize = 2 * 1024 * 1024;
+
+/// The optional `Idempotency-Key` header, as scoped by `scope_idempotency_key`.
+pub struct IdempotencyKeyHeader(pub Option<IdempotencyKey>);
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for IdempotencyKeyHeader {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        Ok(IdempotencyKeyHeader(parts.extensions.get::<IdempotencyKey>().cloned()))
+    }
+}
+
+/// Scopes the `Idempotency-Key` of a request to its client (its session, its
+/// `Authorization` header or its IP address) and to its method, path and body.
+pub async fn scope_idempotency_key(
+    addr: Option<ConnectInfo<SocketAddr>>,
+    req: Request<Body>,
+    next: Next<Body>,
+) -> Response {
+    let key = req
+        .headers()
+        .get(IDEMPOTENCY_KEY_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .filter(|key| !key.is_empty())
+        .map(str::to_string);
+    let Some(key) = key else {
+        return next.run(req).await;
+    };
+
+    let authorization = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .map(|value| hex::encode(Sha256::digest(value.as_bytes())));
+    let client = match (session_id(req.headers()), authorization, addr) {
+        (Some(session), _, _) => format!("session:{}", session),
+        (None, Some(authorization), _) => format!("authorization:{}", authorization),
+        (None, None, Some(ConnectInfo(addr))) => format!("ip:{}", addr.ip()),
+        (None, None, None) => String::new(),
+    };
+
+    let (mut parts, body) = req.into_parts();
+    let body = match read_body(body).await {
+        Ok(body) => body,
+        Err(response) => return response,
+    };
+
+    let request = format!(
+        "{} {} {}",
+        parts.method,
+        parts.uri.path(),
+        hex::encode(Sha256::digest(&body))
+    );
+    parts
+        .extensions
+        .insert(IdempotencyKey::new(&client, &key, request));
+
+    next.run(Request::from_parts(parts, Body::from(body))).await
+}
+
+/// Reads a whole body up to `BODY_LIMIT`, eg. for middlewares that need to look into it.
+pub async fn read_body(body: Body) -> Result<Bytes, Response> {
+    hyper::body::to_bytes(http_body::Limited::new(body, BODY_LIMIT))
+        .await
+        .map_err(|err| match err.downcast_ref::<http_body::LengthLimitError>() {
+            Some(_) => (StatusCode::PAYLOAD_TOO_LARGE, "Request body is too large").into_response(),
+            None => (StatusCode::BAD_REQUEST, "Cannot read the request body").into_response(),
+        })
+}
+
 // ====================== PARAMS ==============================================
 #[derive(Serialize, Deserialize)]
 pub struct NewCounterParams {
@@ -38,117 +122,223 @@ impl Into<Counter> for UpdateCounterParams {
 
 #[derive(Serialize, Deserialize)]
 pub struct CounterIdParams {
-    id: String,
+    pub id: String,
 }
 
 // ====================== HANDLERS ============================================
 
-pub async fn home(_state: State<AppState>) -> impl IntoResponse {
-    HtmlView(HomeView {})
+pub async fn home(_state: State<AppState>, CsrfToken(csrf_token): CsrfToken) -> impl IntoResponse {
+    HtmlView(HomeView { csrf_token })
 }
 
-pub async fn dashboard() -> impl IntoResponse {
-    HtmlView(DashboardView {})
+pub async fn dashboard(CsrfToken(csrf_token): CsrfToken) -> impl IntoResponse {
+    HtmlView(DashboardView { csrf_token })
 }
 
-pub async fn list_counters(state: State<AppState>) -> impl IntoResponse {
-    let counters = kountr_app::list_all_counters(&state).await.unwrap();
+pub async fn list_counters(
+    state: State<AppState>,
+    CsrfToken(csrf_token): CsrfToken,
+) -> Result<impl IntoResponse, ApiError> {
+    let counters = kountr_app::list_all_counters(&state).await?;
+
+    Ok(HtmlView(ListCountersView {
+        csrf_token,
+        counters: counters.into_iter().map(CounterView::new).collect(),
+    }))
+}
+
+pub async fn export_counters(state: State<AppState>) -> Result<impl IntoResponse, ApiError> {
+    let chunks = kountr_app::export_counters_csv(&state).await?;
+
+    Ok(CsvView {
+        filename: "counters.csv".to_string(),
+        chunks,
+    })
+}
+
+pub async fn export_counter_series(
+    Path(id): Path<String>,
+    state: State<AppState>,
+) -> Result<impl IntoResponse, ApiError> {
+    let chunks = kountr_app::export_counter_series_csv(&state, id.clone()).await?;
 
-    HtmlView(ListCountersView {
-        counters: counters
-            .iter()
-            .map(move |c| CounterView {
-                counter: c.clone(),
-                id: c.clone().id,
-                name: c.clone().name,
-                value: c.clone().value,
-            })
-            .collect::<Vec<CounterView>>(),
+    Ok(CsvView {
+        filename: format!("counter-{}-series.csv", id),
+        chunks,
     })
 }
 
-pub async fn new_counter() -> impl IntoResponse {
-    HtmlView(NewCounterView {})
+pub async fn new_counter(CsrfToken(csrf_token): CsrfToken) -> impl IntoResponse {
+    HtmlView(NewCounterView { csrf_token })
 }
 
 pub async fn add_counter(
-    mut state: State<AppState>,
+    state: State<AppState>,
+    IdempotencyKeyHeader(key): IdempotencyKeyHeader,
     Form(form): Form<NewCounterParams>,
-) -> impl IntoResponse {
-    kountr_app::add_counter(&mut state, form.into())
-        .await
-        .expect("Cannot create counter");
+) -> Result<impl IntoResponse, ApiError> {
+    let command = kountr_app::add_counter(&state, form.into());
+    kountr_app::idempotent(&state, key.as_ref(), command).await?;
 
-    Redirect::to("/counters")
+    Ok(Redirect::to("/counters"))
 }
 
-pub async fn edit_counter(Path(id): Path<String>, state: State<AppState>) -> impl IntoResponse {
-    let counter = kountr_app::find_counter(&state, id)
-        .await
-        .expect("Cannot find counter");
+pub async fn edit_counter(
+    Path(id): Path<String>,
+    state: State<AppState>,
+    CsrfToken(csrf_token): CsrfToken,
+) -> Result<impl IntoResponse, ApiError> {
+    let counter = kountr_app::find_counter(&state, id).await?;
 
-    HtmlView(EditCounterView {
+    Ok(HtmlView(EditCounterView {
+        csrf_token,
         id: counter.id,
         name: counter.name,
         value: counter.value,
-    })
+    }))
 }
 
 pub async fn update_counter(
     state: State<AppState>,
+    IdempotencyKeyHeader(key): IdempotencyKeyHeader,
     Form(form): Form<UpdateCounterParams>,
-) -> impl IntoResponse {
-    kountr_app::update_counter(&state, form.into())
-        .await
-        .expect("Cannot update counter");
+) -> Result<impl IntoResponse, ApiError> {
+    let command = kountr_app::update_counter(&state, form.into());
+    kountr_app::idempotent(&state, key.as_ref(), command).await?;
 
-    (
+    Ok((
         StatusCode::SEE_OTHER,
         [("HX-Redirect", "/counters")],
         "updated",
-    )
+    ))
 }
 
-pub async fn delete_counter(Path(id): Path<String>, state: State<AppState>) -> impl IntoResponse {
-    kountr_app::delete_counter(&state, id)
-        .await
-        .expect("Cannot delete counter");
+pub async fn delete_counter(
+    Path(id): Path<String>,
+    state: State<AppState>,
+    IdempotencyKeyHeader(key): IdempotencyKeyHeader,
+) -> Result<impl IntoResponse, ApiError> {
+    let command = kountr_app::delete_counter(&state, id);
+    kountr_app::idempotent(&state, key.as_ref(), command).await?;
 
-    (
+    Ok((
         StatusCode::SEE_OTHER,
         [("HX-Redirect", "/counters")],
         "deleted",
-    )
+    ))
 }
 
 pub async fn increment_counter(
     Path(id): Path<String>,
     state: State<AppState>,
-) -> impl IntoResponse {
-    let counter = kountr_app::increment_counter(&state, id)
-        .await
-        .expect("Cannot increment counter");
+    IdempotencyKeyHeader(key): IdempotencyKeyHeader,
+) -> Result<impl IntoResponse, ApiError> {
+    let command = kountr_app::increment_counter(&state, id);
+    let counter = kountr_app::idempotent(&state, key.as_ref(), command).await?;
 
-    HtmlView(CounterView {
-        counter: counter.clone(),
-        id: counter.id,
-        name: counter.name,
-        value: counter.value,
-    })
+    Ok(HtmlView(CounterView::new(counter)))
 }
 
 pub async fn decrement_counter(
     state: State<AppState>,
     Path(id): Path<String>,
-) -> impl IntoResponse {
-    let counter = kountr_app::decrement_counter(&state, id)
-        .await
-        .expect("Cannot increment counter");
+    IdempotencyKeyHeader(key): IdempotencyKeyHeader,
+) -> Result<impl IntoResponse, ApiError> {
+    let command = kountr_app::decrement_counter(&state, id);
+    let counter = kountr_app::idempotent(&state, key.as_ref(), command).await?;
 
-    HtmlView(CounterView {
-        counter: counter.clone(),
-        id: counter.id,
-        name: counter.name,
-        value: counter.value,
-    })
+    Ok(HtmlView(CounterView::new(counter)))
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{middleware, routing::post, Router};
+    use tower::ServiceExt;
+
+    use super::*;
+
+    /// Replies with the scoped key and request, or nothing without a key.
+    async fn scoped(cookie: Option<&str>, key: Option<&str>, body: Vec<u8>) -> (StatusCode, String) {
+        let router = Router::new()
+            .route(
+                "/counters",
+                post(|IdempotencyKeyHeader(key): IdempotencyKeyHeader, body: String| async move {
+                    match key {
+                        Some(key) => format!("{} {} / {}", key.key, key.request, body),
+                        None => format!("/ {}", body),
+                    }
+                }),
+            )
+            .layer(middleware::from_fn(scope_idempotency_key));
+
+        let mut req = Request::post("/counters");
+        if let Some(cookie) = cookie {
+            req = req.header(header::COOKIE, cookie);
+        }
+        if let Some(key) = key {
+            req = req.header(IDEMPOTENCY_KEY_HEADER, key);
+        }
+
+        let response = router.oneshot(req.body(Body::from(body)).unwrap()).await.unwrap();
+        let status = response.status();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+
+        (status, String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    #[tokio::test]
+    async fn scopes_keys_to_the_request_body() {
+        let session = Some("kountr_session=s1");
+
+        let (status, first) = scoped(session, Some("k1"), b"value=1".to_vec()).await;
+        assert_eq!(status, StatusCode::OK);
+        // The body is still there for the handler
+        assert!(first.ends_with(" / value=1"), "{}", first);
+
+        let (_, again) = scoped(session, Some("k1"), b"value=1".to_vec()).await;
+        assert_eq!(again, first);
+
+        let (_, other_body) = scoped(session, Some("k1"), b"value=2".to_vec()).await;
+        let key = |reply: &str| reply.split(' ').next().unwrap().to_string();
+        let request = |reply: &str| reply.split(" / ").next().unwrap().to_string();
+        assert_eq!(key(&other_body), key(&first));
+        assert_ne!(request(&other_body), request(&first));
+        assert!(request(&first).contains("POST /counters "), "{}", first);
+    }
+
+    #[tokio::test]
+    async fn scopes_keys_to_the_client() {
+        let key = |reply: String| reply.split(' ').next().unwrap().to_string();
+
+        let (_, first) = scoped(Some("kountr_session=s1"), Some("k1"), vec![]).await;
+        let (_, other) = scoped(Some("kountr_session=s2"), Some("k1"), vec![]).await;
+        let (_, anonymous) = scoped(None, Some("k1"), vec![]).await;
+
+        assert_ne!(key(first.clone()), key(other));
+        assert_ne!(key(first), key(anonymous));
+    }
+
+    #[tokio::test]
+    async fn ignores_requests_without_a_key() {
+        assert_eq!(scoped(None, None, b"value=1".to_vec()).await.1, "/ value=1");
+        assert_eq!(scoped(None, Some(""), vec![]).await.1, "/ ");
+    }
+
+    #[tokio::test]
+    async fn rejects_bodies_over_the_limit() {
+        let (status, _) = scoped(None, Some("k1"), vec![b'a'; BODY_LIMIT + 1]).await;
+
+        assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+    }
+
+    #[tokio::test]
+    async fn cannot_edit_unknown_counters() {
+        let state = State(kountr_app::init_in_memory_app());
+        let csrf_token = CsrfToken("token".to_string());
+
+        let response = edit_counter(Path(uuid::Uuid::new_v4().to_string()), state, csrf_token)
+            .await
+            .into_response();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
 }
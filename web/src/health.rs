@@ -0,0 +1,83 @@
+use axum::{extract::State, http::StatusCode, response::IntoResponse, Json};
+use serde::Serialize;
+use serde_json::{json, Value};
+
+use kountr_app::domain::models::Readiness;
+use kountr_app::{AppState, DbError};
+
+#[derive(Serialize)]
+pub struct Version {
+    name: &'static str,
+    version: &'static str,
+    /// Empty when built outside of a git checkout.
+    commit: &'static str,
+    database: &'static str,
+    profile: &'static str,
+}
+
+const DATABASE: &str = if cfg!(feature = "postgres") {
+    "postgres"
+} else if cfg!(feature = "mysql") {
+    "mysql"
+} else {
+    "sqlite"
+};
+
+/// Liveness: the server is up and answering.
+pub async fn healthz() -> impl IntoResponse {
+    Json(json!({ "status": "ok" }))
+}
+
+/// Readiness: 503 until the database can be reached and is up to date.
+pub async fn readyz(state: State<AppState>) -> impl IntoResponse {
+    readiness_response(kountr_app::check_readiness(&state).await)
+}
+
+fn readiness_response(readiness: Result<Readiness, DbError>) -> (StatusCode, Json<Value>) {
+    match readiness {
+        Ok(readiness) if readiness.ready => (StatusCode::OK, Json(json!(readiness))),
+        Ok(readiness) => (StatusCode::SERVICE_UNAVAILABLE, Json(json!(readiness))),
+        Err(err) => (
+            StatusCode::SERVICE_UNAVAILABLE,
+            Json(json!({ "ready": false, "error": err.to_string() })),
+        ),
+    }
+}
+
+pub async fn version() -> impl IntoResponse {
+    Json(Version {
+        name: "kountr",
+        version: env!("CARGO_PKG_VERSION"),
+        commit: env!("KOUNTR_GIT_COMMIT"),
+        database: DATABASE,
+        profile: if cfg!(debug_assertions) { "debug" } else { "release" },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn is_unavailable_until_the_database_is_migrated() {
+        let (status, Json(body)) = readiness_response(Ok(Readiness {
+            ready: false,
+            pending_migrations: 2,
+            projection_lag: 0,
+        }));
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(body["pending_migrations"], 2);
+
+        let (status, Json(body)) = readiness_response(Err(DbError::NotFound));
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(body["ready"], false);
+    }
+
+    #[tokio::test]
+    async fn is_ready_in_memory() {
+        let (status, Json(body)) =
+            readiness_response(kountr_app::check_readiness(&kountr_app::init_in_memory_app()).await);
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["projection_lag"], 0);
+    }
+}
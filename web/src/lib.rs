@@ -1,6 +1,15 @@
+mod api;
+mod assets;
+mod csrf;
+mod health;
+mod metrics;
+mod rate_limit;
+mod security;
 mod server;
+mod tls;
 mod handlers;
 mod views;
+mod ws;
 
 pub use server::*;
 pub use kountr_app::AppOptions;
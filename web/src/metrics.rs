@@ -0,0 +1,66 @@
+use std::{sync::OnceLock, time::Instant};
+
+use axum::{
+    extract::{MatchedPath, State},
+    http::Request,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use metrics_exporter_prometheus::{Matcher, PrometheusBuilder, PrometheusHandle};
+
+use kountr_app::AppState;
+
+// Latency buckets, in seconds.
+const DURATION_BUCKETS: &[f64] = &[
+    0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+static RECORDER: OnceLock<PrometheusHandle> = OnceLock::new();
+
+/// Installs the global metrics recorder, once: the app records its metrics through the `metrics` crate.
+pub fn install() {
+    RECORDER.get_or_init(|| {
+        PrometheusBuilder::new()
+            .set_buckets_for_metric(
+                Matcher::Suffix("duration_seconds".to_string()),
+                DURATION_BUCKETS,
+            )
+            .expect("Invalid metrics buckets")
+            .install_recorder()
+            .expect("Cannot install the metrics recorder")
+    });
+}
+
+/// Counts and times requests, by method, route pattern (not the actual path, to keep
+/// ids out of the labels) and status.
+pub async fn track_requests<B>(req: Request<B>, next: Next<B>) -> Response {
+    let started = Instant::now();
+    let method = req.method().to_string();
+    let route = req
+        .extensions()
+        .get::<MatchedPath>()
+        .map_or("unmatched".to_string(), |path| path.as_str().to_string());
+
+    let response = next.run(req).await;
+
+    let labels = [
+        ("method", method),
+        ("route", route),
+        ("status", response.status().as_u16().to_string()),
+    ];
+    metrics::increment_counter!("kountr_http_requests_total", &labels);
+    metrics::histogram!("kountr_http_request_duration_seconds", started.elapsed(), &labels);
+
+    response
+}
+
+/// The metrics in the Prometheus text format, see `install`.
+pub async fn render(state: State<AppState>) -> impl IntoResponse {
+    if let Some(repo) = &state.repo {
+        let (size, idle) = repo.pool_status();
+        metrics::gauge!("kountr_db_pool_connections", size as f64);
+        metrics::gauge!("kountr_db_pool_idle_connections", idle as f64);
+    }
+
+    RECORDER.get().map(PrometheusHandle::render).unwrap_or_default()
+}
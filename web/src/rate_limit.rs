@@ -0,0 +1,249 @@
+use std::{
+    net::SocketAddr,
+    num::NonZeroU32,
+    sync::Arc,
+    time::Duration,
+};
+
+use axum::{
+    extract::{ConnectInfo, Path, State},
+    http::{header, HeaderMap, Method, Request, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use governor::{clock::{Clock, DefaultClock}, DefaultKeyedRateLimiter, Quota};
+
+use kountr_app::RateLimitOptions;
+
+use crate::handlers::CounterIdParams;
+use crate::views::error_response;
+
+// How often keys of buckets that are full again are forgotten.
+const CLEANUP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Token buckets for changes, per client and per counter.
+#[derive(Clone)]
+pub struct RateLimits {
+    clients: Option<Arc<DefaultKeyedRateLimiter<String>>>,
+    counters: Option<Arc<DefaultKeyedRateLimiter<String>>>,
+    trust_forwarded_for: bool,
+}
+
+impl RateLimits {
+    /// Also starts forgetting idle clients and counters in background.
+    pub fn new(opts: &RateLimitOptions) -> Self {
+        let limits = Self {
+            clients: limiter(opts.per_client),
+            counters: limiter(opts.per_counter),
+            trust_forwarded_for: opts.trust_forwarded_for,
+        };
+
+        let limiters: Vec<_> = [&limits.clients, &limits.counters]
+            .into_iter()
+            .flatten()
+            .map(Arc::downgrade)
+            .collect();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(CLEANUP_INTERVAL);
+            loop {
+                interval.tick().await;
+                for limiter in limiters.iter().filter_map(|limiter| limiter.upgrade()) {
+                    limiter.retain_recent();
+                    limiter.shrink_to_fit();
+                }
+            }
+        });
+
+        limits
+    }
+
+    /// The limits of a single client, eg. for the changes made over its websocket.
+    pub fn for_client(&self, headers: &HeaderMap, addr: Option<SocketAddr>) -> ClientLimits {
+        ClientLimits {
+            limits: self.clone(),
+            client: self.client_key(headers, addr),
+        }
+    }
+
+    /// Identifies clients by their IP address, or by the first address of `X-Forwarded-For`
+    /// when the proxy in front is trusted. There's no authentication, so anything else a
+    /// client sends (eg. a token) could be changed at will to get a new bucket.
+    fn client_key(&self, headers: &HeaderMap, addr: Option<SocketAddr>) -> Option<String> {
+        let forwarded = headers
+            .get("x-forwarded-for")
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.split(',').next())
+            .map(str::trim)
+            .filter(|ip| self.trust_forwarded_for && !ip.is_empty());
+
+        match forwarded {
+            Some(ip) => Some(format!("ip:{}", ip)),
+            None => addr.map(|addr| format!("ip:{}", addr.ip())),
+        }
+    }
+
+    /// Takes a token from the buckets of the client and of the counter, or tells how long
+    /// to wait for one.
+    fn check(&self, client: Option<&String>, counter: Option<&String>) -> Result<(), Duration> {
+        let checks = [
+            ("client", self.clients.as_ref().zip(client)),
+            ("counter", self.counters.as_ref().zip(counter)),
+        ];
+
+        for (name, check) in checks {
+            let Some((limiter, key)) = check else {
+                continue;
+            };
+
+            if let Err(not_until) = limiter.check_key(key) {
+                metrics::increment_counter!("kountr_rate_limited_total", "limit" => name);
+                return Err(not_until.wait_time_from(DefaultClock::default().now()));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// `RateLimits` applied to one client, see `RateLimits::for_client`.
+#[derive(Clone)]
+pub struct ClientLimits {
+    limits: RateLimits,
+    client: Option<String>,
+}
+
+impl ClientLimits {
+    /// Takes a token for a change to the counter, or tells how long to wait for one.
+    pub fn check(&self, counter: &str) -> Result<(), Duration> {
+        self.limits.check(self.client.as_ref(), Some(&counter.to_string()))
+    }
+}
+
+fn limiter(per_minute: u32) -> Option<Arc<DefaultKeyedRateLimiter<String>>> {
+    let quota = Quota::per_minute(NonZeroU32::new(per_minute)?);
+
+    Some(Arc::new(DefaultKeyedRateLimiter::keyed(quota)))
+}
+
+/// Rejects changes with 429 once a client or a counter runs out of tokens. Reads go through.
+pub async fn limit_changes<B>(
+    State(limits): State<RateLimits>,
+    addr: Option<ConnectInfo<SocketAddr>>,
+    counter: Option<Path<CounterIdParams>>,
+    req: Request<B>,
+    next: Next<B>,
+) -> Response {
+    if matches!(*req.method(), Method::GET | Method::HEAD) {
+        return next.run(req).await;
+    }
+
+    let client = limits.client_key(req.headers(), addr.map(|ConnectInfo(addr)| addr));
+    let counter = counter.map(|Path(params)| params.id);
+    if let Err(wait) = limits.check(client.as_ref(), counter.as_ref()) {
+        return too_many_requests(req.headers(), wait);
+    }
+
+    next.run(req).await
+}
+
+/// The message for clients out of tokens.
+pub fn too_many_changes(wait: Duration) -> String {
+    format!("Too many changes, try again in {} seconds", retry_after(wait))
+}
+
+/// Whole seconds, rounded up.
+fn retry_after(wait: Duration) -> u64 {
+    (wait.as_millis() as u64).div_ceil(1000).max(1)
+}
+
+fn too_many_requests(headers: &HeaderMap, wait: Duration) -> Response {
+    let retry_after = [(header::RETRY_AFTER, retry_after(wait).to_string())];
+
+    (retry_after, error_response(headers, StatusCode::TOO_MANY_REQUESTS, &too_many_changes(wait))).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limits(per_client: u32, per_counter: u32, trust_forwarded_for: bool) -> RateLimits {
+        RateLimits::new(&RateLimitOptions {
+            per_client,
+            per_counter,
+            trust_forwarded_for,
+        })
+    }
+
+    fn headers(pairs: &[(&'static str, &str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.insert(*name, value.parse().unwrap());
+        }
+        headers
+    }
+
+    #[tokio::test]
+    async fn identifies_clients_by_their_address() {
+        let addr = Some("10.0.0.1:4321".parse().unwrap());
+        let limits = limits(1, 1, false);
+
+        assert_eq!(limits.client_key(&HeaderMap::new(), addr).as_deref(), Some("ip:10.0.0.1"));
+        assert_eq!(limits.client_key(&HeaderMap::new(), None), None);
+
+        // Clients can send any token or forwarded address they like
+        let sent = headers(&[
+            ("authorization", "Bearer fresh-token"),
+            ("x-forwarded-for", "192.0.2.7"),
+        ]);
+        assert_eq!(limits.client_key(&sent, addr).as_deref(), Some("ip:10.0.0.1"));
+    }
+
+    #[tokio::test]
+    async fn trusts_the_first_forwarded_address_behind_a_proxy() {
+        let addr = Some("10.0.0.1:4321".parse().unwrap());
+        let limits = limits(1, 1, true);
+
+        let forwarded = headers(&[("x-forwarded-for", " 192.0.2.7 , 10.0.0.1")]);
+        assert_eq!(limits.client_key(&forwarded, addr).as_deref(), Some("ip:192.0.2.7"));
+
+        let empty = headers(&[("x-forwarded-for", "")]);
+        assert_eq!(limits.client_key(&empty, addr).as_deref(), Some("ip:10.0.0.1"));
+    }
+
+    #[tokio::test]
+    async fn limits_clients_and_counters() {
+        let limits = limits(2, 1, false);
+        let first = limits.for_client(&HeaderMap::new(), Some("10.0.0.1:4321".parse().unwrap()));
+        let second = limits.for_client(&HeaderMap::new(), Some("10.0.0.2:4321".parse().unwrap()));
+
+        assert!(first.check("a").is_ok());
+        // The counter is out of tokens, whoever changes it
+        assert!(second.check("a").is_err());
+        assert!(first.check("b").is_ok());
+        // So is the first client
+        let wait = first.check("c").unwrap_err();
+        assert!(wait > Duration::from_secs(20) && wait <= Duration::from_secs(30), "{:?}", wait);
+    }
+
+    #[tokio::test]
+    async fn rejects_with_the_time_to_wait() {
+        let response = too_many_requests(&HeaderMap::new(), Duration::from_millis(1500));
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers()[header::RETRY_AFTER], "2");
+        assert!(!response.headers().contains_key("hx-retarget"));
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(
+            serde_json::from_slice::<serde_json::Value>(&body).unwrap(),
+            serde_json::json!({"error": "too many changes, try again in 2 seconds"})
+        );
+
+        // Shown on the page for HTMX requests, never less than a second
+        let response = too_many_requests(&headers(&[("hx-request", "true")]), Duration::ZERO);
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers()[header::RETRY_AFTER], "1");
+        assert_eq!(response.headers()["hx-retarget"], "#flash");
+        assert_eq!(response.headers()["hx-reswap"], "innerHTML");
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert!(String::from_utf8(body.to_vec()).unwrap().contains("try again in 1 seconds"));
+    }
+}
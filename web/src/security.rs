@@ -0,0 +1,64 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::State,
+    http::{header, HeaderName, HeaderValue, Request},
+    middleware::Next,
+    response::Response,
+};
+
+use kountr_app::AppOptions;
+
+// Scripts and styles only from `/assets`, no inline ones, and no framing.
+const CONTENT_SECURITY_POLICY: &str = "default-src 'self'; img-src 'self' data:; object-src 'none'; \
+    base-uri 'self'; form-action 'self'; frame-ancestors 'none'";
+
+/// Headers added to every response, unless the handler already set them.
+#[derive(Clone)]
+pub struct SecurityHeaders {
+    headers: Arc<Vec<(HeaderName, HeaderValue)>>,
+}
+
+impl SecurityHeaders {
+    pub fn new(opts: &AppOptions) -> Self {
+        let mut headers = vec![
+            (
+                header::CONTENT_SECURITY_POLICY,
+                HeaderValue::from_static(CONTENT_SECURITY_POLICY),
+            ),
+            (header::X_FRAME_OPTIONS, HeaderValue::from_static("DENY")),
+            (header::REFERRER_POLICY, HeaderValue::from_static("same-origin")),
+            (header::X_CONTENT_TYPE_OPTIONS, HeaderValue::from_static("nosniff")),
+        ];
+
+        // Off by default: browsers remember it, so it's only for sites that stay on HTTPS
+        if opts.hsts_max_age > 0 {
+            let hsts = format!("max-age={}", opts.hsts_max_age);
+            headers.push((
+                header::STRICT_TRANSPORT_SECURITY,
+                HeaderValue::from_str(&hsts).expect("Invalid HSTS header"),
+            ));
+        }
+
+        Self {
+            headers: Arc::new(headers),
+        }
+    }
+}
+
+pub async fn set_headers<B>(
+    State(security): State<SecurityHeaders>,
+    req: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let mut response = next.run(req).await;
+
+    for (name, value) in security.headers.iter() {
+        response
+            .headers_mut()
+            .entry(name)
+            .or_insert_with(|| value.clone());
+    }
+
+    response
+}
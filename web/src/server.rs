@@ -1,57 +1,400 @@
-use std::{env, net::SocketAddr, str::FromStr};
+use std::{future::Future, net::SocketAddr, time::Duration};
 
 use anyhow::Context;
 use axum::{
-    routing::{get, put},
-    Router,
+    body::Body,
+    http::Request,
+    middleware,
+    routing::{delete, get, post, put},
+    Extension, Router,
 };
 use tower_http::{
+    request_id::{MakeRequestUuid, PropagateRequestIdLayer, SetRequestIdLayer},
     services::ServeDir,
     trace::{self, TraceLayer},
 };
-use tracing::info;
+use axum_server::Handle;
+use tokio::{signal, sync::oneshot};
+use opentelemetry_http::HeaderExtractor;
+use tracing::{info, warn, Span};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
 
 use kountr_app::{init_app, AppOptions, AppState};
 
+use crate::api;
+use crate::assets::{self, ASSETS_PATH};
+use crate::csrf::{self, Csrf};
+use crate::health;
+use crate::metrics;
+use crate::rate_limit::{self, RateLimits};
+use crate::security::{self, SecurityHeaders};
+use crate::tls;
 use crate::handlers::*;
+use crate::ws::{counters_socket, Sockets};
 
 pub struct Server;
 
 impl Server {
     pub async fn start(opts: &AppOptions) -> anyhow::Result<()> {
-        let state = init_app(&opts).await;
+        assets::load()?;
+        let state = init_app(opts).await.context("Database setup failed")?;
 
-        let router = init_router(&opts, &state);
-        let server_url = format!("{}:{}", opts.host, opts.port);
-        let addr = SocketAddr::from_str(&server_url).unwrap();
+        let sockets = Sockets::default();
+        let router = init_router(opts, &state, &sockets);
+        let host = opts.host.parse().context("Invalid host")?;
+        let addr = SocketAddr::new(host, opts.port);
 
-        info!("Starting server listening on http://{}", server_url);
-        axum::Server::bind(&addr)
-            .serve(router.into_make_service())
-            .await
-            .context("Error while starting server")?;
+        let handle = Handle::new();
+        let redirect = Handle::new();
+        let (stopping, stopped) = oneshot::channel();
+        tokio::spawn({
+            let (handle, redirect) = (handle.clone(), redirect.clone());
+            async move {
+                shutdown_signal().await;
+                info!("Shutting down, waiting for requests in progress");
+                handle.graceful_shutdown(None);
+                redirect.graceful_shutdown(None);
+                _ = stopping.send(());
+            }
+        });
+
+        let service = router.into_make_service_with_connect_info::<SocketAddr>();
+        let server = async {
+            match &opts.tls {
+                Some(tls) => {
+                    let config = tls::load(tls).await?;
+                    if let Some(port) = tls.redirect_http_port {
+                        tls::redirect_to_https(SocketAddr::new(host, port), opts.port, redirect);
+                    }
+
+                    info!("Starting server listening on https://{}", addr);
+                    axum_server::bind_rustls(addr, config)
+                        .handle(handle)
+                        .serve(service)
+                        .await
+                }
+                None => {
+                    info!("Starting server listening on http://{}", addr);
+                    axum_server::bind(addr).handle(handle).serve(service).await
+                }
+            }
+            .context("Error while starting server")
+        };
+
+        let drain = async {
+            server.await?;
+            // Websockets outlive the server, and still run commands
+            sockets.close().await;
+            kountr_app::shutdown(state)
+                .await
+                .context("Error while closing the database")
+        };
+        drain_until_deadline(drain, stopped, opts.shutdown_timeout).await?;
 
+        info!("Server stopped");
         Ok(())
     }
 }
 
-fn init_router(opts: &AppOptions, state: &AppState) -> Router {
-    let assets_path = concat!(env!("CARGO_MANIFEST_DIR"), "/assets");
+/// Waits for `drain`, at most `timeout` from the shutdown signal.
+/// Without a signal, there is no deadline.
+async fn drain_until_deadline(
+    drain: impl Future<Output = anyhow::Result<()>>,
+    stopped: oneshot::Receiver<()>,
+    timeout: Duration,
+) -> anyhow::Result<()> {
+    // Counted from the signal, for requests and background work together
+    let deadline = async {
+        match stopped.await {
+            Ok(()) => tokio::time::sleep(timeout).await,
+            Err(_) => std::future::pending().await,
+        }
+    };
 
-    let http_tracing_layer = TraceLayer::new_for_http()
-        .make_span_with(trace::DefaultMakeSpan::new().level(opts.log_level))
-        .on_response(trace::DefaultOnResponse::new().level(opts.log_level));
+    tokio::select! {
+        result = drain => result,
+        _ = deadline => {
+            warn!("Shutdown timed out after {}s, exiting anyway", timeout.as_secs());
+            Ok(())
+        }
+    }
+}
 
-    Router::new()
+/// Continues the trace of the caller, when it sends a `traceparent` header.
+/// The request id is either the caller's `X-Request-Id`, or a new one.
+fn request_span(req: &Request<Body>) -> Span {
+    let request_id = req
+        .headers()
+        .get("x-request-id")
+        .and_then(|id| id.to_str().ok())
+        .unwrap_or_default();
+
+    let span = tracing::info_span!(
+        "request",
+        method = %req.method(),
+        uri = %req.uri(),
+        version = ?req.version(),
+        request_id,
+    );
+
+    let parent = opentelemetry::global::get_text_map_propagator(|propagator| {
+        propagator.extract(&HeaderExtractor(req.headers()))
+    });
+    span.set_parent(parent);
+
+    span
+}
+
+/// Resolves on Ctrl+C or, on Unix, SIGTERM.
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        signal::ctrl_c().await.expect("Cannot listen for Ctrl+C");
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        signal::unix::signal(signal::unix::SignalKind::terminate())
+            .expect("Cannot listen for SIGTERM")
+            .recv()
+            .await;
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+}
+
+fn init_router(opts: &AppOptions, state: &AppState, sockets: &Sockets) -> Router {
+    metrics::install();
+
+    let limits = RateLimits::new(&opts.rate_limit);
+    let limited = middleware::from_fn_with_state(limits.clone(), rate_limit::limit_changes);
+
+    // Pages get sessions, and their changes need the CSRF token of the session
+    let pages = Router::new()
         .route("/", get(home))
         .route("/dashboard", get(dashboard))
-        .route("/counters", get(list_counters).post(add_counter))
+        .route(
+            "/counters",
+            get(list_counters)
+                .post(add_counter)
+                .route_layer(limited.clone()),
+        )
         .route("/counters/new", get(new_counter))
+        .route("/counters/export.csv", get(export_counters))
+        .route("/counters/:id/series.csv", get(export_counter_series))
         .route("/counters/:id/edit", get(edit_counter))
-        .route("/counters/:id/up", put(increment_counter))
-        .route("/counters/:id/down", put(decrement_counter))
+        .route(
+            "/counters/:id/up",
+            put(increment_counter).route_layer(limited.clone()),
+        )
+        .route(
+            "/counters/:id/down",
+            put(decrement_counter).route_layer(limited.clone()),
+        )
         .route("/counters/:id", put(update_counter).delete(delete_counter))
-        .nest_service("/assets", ServeDir::new(assets_path))
+        .layer(middleware::from_fn_with_state(Csrf::new(opts), csrf::protect));
+
+    // The API has no sessions, but its changes still can't come from pages of other sites
+    let api = Router::new()
+        .route(
+            "/api/v1/counters",
+            get(api::list_counters)
+                .post(api::add_counter)
+                .route_layer(limited.clone()),
+        )
+        .route(
+            "/api/v1/counters/:id",
+            get(api::find_counter)
+                .put(api::update_counter)
+                .delete(api::delete_counter),
+        )
+        .route(
+            "/api/v1/counters/:id/increment",
+            post(api::increment_counter).route_layer(limited.clone()),
+        )
+        .route(
+            "/api/v1/counters/:id/decrement",
+            post(api::decrement_counter).route_layer(limited),
+        )
+        .route(
+            "/api/v1/webhooks",
+            get(api::list_webhooks).post(api::add_webhook),
+        )
+        .route("/api/v1/webhooks/:id", delete(api::delete_webhook))
+        .route(
+            "/api/v1/webhooks/:id/deliveries",
+            get(api::list_webhook_deliveries),
+        )
+        .route(
+            "/api/v1/alert_rules",
+            get(api::list_alert_rules).post(api::add_alert_rule),
+        )
+        .route("/api/v1/alert_rules/:id", delete(api::delete_alert_rule))
+        .route("/api/v1/alerts", get(api::list_alerts))
+        .route("/api/v1/notifications", get(api::list_notifications))
+        .route(
+            "/api/v1/notifications/:id/read",
+            put(api::mark_notification_read),
+        )
+        .route("/api/v1/events", get(api::list_events))
+        .layer(middleware::from_fn(csrf::same_origin));
+
+    let http_tracing_layer = TraceLayer::new_for_http()
+        .make_span_with(request_span)
+        .on_response(trace::DefaultOnResponse::new().level(opts.log_level));
+
+    Router::new()
+        .merge(pages)
+        .merge(api)
+        .route("/healthz", get(health::healthz))
+        .route("/readyz", get(health::readyz))
+        .route("/version", get(health::version))
+        .route("/metrics", get(metrics::render))
+        .route(
+            "/ws/counters",
+            get(counters_socket)
+                .route_layer(Extension(sockets.clone()))
+                .route_layer(Extension(limits)),
+        )
+        .nest_service("/assets", ServeDir::new(ASSETS_PATH))
         .with_state(state.clone())
+        .layer(middleware::from_fn(scope_idempotency_key))
+        .layer(middleware::from_fn_with_state(
+            SecurityHeaders::new(opts),
+            security::set_headers,
+        ))
+        .layer(middleware::from_fn(metrics::track_requests))
         .layer(http_tracing_layer)
+        .layer(PropagateRequestIdLayer::x_request_id())
+        .layer(SetRequestIdLayer::x_request_id(MakeRequestUuid))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TIMEOUT: Duration = Duration::from_secs(30);
+
+    #[tokio::test(start_paused = true)]
+    async fn waits_for_the_drain() {
+        let (stopping, stopped) = oneshot::channel();
+        _ = stopping.send(());
+        let drain = async {
+            tokio::time::sleep(Duration::from_secs(10)).await;
+            Ok(())
+        };
+
+        let started = tokio::time::Instant::now();
+        drain_until_deadline(drain, stopped, TIMEOUT).await.unwrap();
+        assert_eq!(started.elapsed(), Duration::from_secs(10));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn gives_up_after_the_timeout() {
+        let (stopping, stopped) = oneshot::channel();
+        _ = stopping.send(());
+
+        let started = tokio::time::Instant::now();
+        drain_until_deadline(std::future::pending(), stopped, TIMEOUT)
+            .await
+            .unwrap();
+        assert_eq!(started.elapsed(), TIMEOUT);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn has_no_deadline_without_a_signal() {
+        let (stopping, stopped) = oneshot::channel::<()>();
+        drop(stopping);
+        let drain = async {
+            tokio::time::sleep(TIMEOUT * 2).await;
+            Ok(())
+        };
+
+        let started = tokio::time::Instant::now();
+        drain_until_deadline(drain, stopped, TIMEOUT).await.unwrap();
+        assert_eq!(started.elapsed(), TIMEOUT * 2);
+    }
+
+    #[tokio::test]
+    async fn continues_the_trace_of_the_caller() {
+        use axum::{body::Bytes, http::StatusCode};
+        use kountr_app::domain::models::Counter;
+        use opentelemetry_proto::tonic::collector::trace::v1::ExportTraceServiceRequest;
+        use prost::Message;
+        use tower::ServiceExt;
+        use tracing_subscriber::layer::SubscriberExt;
+
+        const TRACE_ID: &str = "0af7651916cd43dd8448eb211c80319c";
+        const CALLER_SPAN_ID: &str = "b7ad6b7169203331";
+
+        // A collector receiving OTLP over HTTP
+        let (spans_tx, mut spans_rx) = tokio::sync::mpsc::unbounded_channel();
+        let collector = Router::new().route(
+            "/v1/traces",
+            post(move |body: Bytes| async move {
+                let export = ExportTraceServiceRequest::decode(body).unwrap();
+                for resource in export.resource_spans {
+                    for span in resource.scope_spans.into_iter().flat_map(|scope| scope.spans) {
+                        _ = spans_tx.send(span);
+                    }
+                }
+                StatusCode::OK
+            }),
+        );
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let endpoint = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(axum::Server::from_tcp(listener).unwrap().serve(collector.into_make_service()));
+
+        let otlp = kountr_app::telemetry::otlp_layer(&endpoint).unwrap();
+        let _tracing = tracing::subscriber::set_default(tracing_subscriber::registry().with(otlp));
+
+        let state = kountr_app::init_in_memory_app();
+        let counter = kountr_app::add_counter(&state, Counter::new("Coffee".to_string(), 0))
+            .await
+            .unwrap();
+
+        let router = Router::new()
+            .route("/api/v1/counters/:id/increment", post(api::increment_counter))
+            .with_state(state.clone())
+            .layer(TraceLayer::new_for_http().make_span_with(request_span));
+        let req = Request::post(format!("/api/v1/counters/{}/increment", counter.id))
+            .header("traceparent", format!("00-{}-{}-01", TRACE_ID, CALLER_SPAN_ID))
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(router.oneshot(req).await.unwrap().status(), StatusCode::OK);
+
+        // Exports the spans still buffered
+        kountr_app::shutdown(state).await.unwrap();
+
+        let mut spans = vec![];
+        while let Ok(span) = spans_rx.try_recv() {
+            spans.push(span);
+        }
+        let trace_id = hex::decode(TRACE_ID).unwrap();
+        let traced: Vec<&str> = spans
+            .iter()
+            .filter(|span| span.trace_id == trace_id)
+            .map(|span| span.name.as_str())
+            .collect();
+
+        let request = spans.iter().find(|span| span.name == "request").unwrap();
+        assert_eq!(request.trace_id, trace_id);
+        assert_eq!(request.parent_span_id, hex::decode(CALLER_SPAN_ID).unwrap());
+        for name in ["command", "event_store.save", "consumer"] {
+            assert!(traced.contains(&name), "{} isn't in {:?}", name, traced);
+        }
+    }
+
+    #[tokio::test]
+    async fn returns_drain_errors() {
+        let (_stopping, stopped) = oneshot::channel();
+        let drain = async { Err(anyhow::anyhow!("Error while closing the database")) };
+
+        let err = drain_until_deadline(drain, stopped, TIMEOUT).await.unwrap_err();
+        assert_eq!(err.to_string(), "Error while closing the database");
+    }
 }
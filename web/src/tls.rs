@@ -0,0 +1,285 @@
+use std::{
+    collections::HashSet,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::Context;
+use axum::{
+    http::{header, uri::PathAndQuery, HeaderMap, StatusCode, Uri},
+    response::{IntoResponse, Redirect},
+    Router,
+};
+use axum_server::{tls_rustls::RustlsConfig, Handle};
+use notify::{RecursiveMode, Watcher};
+use tokio::sync::mpsc;
+use tracing::{error, info};
+
+use kountr_app::TlsOptions;
+
+// Certificates and keys are often replaced one after the other, reload once both are there.
+const RELOAD_DELAY: Duration = Duration::from_secs(1);
+
+/// Loads the certificate and the key, then reloads them whenever their files change.
+pub async fn load(opts: &TlsOptions) -> anyhow::Result<RustlsConfig> {
+    let (cert, key) = read_pem(opts).await?;
+    let config = RustlsConfig::from_pem(cert, key)
+        .await
+        .context("Invalid TLS certificate or key")?;
+
+    watch(opts, config.clone()).context("Cannot watch the TLS certificate for changes")?;
+
+    Ok(config)
+}
+
+/// Reads the certificate chain and the key. An empty chain is an error here, since
+/// rustls would accept it and then fail every handshake (eg. with a half written file).
+async fn read_pem(opts: &TlsOptions) -> anyhow::Result<(Vec<u8>, Vec<u8>)> {
+    let cert = tokio::fs::read(&opts.cert)
+        .await
+        .with_context(|| format!("Cannot read the TLS certificate {}", opts.cert.display()))?;
+    let key = tokio::fs::read(&opts.key)
+        .await
+        .with_context(|| format!("Cannot read the TLS key {}", opts.key.display()))?;
+
+    let chain = rustls_pemfile::certs(&mut cert.as_slice()).unwrap_or_default();
+    if chain.is_empty() {
+        anyhow::bail!("No certificate found in {}", opts.cert.display());
+    }
+
+    Ok((cert, key))
+}
+
+fn watch(opts: &TlsOptions, config: RustlsConfig) -> notify::Result<()> {
+    let files: HashSet<_> = [&opts.cert, &opts.key]
+        .into_iter()
+        .filter_map(|path| path.file_name().map(ToOwned::to_owned))
+        .collect();
+
+    let (tx, mut rx) = mpsc::channel(1);
+    let mut watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+        let Ok(event) = event else {
+            return;
+        };
+
+        let changed = !event.kind.is_access()
+            && event
+                .paths
+                .iter()
+                .filter_map(|path| path.file_name())
+                .any(|name| files.contains(name));
+        if changed {
+            // A reload is already pending otherwise
+            _ = tx.try_send(());
+        }
+    })?;
+
+    // Directories rather than files, to notice files replaced by a rename or
+    // a symlink swap too (eg. certbot)
+    let dirs: HashSet<_> = [&opts.cert, &opts.key].into_iter().map(|path| parent(path)).collect();
+    for dir in dirs {
+        watcher.watch(&dir, RecursiveMode::NonRecursive)?;
+    }
+
+    let opts = opts.clone();
+    tokio::spawn(async move {
+        // Stops watching when dropped
+        let _watcher = watcher;
+
+        while rx.recv().await.is_some() {
+            tokio::time::sleep(RELOAD_DELAY).await;
+            _ = rx.try_recv();
+
+            let reloaded = async {
+                let (cert, key) = read_pem(&opts).await?;
+                config.reload_from_pem(cert, key).await?;
+                anyhow::Ok(())
+            };
+            match reloaded.await {
+                Ok(()) => info!("Reloaded the TLS certificate {}", opts.cert.display()),
+                Err(err) => error!("Cannot reload the TLS certificate, keeping the previous one: {:#}", err),
+            }
+        }
+    });
+
+    Ok(())
+}
+
+fn parent(path: &Path) -> PathBuf {
+    match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+        _ => PathBuf::from("."),
+    }
+}
+
+/// Serves plain HTTP on `addr`, redirecting every request to HTTPS on `https_port`.
+pub fn redirect_to_https(addr: SocketAddr, https_port: u16, handle: Handle) {
+    let router = Router::new().fallback(move |headers: HeaderMap, uri: Uri| async move {
+        redirect(&headers, &uri, https_port)
+    });
+
+    info!("Redirecting http://{} to HTTPS", addr);
+    tokio::spawn(async move {
+        let server = axum_server::bind(addr)
+            .handle(handle)
+            .serve(router.into_make_service());
+
+        if let Err(err) = server.await {
+            error!("Error while redirecting HTTP to HTTPS: {}", err);
+        }
+    });
+}
+
+fn redirect(headers: &HeaderMap, uri: &Uri, https_port: u16) -> impl IntoResponse {
+    let host = headers
+        .get(header::HOST)
+        .and_then(|host| host.to_str().ok())
+        .and_then(|host| host.parse::<Uri>().ok())
+        .and_then(|host| host.host().map(str::to_string));
+    let Some(host) = host else {
+        return (StatusCode::BAD_REQUEST, "Missing Host header").into_response();
+    };
+
+    let authority = match https_port {
+        443 => host,
+        port => format!("{}:{}", host, port),
+    };
+    let path = uri.path_and_query().map_or("/", PathAndQuery::as_str);
+
+    Redirect::permanent(&format!("https://{}{}", authority, path)).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::SystemTime;
+
+    use rustls::{
+        client::{ServerCertVerified, ServerCertVerifier},
+        Certificate, ServerName,
+    };
+
+    use super::*;
+
+    // Self-signed for `localhost`, never verified: their dates don't matter
+    const CERT_A: &str = include_str!("fixtures/cert-a.pem");
+    const KEY_A: &str = include_str!("fixtures/key-a.pem");
+    const CERT_B: &str = include_str!("fixtures/cert-b.pem");
+    const KEY_B: &str = include_str!("fixtures/key-b.pem");
+
+    fn redirect_to(host: Option<&str>, uri: &str, https_port: u16) -> (StatusCode, Option<String>) {
+        let mut headers = HeaderMap::new();
+        if let Some(host) = host {
+            headers.insert(header::HOST, host.parse().unwrap());
+        }
+
+        let response = redirect(&headers, &uri.parse().unwrap(), https_port).into_response();
+        let location = response
+            .headers()
+            .get(header::LOCATION)
+            .map(|location| location.to_str().unwrap().to_string());
+
+        (response.status(), location)
+    }
+
+    #[test]
+    fn redirects_to_the_same_host_over_https() {
+        let redirected = |location: &str| (StatusCode::PERMANENT_REDIRECT, Some(location.to_string()));
+
+        assert_eq!(
+            redirect_to(Some("kountr.example:8080"), "/counters?page=2", 8443),
+            redirected("https://kountr.example:8443/counters?page=2")
+        );
+        // The default port is left out
+        assert_eq!(
+            redirect_to(Some("kountr.example:80"), "/", 443),
+            redirected("https://kountr.example/")
+        );
+        assert_eq!(redirect_to(Some("kountr.example"), "/", 443), redirected("https://kountr.example/"));
+        assert_eq!(redirect_to(Some("[::1]:8080"), "/", 8443), redirected("https://[::1]:8443/"));
+        assert_eq!(redirect_to(Some("[::1]"), "/", 443), redirected("https://[::1]/"));
+
+        assert_eq!(redirect_to(None, "/", 443), (StatusCode::BAD_REQUEST, None));
+        assert_eq!(redirect_to(Some("not a host"), "/", 443), (StatusCode::BAD_REQUEST, None));
+    }
+
+    /// Accepts any certificate, to look at the one the server presents.
+    struct AnyCertificate;
+
+    impl ServerCertVerifier for AnyCertificate {
+        fn verify_server_cert(
+            &self,
+            _end_entity: &Certificate,
+            _intermediates: &[Certificate],
+            _server_name: &ServerName,
+            _scts: &mut dyn Iterator<Item = &[u8]>,
+            _ocsp_response: &[u8],
+            _now: SystemTime,
+        ) -> Result<ServerCertVerified, rustls::Error> {
+            Ok(ServerCertVerified::assertion())
+        }
+    }
+
+    async fn presented_certificate(addr: SocketAddr) -> Vec<u8> {
+        let config = rustls::ClientConfig::builder()
+            .with_safe_defaults()
+            .with_custom_certificate_verifier(Arc::new(AnyCertificate))
+            .with_no_client_auth();
+        let connector = tokio_rustls::TlsConnector::from(Arc::new(config));
+
+        let tcp = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let server_name = ServerName::try_from("localhost").unwrap();
+        let tls = connector.connect(server_name, tcp).await.unwrap();
+
+        tls.get_ref().1.peer_certificates().unwrap()[0].0.clone()
+    }
+
+    fn der(pem: &str) -> Vec<u8> {
+        rustls_pemfile::certs(&mut pem.as_bytes()).unwrap().remove(0)
+    }
+
+    #[tokio::test]
+    async fn reloads_the_certificate_when_it_changes() {
+        let dir = std::env::temp_dir().join(format!("kountr-tls-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir(&dir).unwrap();
+        let opts = TlsOptions {
+            cert: dir.join("cert.pem"),
+            key: dir.join("key.pem"),
+            redirect_http_port: None,
+        };
+        std::fs::write(&opts.cert, CERT_A).unwrap();
+        std::fs::write(&opts.key, KEY_A).unwrap();
+
+        let config = load(&opts).await.unwrap();
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let handle = Handle::new();
+        tokio::spawn(
+            axum_server::from_tcp_rustls(listener, config)
+                .handle(handle.clone())
+                .serve(Router::new().into_make_service()),
+        );
+        assert_eq!(presented_certificate(addr).await, der(CERT_A));
+
+        // eg. a renewal, one file after the other
+        std::fs::write(&opts.key, KEY_B).unwrap();
+        std::fs::write(&opts.cert, CERT_B).unwrap();
+        let renewed = async {
+            while presented_certificate(addr).await != der(CERT_B) {
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+        };
+        tokio::time::timeout(RELOAD_DELAY * 10, renewed)
+            .await
+            .expect("The certificate wasn't reloaded");
+
+        // A half written certificate is ignored
+        std::fs::write(&opts.cert, "").unwrap();
+        tokio::time::sleep(RELOAD_DELAY * 2).await;
+        assert_eq!(presented_certificate(addr).await, der(CERT_B));
+
+        handle.shutdown();
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -1,9 +1,13 @@
 use askama::Template;
 use axum::{
-    http::StatusCode,
+    body::StreamBody,
+    http::{header, HeaderMap, StatusCode},
     response::{Html, IntoResponse, Response},
+    Json,
 };
-use kountr_app::domain::models::Counter;
+use futures::Stream;
+use serde_json::json;
+use kountr_app::{domain::models::Counter, DbError};
 
 pub struct HtmlView<T>(pub T);
 
@@ -27,28 +31,74 @@ where
     }
 }
 
+/// Streams CSV chunks as a file download.
+pub struct CsvView<S> {
+    pub filename: String,
+    pub chunks: S,
+}
+
+impl<S> IntoResponse for CsvView<S>
+where
+    S: Stream<Item = Result<Vec<u8>, DbError>> + Send + 'static,
+{
+    fn into_response(self) -> Response {
+        let disposition = format!("attachment; filename=\"{}\"", self.filename);
+
+        (
+            [
+                (header::CONTENT_TYPE, "text/csv; charset=utf-8".to_string()),
+                (header::CONTENT_DISPOSITION, disposition),
+            ],
+            StreamBody::new(self.chunks),
+        )
+            .into_response()
+    }
+}
+
+/// HTMX requests get a message to show in `#flash`, see `base.html`, the others a JSON error.
+pub fn error_response(headers: &HeaderMap, status: StatusCode, message: &str) -> Response {
+    if headers.contains_key("hx-request") {
+        let html = format!(r#"<p class="text-red-600">{}</p>"#, message);
+        let retarget = [("HX-Retarget", "#flash"), ("HX-Reswap", "innerHTML")];
+
+        return (status, retarget, Html(html)).into_response();
+    }
+
+    let body = Json(json!({ "error": message.to_lowercase() }));
+    (status, body).into_response()
+}
+
+// Pages extending `base.html` carry the CSRF token of the session, sent back by HTMX requests.
 #[derive(Template)]
 #[template(path = "pages/home.html")]
-pub struct HomeView {}
+pub struct HomeView {
+    pub csrf_token: String,
+}
 
 #[derive(Template)]
 #[template(path = "pages/dashboard.html")]
-pub struct DashboardView;
+pub struct DashboardView {
+    pub csrf_token: String,
+}
 
 // Counters
 #[derive(Template)]
 #[template(path = "counters/list.html")]
 pub struct ListCountersView {
+    pub csrf_token: String,
     pub counters: Vec<CounterView>,
 }
 
 #[derive(Template)]
 #[template(path = "counters/new.html")]
-pub struct NewCounterView;
+pub struct NewCounterView {
+    pub csrf_token: String,
+}
 
 #[derive(Template)]
 #[template(path = "counters/edit.html")]
 pub struct EditCounterView {
+    pub csrf_token: String,
     pub id: String,
     pub name: String,
     pub value: i32,
@@ -61,4 +111,18 @@ pub struct CounterView {
     pub name: String,
     pub value: i32,
     pub counter: Counter,
+    /// Sent as `Idempotency-Key` by the buttons, so that double clicks count once.
+    pub request_key: String,
+}
+
+impl CounterView {
+    pub fn new(counter: Counter) -> Self {
+        Self {
+            id: counter.id.clone(),
+            name: counter.name.clone(),
+            value: counter.value,
+            counter,
+            request_key: uuid::Uuid::new_v4().to_string(),
+        }
+    }
 }
@@ -0,0 +1,468 @@
+use std::{collections::HashSet, net::SocketAddr};
+
+use axum::{
+    extract::{
+        ws::{close_code, CloseFrame, Message, WebSocket, WebSocketUpgrade},
+        ConnectInfo, State,
+    },
+    http::HeaderMap,
+    response::{IntoResponse, Response},
+    Extension,
+};
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast::error::RecvError;
+use tokio_util::{sync::CancellationToken, task::TaskTracker};
+use tracing::debug;
+
+use kountr_app::domain::models::{Counter, CounterChange};
+use kountr_app::AppState;
+
+use crate::csrf;
+use crate::rate_limit::{self, ClientLimits, RateLimits};
+
+// ====================== PROTOCOL ============================================
+
+/// Messages sent by clients, eg. `{"type": "increment", "id": "..."}`.
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ClientMessage {
+    Subscribe { ids: Vec<String> },
+    Unsubscribe { ids: Vec<String> },
+    Increment { id: String },
+    Decrement { id: String },
+}
+
+/// Messages pushed to clients, eg. `{"type": "counter", "id": "...", "name": "...", "value": 1}`.
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ServerMessage {
+    Counter { id: String, name: String, value: i32 },
+    Deleted { id: String },
+    Error { message: String },
+}
+
+impl From<Counter> for ServerMessage {
+    fn from(counter: Counter) -> Self {
+        ServerMessage::Counter {
+            id: counter.id,
+            name: counter.name,
+            value: counter.value,
+        }
+    }
+}
+
+// ====================== SHUTDOWN ============================================
+
+/// The open sockets, closed on shutdown before the database they run commands on.
+#[derive(Clone, Default)]
+pub struct Sockets {
+    closing: CancellationToken,
+    open: TaskTracker,
+}
+
+impl Sockets {
+    /// Asks every socket to close, then waits until they have.
+    pub async fn close(&self) {
+        self.closing.cancel();
+        self.open.close();
+        self.open.wait().await;
+    }
+}
+
+// ====================== HANDLERS ============================================
+
+/// Only pages of the same origin can open the socket, and changes made over it are rate
+/// limited like the others, see `RateLimits`.
+pub async fn counters_socket(
+    ws: WebSocketUpgrade,
+    state: State<AppState>,
+    Extension(sockets): Extension<Sockets>,
+    Extension(limits): Extension<RateLimits>,
+    addr: Option<ConnectInfo<SocketAddr>>,
+    headers: HeaderMap,
+) -> Response {
+    // Browsers send cookies along with sockets opened from any site
+    if csrf::is_cross_site(&headers) {
+        return csrf::cross_site_forbidden(&headers);
+    }
+    let limits = limits.for_client(&headers, addr.map(|ConnectInfo(addr)| addr));
+
+    // Counted as open from the upgrade until the socket is done
+    let open = sockets.open.token();
+    ws.on_upgrade(move |socket| async move {
+        handle_socket(socket, state.0, limits, sockets.closing).await;
+        drop(open);
+    })
+    .into_response()
+}
+
+async fn handle_socket(
+    mut socket: WebSocket,
+    state: AppState,
+    limits: ClientLimits,
+    closing: CancellationToken,
+) {
+    let mut changes = kountr_app::subscribe_counter_changes(&state);
+    let mut subscriptions: HashSet<String> = HashSet::new();
+
+    loop {
+        tokio::select! {
+            _ = closing.cancelled() => {
+                let frame = CloseFrame {
+                    code: close_code::AWAY,
+                    reason: "server shutting down".into(),
+                };
+                _ = socket.send(Message::Close(Some(frame))).await;
+                break;
+            }
+            msg = socket.recv() => {
+                let text = match msg {
+                    Some(Ok(Message::Text(text))) => text,
+                    Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                    Some(Ok(_)) => continue,
+                };
+
+                for reply in handle_message(&state, &limits, &mut subscriptions, &text).await {
+                    if send(&mut socket, reply).await.is_err() {
+                        return;
+                    }
+                }
+            }
+            change = changes.recv() => {
+                let change = match change {
+                    Ok(change) => change,
+                    Err(RecvError::Lagged(skipped)) => {
+                        debug!("websocket subscriber lagged behind, skipped {} changes", skipped);
+                        continue;
+                    }
+                    Err(RecvError::Closed) => break,
+                };
+                let Some(reply) = change_message(&mut subscriptions, change) else {
+                    continue;
+                };
+
+                if send(&mut socket, reply).await.is_err() {
+                    return;
+                }
+            }
+        }
+    }
+}
+
+/// Only the changes of subscribed counters are pushed, a deleted counter ends its subscription.
+fn change_message(subscriptions: &mut HashSet<String>, change: CounterChange) -> Option<ServerMessage> {
+    match change {
+        CounterChange::Updated(counter) if subscriptions.contains(&counter.id) => Some(counter.into()),
+        CounterChange::Deleted(id) if subscriptions.remove(&id) => Some(ServerMessage::Deleted { id }),
+        _ => None,
+    }
+}
+
+async fn handle_message(
+    state: &AppState,
+    limits: &ClientLimits,
+    subscriptions: &mut HashSet<String>,
+    text: &str,
+) -> Vec<ServerMessage> {
+    let msg = match serde_json::from_str::<ClientMessage>(text) {
+        Ok(msg) => msg,
+        Err(err) => {
+            return vec![ServerMessage::Error {
+                message: format!("Invalid message: {}", err),
+            }]
+        }
+    };
+
+    match msg {
+        ClientMessage::Subscribe { ids } => {
+            let mut replies = vec![];
+            for id in ids {
+                // Send a snapshot first, later changes are pushed as they happen
+                match kountr_app::find_counter(state, id.clone()).await {
+                    Ok(counter) => {
+                        subscriptions.insert(id);
+                        replies.push(counter.into());
+                    }
+                    Err(_) => replies.push(counter_not_found(&id)),
+                }
+            }
+            replies
+        }
+        ClientMessage::Unsubscribe { ids } => {
+            for id in ids.iter() {
+                subscriptions.remove(id);
+            }
+            vec![]
+        }
+        ClientMessage::Increment { id } => {
+            if kountr_app::find_counter(state, id.clone()).await.is_err() {
+                return vec![counter_not_found(&id)];
+            }
+            if let Err(wait) = limits.check(&id) {
+                return vec![too_many_changes(wait)];
+            }
+            let result = kountr_app::increment_counter(state, id.clone()).await;
+            command_reply(subscriptions, &id, result)
+        }
+        ClientMessage::Decrement { id } => {
+            if kountr_app::find_counter(state, id.clone()).await.is_err() {
+                return vec![counter_not_found(&id)];
+            }
+            if let Err(wait) = limits.check(&id) {
+                return vec![too_many_changes(wait)];
+            }
+            let result = kountr_app::decrement_counter(state, id.clone()).await;
+            command_reply(subscriptions, &id, result)
+        }
+    }
+}
+
+/// Subscribed clients already get the change pushed, the others get it as a reply.
+fn command_reply<E: std::fmt::Display>(
+    subscriptions: &HashSet<String>,
+    id: &str,
+    result: Result<Counter, E>,
+) -> Vec<ServerMessage> {
+    match result {
+        Ok(_) if subscriptions.contains(id) => vec![],
+        Ok(counter) => vec![counter.into()],
+        Err(err) => vec![ServerMessage::Error {
+            message: format!("Command failed: {}", err),
+        }],
+    }
+}
+
+fn counter_not_found(id: &str) -> ServerMessage {
+    ServerMessage::Error {
+        message: format!("Counter {} not found", id),
+    }
+}
+
+fn too_many_changes(wait: std::time::Duration) -> ServerMessage {
+    ServerMessage::Error {
+        message: rate_limit::too_many_changes(wait),
+    }
+}
+
+async fn send(socket: &mut WebSocket, msg: ServerMessage) -> Result<(), axum::Error> {
+    // ServerMessage only holds strings and numbers, serialization can't fail
+    let text = serde_json::to_string(&msg).unwrap();
+    socket.send(Message::Text(text)).await
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    async fn new_counter(state: &AppState, name: &str) -> Counter {
+        kountr_app::add_counter(state, Counter::new(name.to_string(), 0))
+            .await
+            .unwrap()
+    }
+
+    fn unlimited() -> ClientLimits {
+        let opts = kountr_app::RateLimitOptions {
+            per_client: 0,
+            per_counter: 0,
+            trust_forwarded_for: false,
+        };
+        RateLimits::new(&opts).for_client(&HeaderMap::new(), None)
+    }
+
+    fn to_json(replies: Vec<ServerMessage>) -> Vec<serde_json::Value> {
+        replies
+            .iter()
+            .map(|reply| serde_json::to_value(reply).unwrap())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn waits_for_open_sockets_to_close() {
+        use std::sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc,
+        };
+
+        let sockets = Sockets::default();
+        let open = sockets.open.token();
+        let closing = sockets.closing.clone();
+        let closed = Arc::new(AtomicBool::new(false));
+        tokio::spawn({
+            let closed = closed.clone();
+            async move {
+                closing.cancelled().await;
+                // eg. a command in progress
+                tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+                closed.store(true, Ordering::SeqCst);
+                drop(open);
+            }
+        });
+
+        sockets.close().await;
+        assert!(closed.load(Ordering::SeqCst));
+
+        // Without open sockets, there's nothing to wait for
+        Sockets::default().close().await;
+    }
+
+    #[test]
+    fn parses_client_messages() {
+        let msg = r#"{"type": "subscribe", "ids": ["a", "b"]}"#;
+        assert!(matches!(
+            serde_json::from_str(msg),
+            Ok(ClientMessage::Subscribe { ids }) if ids == ["a", "b"]
+        ));
+
+        let msg = r#"{"type": "unsubscribe", "ids": []}"#;
+        assert!(matches!(
+            serde_json::from_str(msg),
+            Ok(ClientMessage::Unsubscribe { ids }) if ids.is_empty()
+        ));
+
+        let msg = r#"{"type": "increment", "id": "a"}"#;
+        assert!(matches!(serde_json::from_str(msg), Ok(ClientMessage::Increment { id }) if id == "a"));
+
+        let msg = r#"{"type": "decrement", "id": "a"}"#;
+        assert!(matches!(serde_json::from_str(msg), Ok(ClientMessage::Decrement { id }) if id == "a"));
+
+        for msg in [r#"{"type": "reset", "id": "a"}"#, r#"{"type": "increment"}"#, "[]"] {
+            assert!(serde_json::from_str::<ClientMessage>(msg).is_err(), "{}", msg);
+        }
+    }
+
+    #[test]
+    fn serializes_server_messages() {
+        let counter = Counter::new_with_id("a".to_string(), "Coffee".to_string(), 3);
+        let messages = vec![
+            counter.into(),
+            ServerMessage::Deleted { id: "a".to_string() },
+            ServerMessage::Error {
+                message: "oops".to_string(),
+            },
+        ];
+
+        assert_eq!(
+            to_json(messages),
+            vec![
+                json!({"type": "counter", "id": "a", "name": "Coffee", "value": 3}),
+                json!({"type": "deleted", "id": "a"}),
+                json!({"type": "error", "message": "oops"}),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn subscribes_with_a_snapshot() {
+        let state = kountr_app::init_in_memory_app();
+        let counter = new_counter(&state, "Coffee").await;
+        let limits = unlimited();
+        let mut subscriptions = HashSet::new();
+
+        let msg = json!({"type": "subscribe", "ids": [counter.id, "unknown"]}).to_string();
+        let replies = handle_message(&state, &limits, &mut subscriptions, &msg).await;
+
+        assert_eq!(
+            to_json(replies),
+            vec![
+                json!({"type": "counter", "id": counter.id, "name": "Coffee", "value": 0}),
+                json!({"type": "error", "message": "Counter unknown not found"}),
+            ]
+        );
+        assert_eq!(subscriptions, HashSet::from([counter.id.clone()]));
+
+        let msg = json!({"type": "unsubscribe", "ids": [counter.id]}).to_string();
+        assert!(handle_message(&state, &limits, &mut subscriptions, &msg).await.is_empty());
+        assert!(subscriptions.is_empty());
+    }
+
+    #[tokio::test]
+    async fn replies_to_commands_unless_subscribed() {
+        let state = kountr_app::init_in_memory_app();
+        let counter = new_counter(&state, "Coffee").await;
+        let limits = unlimited();
+        let mut subscriptions = HashSet::new();
+
+        let msg = json!({"type": "increment", "id": counter.id}).to_string();
+        let replies = handle_message(&state, &limits, &mut subscriptions, &msg).await;
+        assert_eq!(
+            to_json(replies),
+            vec![json!({"type": "counter", "id": counter.id, "name": "Coffee", "value": 1})]
+        );
+
+        // The change is pushed to subscribers instead
+        subscriptions.insert(counter.id.clone());
+        let msg = json!({"type": "decrement", "id": counter.id}).to_string();
+        assert!(handle_message(&state, &limits, &mut subscriptions, &msg).await.is_empty());
+        assert_eq!(kountr_app::find_counter(&state, counter.id).await.unwrap().value, 0);
+    }
+
+    #[tokio::test]
+    async fn rejects_unknown_counters_and_invalid_messages() {
+        let state = kountr_app::init_in_memory_app();
+        let limits = unlimited();
+        let mut subscriptions = HashSet::new();
+
+        for kind in ["increment", "decrement"] {
+            let msg = json!({"type": kind, "id": "unknown"}).to_string();
+            let replies = handle_message(&state, &limits, &mut subscriptions, &msg).await;
+            assert_eq!(
+                to_json(replies),
+                vec![json!({"type": "error", "message": "Counter unknown not found"})]
+            );
+        }
+
+        let replies = handle_message(&state, &limits, &mut subscriptions, "not json").await;
+        assert!(matches!(
+            replies.as_slice(),
+            [ServerMessage::Error { message }] if message.starts_with("Invalid message: ")
+        ));
+    }
+
+    #[tokio::test]
+    async fn limits_changes_like_requests() {
+        let state = kountr_app::init_in_memory_app();
+        let counter = new_counter(&state, "Coffee").await;
+        let opts = kountr_app::RateLimitOptions {
+            per_client: 0,
+            per_counter: 1,
+            trust_forwarded_for: false,
+        };
+        let limits = RateLimits::new(&opts).for_client(&HeaderMap::new(), None);
+        let mut subscriptions = HashSet::new();
+
+        let msg = json!({"type": "increment", "id": counter.id}).to_string();
+        assert_eq!(handle_message(&state, &limits, &mut subscriptions, &msg).await.len(), 1);
+
+        let msg = json!({"type": "decrement", "id": counter.id}).to_string();
+        let replies = handle_message(&state, &limits, &mut subscriptions, &msg).await;
+        assert!(matches!(
+            replies.as_slice(),
+            [ServerMessage::Error { message }] if message.starts_with("Too many changes, try again in ")
+        ));
+        assert_eq!(kountr_app::find_counter(&state, counter.id).await.unwrap().value, 1);
+    }
+
+    #[test]
+    fn pushes_changes_of_subscribed_counters_only() {
+        let mut subscriptions = HashSet::from(["a".to_string()]);
+        let counter = |id: &str| Counter::new_with_id(id.to_string(), "Coffee".to_string(), 1);
+
+        let pushed = change_message(&mut subscriptions, CounterChange::Updated(counter("a")));
+        assert!(matches!(pushed, Some(ServerMessage::Counter { id, value: 1, .. }) if id == "a"));
+
+        let pushed = change_message(&mut subscriptions, CounterChange::Updated(counter("b")));
+        assert!(pushed.is_none());
+        let pushed = change_message(&mut subscriptions, CounterChange::Deleted("b".to_string()));
+        assert!(pushed.is_none());
+
+        let pushed = change_message(&mut subscriptions, CounterChange::Deleted("a".to_string()));
+        assert!(matches!(pushed, Some(ServerMessage::Deleted { id }) if id == "a"));
+        assert!(subscriptions.is_empty());
+
+        // No longer subscribed once deleted
+        let pushed = change_message(&mut subscriptions, CounterChange::Updated(counter("a")));
+        assert!(pushed.is_none());
+    }
+}
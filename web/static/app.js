@@ -0,0 +1,7 @@
+// htmx doesn't swap error responses, but rate limit and CSRF messages are meant to be shown
+document.addEventListener("htmx:beforeSwap", function (evt) {
+  if ([403, 429].includes(evt.detail.xhr.status)) {
+    evt.detail.shouldSwap = true;
+    evt.detail.isError = false;
+  }
+});